@@ -0,0 +1,356 @@
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+use super::create_command;
+use super::return_something_from_progress_holder;
+use super::set_animations;
+use super::use_me_from_progress_holder;
+use super::Clip;
+use super::ProgressVars;
+use super::TaskResult;
+use super::PROGHOLDER;
+use super::media_probe::probe_media;
+
+pub const DEFAULT_ANIMATION_FPS: u32 = 12;
+pub const MAX_ANIMATION_FPS: u32 = 30;
+pub const DEFAULT_ANIMATION_WIDTH: u32 = 480;
+pub const MIN_ANIMATION_WIDTH: u32 = 64;
+pub const MAX_ANIMATION_WIDTH: u32 = 1920;
+/// from 0 to 100. tried in this order until the animation fits under max_bytes
+pub const ANIMATION_QUALITIES: [u32; 4] = [90, 70, 50, 30];
+/// once the lowest quality is still too large, the
+/// width is shrunk by this much for every attempt
+pub const ANIMATION_SHRINK_FACTOR: f64 = 0.75;
+pub const MAX_ANIMATION_ATTEMPTS: usize = 8;
+/// animations are for short moments. chapters can be any length,
+/// so anything past this is cut off rather than encoded
+pub const MAX_ANIMATION_SECONDS: f64 = 60.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnimationFormat {
+    Gif,
+    Webp,
+}
+
+impl AnimationFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AnimationFormat::Gif => "gif",
+            AnimationFormat::Webp => "webp",
+        }
+    }
+}
+
+/// an animated gif or webp made from every clip of the job
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AnimationRequest {
+    pub format: AnimationFormat,
+    /// defaults to DEFAULT_ANIMATION_FPS
+    pub fps: Option<u32>,
+    /// in pixels, defaults to DEFAULT_ANIMATION_WIDTH.
+    /// clips narrower than this are not scaled up
+    pub width: Option<u32>,
+    /// if set, the quality and then the width are
+    /// lowered until the animation fits in this many bytes
+    pub max_bytes: Option<u64>,
+}
+
+/// the settings of one try at making the animation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AnimationSettings {
+    pub fps: u32,
+    pub width: u32,
+    pub quality: u32,
+}
+
+/// duration is the length of the clip, if the request set one
+pub fn validate_animation_request(request: &AnimationRequest, duration: Option<f64>) -> Result<(), String> {
+    if duration.map_or(false, |d| d > MAX_ANIMATION_SECONDS) {
+        return Err(format!("Animations can be at most {} seconds long", MAX_ANIMATION_SECONDS));
+    }
+    if let Some(fps) = request.fps {
+        if fps == 0 || fps > MAX_ANIMATION_FPS {
+            return Err(format!("Animation fps must be between 1 and {}", MAX_ANIMATION_FPS));
+        }
+    }
+    if let Some(width) = request.width {
+        if !(MIN_ANIMATION_WIDTH..=MAX_ANIMATION_WIDTH).contains(&width) {
+            return Err(format!(
+                "Animation width must be between {} and {}",
+                MIN_ANIMATION_WIDTH, MAX_ANIMATION_WIDTH,
+            ));
+        }
+    }
+    if request.max_bytes == Some(0) {
+        return Err("Animation max_bytes must be positive".into());
+    }
+    Ok(())
+}
+
+/// the settings to try, in order. without a size cap
+/// only the first one is needed. the clip is never scaled up,
+/// so the widths start from the width of the clip if it is smaller
+pub fn animation_attempts(request: &AnimationRequest, clip_width: Option<u32>) -> Vec<AnimationSettings> {
+    let fps = request.fps.unwrap_or(DEFAULT_ANIMATION_FPS);
+    let mut width = request.width.unwrap_or(DEFAULT_ANIMATION_WIDTH);
+    if let Some(clip_width) = clip_width {
+        width = width.min(clip_width - clip_width % 2);
+    }
+    let mut attempts: Vec<AnimationSettings> = ANIMATION_QUALITIES.iter()
+        .map(|quality| AnimationSettings { fps, width, quality: *quality })
+        .collect();
+    if request.max_bytes.is_none() {
+        attempts.truncate(1);
+        return attempts;
+    }
+    let lowest_quality = ANIMATION_QUALITIES[ANIMATION_QUALITIES.len() - 1];
+    while attempts.len() < MAX_ANIMATION_ATTEMPTS {
+        let smaller = (width as f64 * ANIMATION_SHRINK_FACTOR) as u32;
+        width = smaller - smaller % 2;
+        if width < MIN_ANIMATION_WIDTH {
+            break;
+        }
+        attempts.push(AnimationSettings { fps, width, quality: lowest_quality });
+    }
+    // the same settings would make the same file again
+    attempts.dedup();
+    attempts
+}
+
+fn frame_filters(settings: &AnimationSettings) -> String {
+    format!("fps={},scale='min({},iw)':-2:flags=lanczos", settings.fps, settings.width)
+}
+
+/// the first gif pass. fewer colors make a smaller file.
+/// stats_mode=diff favors the parts of the frame that move
+pub fn palettegen_filter(settings: &AnimationSettings) -> String {
+    let max_colors = (256 * settings.quality / 100).clamp(16, 256);
+    format!("{},palettegen=max_colors={}:stats_mode=diff", frame_filters(settings), max_colors)
+}
+
+/// the second gif pass, which maps the frames onto the palette. a larger
+/// bayer_scale dithers less, which compresses better but bands more.
+/// diff_mode=rectangle only redraws the part of the frame that changed
+pub fn paletteuse_filter(settings: &AnimationSettings) -> String {
+    let bayer_scale = (100 - settings.quality.min(100)) / 20;
+    format!(
+        "{}[frames];[frames][1:v]paletteuse=dither=bayer:bayer_scale={}:diff_mode=rectangle",
+        frame_filters(settings), bayer_scale.min(5),
+    )
+}
+
+async fn run_ffmpeg(exe_and_args: Vec<String>) -> Result<(), String> {
+    let mut cmd = create_command(&exe_and_args[..]);
+    let output = cmd.output().await.map_err(
+        |e| format!("Failed to run ffmpeg: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let last_line = stderr.lines().last().unwrap_or_default();
+        return Err(format!("Animation export failed: {}", last_line));
+    }
+    Ok(())
+}
+
+fn path_string(path: &Path) -> Result<String, String> {
+    path.to_str().map_or_else(
+        || Err(format!("File path contains invalid characters: {:?}", path)),
+        |s| Ok(s.to_string()))
+}
+
+/// makes one animation with the given settings and returns its size.
+/// gifs are made in two passes, the first one picks a palette for
+/// the clip. webp is not limited to 256 colors so it has a quality instead
+pub async fn export_animation(
+    input: &Path,
+    output: &Path,
+    format: AnimationFormat,
+    settings: &AnimationSettings,
+) -> Result<u64, String> {
+    let input_string = path_string(input)?;
+    let output_string = path_string(output)?;
+    let mut exe_and_args: Vec<String> = vec![
+        "ffmpeg".into(),
+        "-loglevel".into(), "error".into(),
+        "-hide_banner".into(),
+        "-nostats".into(),
+        "-i".into(), input_string.clone(),
+    ];
+    let max_seconds = MAX_ANIMATION_SECONDS.to_string();
+    match format {
+        AnimationFormat::Gif => {
+            let palette_path = output.with_extension("palette.png");
+            let palette_string = path_string(&palette_path)?;
+            let res = run_ffmpeg(vec![
+                "ffmpeg".into(),
+                "-loglevel".into(), "error".into(),
+                "-hide_banner".into(),
+                "-nostats".into(),
+                "-i".into(), input_string,
+                "-t".into(), max_seconds.clone(),
+                "-vf".into(), palettegen_filter(settings),
+                "-y".into(), palette_string.clone(),
+            ]).await;
+            if let Err(e) = res {
+                let _ = tokio::fs::remove_file(&palette_path).await;
+                return Err(e);
+            }
+            exe_and_args.extend(vec![
+                "-i".into(), palette_string,
+                "-t".into(), max_seconds,
+                "-lavfi".into(), paletteuse_filter(settings),
+                "-loop".into(), "0".into(),
+                "-y".into(), output_string,
+            ]);
+            let res = run_ffmpeg(exe_and_args).await;
+            let _ = tokio::fs::remove_file(&palette_path).await;
+            res?;
+        },
+        AnimationFormat::Webp => {
+            exe_and_args.extend(vec![
+                "-t".into(), max_seconds,
+                "-an".into(),
+                "-vf".into(), frame_filters(settings),
+                "-vcodec".into(), "libwebp".into(),
+                "-q:v".into(), settings.quality.to_string(),
+                "-compression_level".into(), "6".into(),
+                "-loop".into(), "0".into(),
+                "-y".into(), output_string,
+            ]);
+            run_ffmpeg(exe_and_args).await?;
+        },
+    }
+    let metadata = tokio::fs::metadata(output).await.map_err(
+        |e| format!("Failed to read the animation {:?}: {}", output, e))?;
+    Ok(metadata.len())
+}
+
+/// tries the attempts in order until one fits under max_bytes.
+/// if none of them fit, nothing is left behind
+pub async fn make_animation(
+    key: &String,
+    input: &Path,
+    output: &Path,
+    request: &AnimationRequest,
+    clip_width: Option<u32>,
+) -> Result<(AnimationSettings, u64), String> {
+    let attempts = animation_attempts(request, clip_width);
+    let mut smallest = None;
+    for (i, settings) in attempts.iter().enumerate() {
+        let size = match export_animation(input, output, request.format, settings).await {
+            Ok(size) => size,
+            Err(e) => {
+                let _ = tokio::fs::remove_file(output).await;
+                return Err(e);
+            },
+        };
+        if request.max_bytes.map_or(true, |max_bytes| size <= max_bytes) {
+            return Ok((*settings, size));
+        }
+        smallest = Some(smallest.map_or(size, |s: u64| s.min(size)));
+        let progress = (i + 1) as f64 / attempts.len() as f64;
+        use_me_from_progress_holder(key, &PROGHOLDER, |me| {
+            me.inc_progress_percent_normalized(progress);
+        });
+    }
+    let _ = tokio::fs::remove_file(output).await;
+    Err(format!(
+        "Failed to fit the animation of {:?} in {} bytes, the smallest was {} bytes",
+        input, request.max_bytes.unwrap_or(0), smallest.unwrap_or(0),
+    ))
+}
+
+/// makes an animation next to every clip the cut stage made. unlike
+/// the thumbnails, the animation was asked for, so a failure fails the job
+pub async fn export_animations(key: String, request: Option<AnimationRequest>) -> TaskResult {
+    let request = match request {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+    let mut clips: Vec<Clip> = return_something_from_progress_holder(&key, &PROGHOLDER, |me| {
+        me.clone_var::<Vec<Clip>>("clips")
+    }).unwrap_or_default();
+    if clips.is_empty() {
+        return Err("Failed to find the clips to make animations from".into());
+    }
+
+    let mut animations = vec![];
+    for clip in clips.iter_mut() {
+        let media_info = probe_media(&clip.location).await?;
+        if !media_info.has_video {
+            return Err(format!("Cannot make an animation of {:?}, it has no video", clip.location));
+        }
+        let output = clip.location.with_extension(request.format.extension());
+        let (settings, size) = make_animation(&key, &clip.location, &output, &request, media_info.width).await?;
+        println!("made animation {:?} of {} bytes with {:?}", output, size, settings);
+        clip.animation_location = Some(output.clone());
+        animations.push(output);
+    }
+
+    let video_key: Option<String> = return_something_from_progress_holder(&key, &PROGHOLDER, |me| {
+        me.clone_var::<String>("video_key")
+    });
+    if let Some(video_key) = video_key {
+        set_animations(&video_key, &clips);
+    }
+    let mut progvars = ProgressVars::default();
+    progvars.insert_var("clips", Box::new(clips));
+    progvars.insert_var("animations", Box::new(animations));
+    Ok(Some(progvars))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gif_request(max_bytes: Option<u64>) -> AnimationRequest {
+        AnimationRequest { format: AnimationFormat::Gif, fps: Some(10), width: Some(320), max_bytes }
+    }
+
+    #[test]
+    fn lowers_quality_then_width() {
+        let attempts = animation_attempts(&gif_request(None), None);
+        assert_eq!(attempts, vec![AnimationSettings { fps: 10, width: 320, quality: 90 }]);
+
+        let attempts = animation_attempts(&gif_request(Some(2_000_000)), Some(1920));
+        let sizes: Vec<(u32, u32)> = attempts.iter().map(|a| (a.width, a.quality)).collect();
+        assert_eq!(sizes, vec![
+            (320, 90), (320, 70), (320, 50), (320, 30),
+            (240, 30), (180, 30), (134, 30), (100, 30),
+        ]);
+
+        // a narrow clip starts from its own width
+        let attempts = animation_attempts(&gif_request(Some(2_000_000)), Some(201));
+        let sizes: Vec<(u32, u32)> = attempts.iter().map(|a| (a.width, a.quality)).collect();
+        assert_eq!(sizes, vec![
+            (200, 90), (200, 70), (200, 50), (200, 30), (150, 30), (112, 30), (84, 30),
+        ]);
+    }
+
+    #[test]
+    fn builds_palette_filters() {
+        let settings = AnimationSettings { fps: 12, width: 480, quality: 50 };
+        assert_eq!(
+            palettegen_filter(&settings),
+            "fps=12,scale='min(480,iw)':-2:flags=lanczos,palettegen=max_colors=128:stats_mode=diff",
+        );
+        assert_eq!(
+            paletteuse_filter(&settings),
+            "fps=12,scale='min(480,iw)':-2:flags=lanczos[frames];[frames][1:v]paletteuse=dither=bayer:bayer_scale=2:diff_mode=rectangle",
+        );
+    }
+
+    #[test]
+    fn validates_animation_requests() {
+        assert!(validate_animation_request(&gif_request(Some(1000)), None).is_ok());
+        assert!(validate_animation_request(&gif_request(Some(0)), None).is_err());
+        let request = AnimationRequest { fps: Some(60), ..gif_request(None) };
+        assert!(validate_animation_request(&request, None).is_err());
+        let request = AnimationRequest { width: Some(10), ..gif_request(None) };
+        assert!(validate_animation_request(&request, None).is_err());
+        assert!(validate_animation_request(&gif_request(None), Some(MAX_ANIMATION_SECONDS)).is_ok());
+        assert!(validate_animation_request(&gif_request(None), Some(MAX_ANIMATION_SECONDS + 1.0)).is_err());
+        let request: AnimationRequest = serde_json::from_str(r#"{ "format": "webp" }"#).unwrap();
+        assert_eq!(request.format.extension(), "webp");
+    }
+}
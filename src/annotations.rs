@@ -0,0 +1,224 @@
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+
+use super::Annotations;
+use super::Clip;
+use super::DownloadedVideo;
+use super::pagination::Page;
+use super::pagination::SortOrder;
+use super::pagination::SortValue;
+use super::pagination::decode_cursor;
+use super::pagination::paginate;
+
+pub const MAX_LABEL_LENGTH: usize = 100;
+pub const MAX_LABELS: usize = 100;
+pub const MAX_NOTES_LENGTH: usize = 10_000;
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct CollectionSummary {
+    pub name: String,
+    pub videos: usize,
+    pub clips: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClipSort {
+    Created,
+    Name,
+    Duration,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ClipQuery {
+    pub tag: Option<String>,
+    pub collection: Option<String>,
+    /// only list the clips of the video with this id
+    pub video: Option<String>,
+    pub sort: Option<ClipSort>,
+    pub order: Option<SortOrder>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    pub cursor: Option<String>,
+}
+
+/// a clip along with the video it was cut from
+#[derive(Debug, Serialize)]
+pub struct ClipEntry {
+    pub url: String,
+    pub video_id: Option<String>,
+    pub clip: Clip,
+}
+
+/// trims the tags/collection names, removes empty ones and
+/// duplicates, while keeping the order they were given in
+pub fn normalize_labels(labels: Vec<String>, what: &str) -> Result<Vec<String>, String> {
+    let mut out: Vec<String> = vec![];
+    for label in labels {
+        let label = label.trim();
+        if label.is_empty() || out.iter().any(|l| l.eq_ignore_ascii_case(label)) {
+            continue;
+        }
+        if label.chars().count() > MAX_LABEL_LENGTH {
+            return Err(format!("{} '{}' is longer than {} characters", what, label, MAX_LABEL_LENGTH));
+        }
+        if label.chars().any(|c| c.is_control()) {
+            return Err(format!("{} '{}' contains invalid characters", what, label));
+        }
+        out.push(label.to_string());
+    }
+    if out.len() > MAX_LABELS {
+        return Err(format!("Cannot have more than {} {}s", MAX_LABELS, what.to_lowercase()));
+    }
+    Ok(out)
+}
+
+pub fn normalize_annotations(annotations: Annotations) -> Result<Annotations, String> {
+    let notes = annotations.notes.filter(|n| !n.trim().is_empty());
+    if let Some(ref notes) = notes {
+        if notes.len() > MAX_NOTES_LENGTH {
+            return Err(format!("Notes cannot be longer than {} bytes", MAX_NOTES_LENGTH));
+        }
+    }
+    Ok(Annotations {
+        tags: normalize_labels(annotations.tags, "Tag")?,
+        notes,
+        collections: normalize_labels(annotations.collections, "Collection")?,
+    })
+}
+
+/// counts how many videos and clips are in each collection
+pub fn summarize_collections<'a, I: Iterator<Item = &'a DownloadedVideo>>(
+    videos: I,
+) -> Vec<CollectionSummary> {
+    let mut counts: BTreeMap<String, (usize, usize)> = BTreeMap::new();
+    for video in videos {
+        for collection in video.annotations.collections.iter() {
+            counts.entry(collection.clone()).or_insert((0, 0)).0 += 1;
+        }
+        for clip in video.clips.iter() {
+            for collection in clip.annotations.collections.iter() {
+                counts.entry(collection.clone()).or_insert((0, 0)).1 += 1;
+            }
+        }
+    }
+    counts.into_iter()
+        .map(|(name, (videos, clips))| CollectionSummary { name, videos, clips })
+        .collect()
+}
+
+/// filters the clips of every video by the query,
+/// sorts them, and returns the page the cursor/offset/limit point to
+pub fn query_clips(
+    videos: Vec<(String, DownloadedVideo)>,
+    query: &ClipQuery,
+) -> Result<Page<ClipEntry>, String> {
+    let cursor = match query.cursor {
+        Some(ref c) => Some(decode_cursor(c)?),
+        None => None,
+    };
+    let sort = query.sort.unwrap_or(ClipSort::Created);
+    let order = query.order.unwrap_or(match sort {
+        ClipSort::Name => SortOrder::Asc,
+        _ => SortOrder::Desc,
+    });
+
+    let mut entries: Vec<(SortValue, String, ClipEntry)> = vec![];
+    for (_, video) in videos {
+        let video_id = video.id();
+        let url = video.url().cloned().unwrap_or_default();
+        if query.video.is_some() && query.video != video_id {
+            continue;
+        }
+        for clip in video.clips {
+            if let Some(ref tag) = query.tag {
+                if !clip.annotations.has_tag(tag) {
+                    continue;
+                }
+            }
+            if let Some(ref collection) = query.collection {
+                if !clip.annotations.in_collection(collection) {
+                    continue;
+                }
+            }
+            let key = clip.id().unwrap_or_default();
+            let value = match sort {
+                ClipSort::Created => clip.created_at.map(|c| c as f64).into(),
+                ClipSort::Name => Some(key.to_lowercase()).into(),
+                ClipSort::Duration => clip.duration.into(),
+            };
+            entries.push((value, key, ClipEntry { url: url.clone(), video_id: video_id.clone(), clip }));
+        }
+    }
+
+    Ok(paginate(entries, order, cursor.as_ref(), query.offset.unwrap_or(0), query.limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_annotations() {
+        let annotations = Annotations {
+            tags: vec![" interview ".into(), "".into(), "Interview".into(), "b-roll".into()],
+            notes: Some("   ".into()),
+            collections: vec!["Project X".into()],
+        };
+        let normalized = normalize_annotations(annotations).unwrap();
+        assert_eq!(normalized.tags, vec!["interview", "b-roll"]);
+        assert_eq!(normalized.notes, None);
+        assert_eq!(normalized.collections, vec!["Project X"]);
+        assert!(normalized.has_tag("INTERVIEW"));
+
+        let too_long: String = std::iter::repeat('a').take(MAX_LABEL_LENGTH + 1).collect();
+        assert!(normalize_labels(vec![too_long], "Tag").is_err());
+        assert!(normalize_labels(vec!["a\nb".into()], "Tag").is_err());
+    }
+
+    #[test]
+    fn summarizes_collections() {
+        let mut video = DownloadedVideo::default();
+        video.annotations.collections = vec!["a".into()];
+        let mut clip = Clip::default();
+        clip.annotations.collections = vec!["a".into(), "b".into()];
+        video.clips.push(clip);
+        let summaries = summarize_collections(vec![video].iter());
+        assert_eq!(summaries, vec![
+            CollectionSummary { name: "a".into(), videos: 1, clips: 1 },
+            CollectionSummary { name: "b".into(), videos: 0, clips: 1 },
+        ]);
+    }
+
+    #[test]
+    fn query_clips_filters_by_annotations() {
+        let mut video = DownloadedVideo::default();
+        video.location = "/dl/source.mp4".into();
+        video.source_urls = vec!["url".into()];
+        for (name, created_at, tag) in vec![("a", 1, "funny"), ("b", 2, "sad"), ("c", 3, "Funny")] {
+            let mut clip = Clip::default();
+            clip.location = format!("/dl/{}.mp4", name).into();
+            clip.created_at = Some(created_at);
+            clip.annotations.tags = vec![tag.into()];
+            video.clips.push(clip);
+        }
+        let videos = vec![("youtube:abc".to_string(), video)];
+        let clip_ids = |page: Page<ClipEntry>| -> Vec<String> {
+            page.items.into_iter().filter_map(|e| e.clip.id()).collect()
+        };
+
+        let mut query = ClipQuery::default();
+        assert_eq!(clip_ids(query_clips(videos.clone(), &query).unwrap()), vec!["c", "b", "a"]);
+
+        query.tag = Some("funny".into());
+        assert_eq!(clip_ids(query_clips(videos.clone(), &query).unwrap()), vec!["c", "a"]);
+
+        query.video = Some("other".into());
+        assert!(query_clips(videos.clone(), &query).unwrap().items.is_empty());
+        query.video = Some("source".into());
+        query.sort = Some(ClipSort::Name);
+        let page = query_clips(videos.clone(), &query).unwrap();
+        assert_eq!(page.items[0].url, "url");
+        assert_eq!(clip_ids(page), vec!["a", "c"]);
+    }
+}
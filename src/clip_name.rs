@@ -0,0 +1,143 @@
+use std::path::Path;
+use std::path::PathBuf;
+
+pub const MAX_CLIP_NAME_LENGTH: usize = 100;
+/// if we cant find a free name after this many attempts
+/// something is probably wrong
+pub const MAX_COLLISION_SUFFIX: u32 = 1000;
+
+pub fn is_allowed_clip_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' || c == ' '
+}
+
+/// turn a user supplied clip name into something that is safe
+/// to use as a file name inside the download directory.
+/// any character that is not alphanumeric, '-', '_', '.', or ' '
+/// gets replaced with '_', which also means path separators
+/// can never make it through. leading dots/dashes/spaces are removed
+/// so we cannot create hidden files, or names that look like
+/// command line options.
+pub fn sanitize_clip_name<S: AsRef<str>>(name: S) -> Result<String, String> {
+    let mut out = String::with_capacity(name.as_ref().len());
+    let mut last_was_replaced = false;
+    for c in name.as_ref().trim().chars() {
+        if is_allowed_clip_name_char(c) {
+            out.push(c);
+            last_was_replaced = false;
+        } else if !last_was_replaced {
+            // collapse runs of bad characters into a single '_'
+            out.push('_');
+            last_was_replaced = true;
+        }
+    }
+
+    // ".." would still be there after replacing the separators,
+    // ie: "../../x" -> ".._.._x" so collapse any runs of dots
+    while out.contains("..") {
+        out = out.replace("..", ".");
+    }
+
+    let out = out.trim_start_matches(|c| c == '.' || c == '-' || c == ' ' || c == '_');
+    let out = out.trim_end_matches(|c| c == '.' || c == ' ');
+    let mut out = out.to_string();
+    if out.len() > MAX_CLIP_NAME_LENGTH {
+        // only ascii chars are allowed, so this
+        // is always on a char boundary
+        out.truncate(MAX_CLIP_NAME_LENGTH);
+        let trimmed_len = out.trim_end_matches(|c| c == '.' || c == ' ').len();
+        out.truncate(trimmed_len);
+    }
+
+    if out.is_empty() {
+        return Err(format!("Clip name '{}' does not contain any valid characters", name.as_ref()));
+    }
+    Ok(out)
+}
+
+/// sanitizes the name, and then finds a path inside of dir
+/// that does not exist yet. if <name>.<ext> is taken, we try
+/// <name>-1.<ext>, <name>-2.<ext>, etc. the path is reserved by
+/// creating an empty file there, so two jobs with the same name
+/// at the same time cannot both pick it
+pub fn unique_clip_path<S: AsRef<str>, P: AsRef<Path>>(
+    dir: P,
+    name: S,
+    extension: &str,
+) -> Result<PathBuf, String> {
+    let name = sanitize_clip_name(name)?;
+    for i in 0..MAX_COLLISION_SUFFIX {
+        let file_name = match i {
+            0 => format!("{}.{}", name, extension),
+            _ => format!("{}-{}.{}", name, i, extension),
+        };
+        let mut path = dir.as_ref().to_path_buf();
+        path.push(file_name);
+        match std::fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => return Ok(path),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(format!("Failed to create {:?}: {}", path, e)),
+        }
+    }
+    Err(format!("Failed to find an unused file name for clip '{}'", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_rejects_path_traversal() {
+        let hostile = [
+            "../../etc/x", "/etc/passwd", "..\\..\\windows\\x", "a/../../b",
+            "./.hidden", "-y", "--output=x", "x\0y", "foo\nbar",
+        ];
+        for name in hostile.iter() {
+            let sanitized = sanitize_clip_name(name).unwrap();
+            assert!(!sanitized.contains('/'), "{:?} -> {:?}", name, sanitized);
+            assert!(!sanitized.contains('\\'), "{:?} -> {:?}", name, sanitized);
+            assert!(!sanitized.contains(".."), "{:?} -> {:?}", name, sanitized);
+            assert!(!sanitized.starts_with('.'), "{:?} -> {:?}", name, sanitized);
+            assert!(!sanitized.starts_with('-'), "{:?} -> {:?}", name, sanitized);
+            assert!(sanitized.chars().all(is_allowed_clip_name_char));
+
+            let mut path = PathBuf::from("/downloads");
+            path.push(&sanitized);
+            assert_eq!(path.parent().unwrap(), Path::new("/downloads"));
+        }
+        assert_eq!(sanitize_clip_name("../../etc/x").unwrap(), "etc_x");
+    }
+
+    #[test]
+    fn sanitize_keeps_normal_names_and_limits_length() {
+        assert_eq!(sanitize_clip_name("my clip-1_final").unwrap(), "my clip-1_final");
+        assert_eq!(sanitize_clip_name("  spaced  ").unwrap(), "spaced");
+        assert!(sanitize_clip_name("").is_err());
+        assert!(sanitize_clip_name("../..").is_err());
+        assert!(sanitize_clip_name("///").is_err());
+
+        let long_name: String = std::iter::repeat('a').take(500).collect();
+        assert_eq!(sanitize_clip_name(long_name).unwrap().len(), MAX_CLIP_NAME_LENGTH);
+        // multibyte characters get replaced, so we never truncate mid char
+        let unicode_name: String = std::iter::repeat("é").take(200).collect();
+        assert!(sanitize_clip_name(unicode_name).is_err());
+    }
+
+    #[test]
+    fn unique_clip_path_adds_suffix_on_collision() {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("vidclipper-clip-name-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let first = unique_clip_path(&dir, "clip", "mp4").unwrap();
+        assert_eq!(first.file_name().unwrap(), "clip.mp4");
+        // the path is reserved right away, before anything is written to it
+        assert!(first.exists());
+        let second = unique_clip_path(&dir, "clip", "mp4").unwrap();
+        assert_eq!(second.file_name().unwrap(), "clip-1.mp4");
+        std::fs::write(&second, "").unwrap();
+        let third = unique_clip_path(&dir, "clip", "mp4").unwrap();
+        assert_eq!(third.file_name().unwrap(), "clip-2.mp4");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,178 @@
+use super::DownloadedVideo;
+use super::url_validation::parse_url;
+
+/// query params that only track where the link was shared from,
+/// or where to start playing, and dont change which video it is
+pub const IGNORED_QUERY_PARAMS: [&str; 11] = [
+    "t", "start", "feature", "si", "pp", "ab_channel", "fbclid", "gclid", "igshid", "ref", "ref_src",
+];
+
+pub const YOUTUBE_HOSTS: [&str; 4] = ["youtube.com", "youtu.be", "youtube-nocookie.com", "music.youtube.com"];
+
+/// hosts whose m. site serves the same videos at the same paths.
+/// on other hosts m. can be a different site entirely
+pub const MOBILE_HOSTS: [&str; 5] = ["youtube.com", "facebook.com", "twitter.com", "dailymotion.com", "twitch.tv"];
+
+/// the key a video is stored under. it is the same no matter
+/// which url the video was downloaded from
+pub fn content_key<S: AsRef<str>>(extractor: S, video_id: S) -> String {
+    format!("{}:{}", extractor.as_ref().to_lowercase(), video_id.as_ref())
+}
+
+/// the key of a video that did not come from youtube-dl,
+/// ie: a file that was found in or imported into the download dir
+pub fn local_content_key<S: AsRef<str>>(name: S) -> String {
+    format!("local:{}", name.as_ref())
+}
+
+/// the content key of the video if youtube-dl told us its
+/// extractor and id. otherwise None
+pub fn video_content_key(video: &DownloadedVideo) -> Option<String> {
+    match (&video.extractor, &video.video_id) {
+        (Some(extractor), Some(video_id)) if !extractor.is_empty() && !video_id.is_empty() => {
+            Some(content_key(extractor, video_id))
+        },
+        _ => None,
+    }
+}
+
+fn is_ignored_query_param(name: &str) -> bool {
+    let name = name.to_lowercase();
+    name.starts_with("utm_") || IGNORED_QUERY_PARAMS.iter().any(|p| *p == name)
+}
+
+/// youtube has many urls for the same video, ie: youtu.be/x,
+/// youtube.com/shorts/x, youtube.com/embed/x. turn them all
+/// into youtube.com/watch?v=x
+fn youtube_video_id(host: &str, path: &str, query: &[(String, String)]) -> Option<String> {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let id = if host == "youtu.be" {
+        segments.first().map(|s| s.to_string())
+    } else if segments.first() == Some(&"watch") {
+        query.iter().find(|(k, _)| k == "v").map(|(_, v)| v.clone())
+    } else {
+        match segments.as_slice() {
+            [kind, id, ..] if ["shorts", "embed", "v", "live"].contains(kind) => Some(id.to_string()),
+            _ => None,
+        }
+    };
+    id.filter(|id| !id.is_empty())
+}
+
+/// normalizes a url so that urls that point to the same video
+/// compare equal. the scheme and host are lowercased, http becomes https,
+/// www. is removed from the host (and m. for known hosts), the fragment
+/// and tracking query params are removed, and the remaining query params are sorted.
+/// if the url cannot be parsed, it is only trimmed
+pub fn normalize_url<S: AsRef<str>>(url: S) -> String {
+    let url = url.as_ref().trim();
+    let parsed = match parse_url(url) {
+        Ok(p) => p,
+        Err(_) => return url.to_string(),
+    };
+    let port = match (parsed.scheme.as_str(), parsed.port) {
+        ("http", Some(80)) | ("https", Some(443)) => None,
+        (_, port) => port,
+    };
+    let scheme = if parsed.scheme == "http" { "https".to_string() } else { parsed.scheme };
+    let host = parsed.host.trim_start_matches("www.");
+    let host = match host.strip_prefix("m.") {
+        Some(desktop_host) if MOBILE_HOSTS.contains(&desktop_host) => desktop_host,
+        _ => host,
+    }.to_string();
+
+    let rest = &url[(url.find("://").unwrap_or(0) + 3)..];
+    let rest = rest.split('#').next().unwrap_or("");
+    let path_start = rest.find(|c| c == '/' || c == '?').unwrap_or(rest.len());
+    let rest = &rest[path_start..];
+    let (path, query_string) = match rest.find('?') {
+        Some(i) => (&rest[..i], &rest[(i + 1)..]),
+        None => (rest, ""),
+    };
+    let path = path.trim_end_matches('/');
+
+    let mut query: Vec<(String, String)> = query_string.split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| match p.find('=') {
+            Some(i) => (p[..i].to_string(), p[(i + 1)..].to_string()),
+            None => (p.to_string(), String::new()),
+        })
+        .filter(|(k, _)| !is_ignored_query_param(k))
+        .collect();
+
+    if YOUTUBE_HOSTS.contains(&host.as_str()) {
+        if let Some(id) = youtube_video_id(&host, path, &query) {
+            return format!("https://youtube.com/watch?v={}", id);
+        }
+    }
+
+    query.sort();
+    let host_and_port = match port {
+        None => host,
+        Some(port) => format!("{}:{}", host, port),
+    };
+    let mut normalized = format!("{}://{}{}", scheme, host_and_port, path);
+    if !query.is_empty() {
+        let query_string = query.iter()
+            .map(|(k, v)| if v.is_empty() { k.clone() } else { format!("{}={}", k, v) })
+            .collect::<Vec<String>>()
+            .join("&");
+        normalized.push('?');
+        normalized.push_str(&query_string);
+    }
+    normalized
+}
+
+/// true if the video was downloaded from this url, or if
+/// this is the url of the video's webpage
+pub fn video_matches_url(video: &DownloadedVideo, normalized_url: &str) -> bool {
+    video.source_urls.iter().chain(video.webpage_url.iter())
+        .any(|u| normalize_url(u) == normalized_url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_youtube_urls() {
+        let expected = "https://youtube.com/watch?v=abc123";
+        assert_eq!(normalize_url("https://youtu.be/abc123"), expected);
+        assert_eq!(normalize_url("https://youtu.be/abc123?t=10"), expected);
+        assert_eq!(normalize_url("http://www.youtube.com/watch?v=abc123&t=10s&feature=share"), expected);
+        assert_eq!(normalize_url("https://m.youtube.com/watch?list=PL1&v=abc123&index=2"), expected);
+        assert_eq!(normalize_url("https://youtube.com/shorts/abc123/"), expected);
+        assert_eq!(normalize_url("https://www.youtube-nocookie.com/embed/abc123"), expected);
+        // not a video, so it is left mostly alone
+        assert_eq!(normalize_url("https://www.youtube.com/channel/xyz/"), "https://youtube.com/channel/xyz");
+    }
+
+    #[test]
+    fn normalizes_other_urls() {
+        assert_eq!(
+            normalize_url(" HTTP://Example.com:80/Video/1/?b=2&utm_source=x&a=1#comments "),
+            "https://example.com/Video/1?a=1&b=2",
+        );
+        assert_eq!(normalize_url("https://example.com:8443/x"), "https://example.com:8443/x");
+        assert_eq!(normalize_url("https://example.com"), "https://example.com");
+        assert_eq!(normalize_url("https://m.example.com/x"), "https://m.example.com/x");
+        assert_eq!(normalize_url("https://m.facebook.com/watch?v=1"), "https://facebook.com/watch?v=1");
+        assert_eq!(normalize_url("not a url"), "not a url");
+    }
+
+    #[test]
+    fn matches_videos_by_url() {
+        let mut video = DownloadedVideo::default();
+        video.extractor = Some("Youtube".into());
+        video.video_id = Some("abc123".into());
+        video.webpage_url = Some("https://www.youtube.com/watch?v=abc123".into());
+        video.source_urls = vec!["https://vimeo.com/1".into()];
+        assert_eq!(video_content_key(&video), Some("youtube:abc123".into()));
+        assert!(video_matches_url(&video, &normalize_url("https://youtu.be/abc123")));
+        assert!(video_matches_url(&video, &normalize_url("http://vimeo.com/1/")));
+        assert!(!video_matches_url(&video, &normalize_url("https://youtu.be/other")));
+
+        video.video_id = None;
+        assert_eq!(video_content_key(&video), None);
+    }
+}
@@ -9,8 +9,90 @@ use super::get_time_string_from_line;
 use super::PROGHOLDER;
 use super::use_me_from_progress_holder;
 use super::handle_child_exit;
+use super::unix_timestamp_now;
 use super::ProgressVars;
+use super::Chapter;
+use super::Clip;
+use super::SubtitleMode;
+use super::SubtitleTrack;
+use super::subtitles::escape_filter_path;
+use super::subtitles::parse_srt;
+use super::subtitles::pick_subtitle_track;
+use super::subtitles::shift_cues;
+use super::subtitles::write_srt;
+use super::clip_name::unique_clip_path;
+use super::media_probe::probe_media;
+use super::media_probe::CLIP_MARKER;
+use super::loudness::loudness_filter;
+use super::loudness::LOUDNORM_SAMPLE_RATE;
+use super::video_filters::build_video_filters;
+use super::get_config;
+use super::overlay::caption_filter;
+use super::overlay::image_overlay_input;
+use super::overlay::resolve_overlay_image;
+use super::overlay::OverlayInput;
+use super::overlay::TextOverlay;
+use super::silence::detect_silences;
+use super::silence::trim_silence;
+use super::disk_guard::ensure_disk_limits;
+use super::disk_guard::wait_for_child_within_disk_limits;
 
+/// the part of the source video that gets cut into a clip.
+/// both are in seconds
+#[derive(Debug, PartialEq)]
+pub struct ClipRange {
+    pub start: Option<f64>,
+    pub duration: Option<f64>,
+}
+
+impl From<&Chapter> for ClipRange {
+    fn from(chapter: &Chapter) -> Self {
+        let duration = chapter.end_time - chapter.start_time;
+        ClipRange {
+            start: Some(chapter.start_time),
+            duration: if duration > 0.0 { Some(duration) } else { None },
+        }
+    }
+}
+
+/// the ffmpeg filters a clip gets, besides the subtitles.
+/// they are applied in this order: video, subtitles, overlay, text
+#[derive(Clone, Debug, Default)]
+pub struct ClipFilters {
+    pub video: Vec<String>,
+    /// the watermark, which is a second input to ffmpeg
+    pub overlay: Option<OverlayInput>,
+    pub text: Vec<String>,
+    pub audio: Option<String>,
+}
+
+/// figure out which ranges of the source video to cut.
+/// if the request asks for chapters, the ranges come from the chapters,
+/// otherwise there is a single range from the start/duration of the request
+pub fn resolve_clip_ranges(
+    split_request: &SplitRequest,
+    chapters: &[Chapter],
+) -> Result<Vec<ClipRange>, String> {
+    if split_request.all_chapters {
+        if chapters.is_empty() {
+            return Err("Video does not have any chapters".into());
+        }
+        return Ok(chapters.iter().map(ClipRange::from).collect());
+    }
+    if let Some(chapter_index) = split_request.chapter {
+        return match chapters.get(chapter_index) {
+            Some(chapter) => Ok(vec![chapter.into()]),
+            None => Err(format!(
+                "Chapter {} does not exist, video has {} chapters",
+                chapter_index, chapters.len()
+            )),
+        };
+    }
+    Ok(vec![ClipRange {
+        start: split_request.start.map(|s| s as f64),
+        duration: split_request.duration.map(|d| d as f64),
+    }])
+}
 
 pub async fn cut_video(
     key: String,
@@ -37,10 +119,176 @@ pub async fn cut_video(
         }
     };
 
-    // output should have same extension as input
-    let mut cut_video_outpath = output_dir;
-    cut_video_outpath.push(&output_file_name);
-    cut_video_outpath.set_extension("mp4");
+    let chapters: Vec<Chapter> = return_something_from_progress_holder(&key, &PROGHOLDER, |me| {
+        me.clone_var::<Vec<Chapter>>("chapters")
+    }).unwrap_or_default();
+    let clip_ranges = resolve_clip_ranges(&split_request, &chapters)?;
+
+    // move the ranges inward past any dead air at
+    // their start or end before anything gets encoded
+    let needs_probe = split_request.trim_silence ||
+        split_request.loudness_target.is_some() ||
+        !split_request.video_filters.is_empty() ||
+        split_request.watermark.is_some() ||
+        split_request.caption.is_some();
+    let media_info = match needs_probe {
+        true => probe_media(&input_path).await.ok(),
+        false => None,
+    };
+    // theres no silence to find in a file without audio
+    let has_audio = media_info.as_ref().map_or(true, |info| info.has_audio);
+    let mut silence_trims = vec![];
+    let clip_ranges = if split_request.trim_silence && has_audio {
+        let total_duration = media_info.as_ref().and_then(|info| info.duration);
+        let mut trimmed_ranges = vec![];
+        for clip_range in clip_ranges {
+            let range_length = clip_range.duration.or_else(
+                || total_duration.map(|d| d - clip_range.start.unwrap_or(0.0)));
+            let silences = detect_silences(&input_string, &clip_range).await?;
+            let (trimmed_range, silence_trim) = trim_silence(&clip_range, &silences, range_length);
+            trimmed_ranges.push(trimmed_range);
+            silence_trims.push(silence_trim);
+        }
+        trimmed_ranges
+    } else {
+        clip_ranges
+    };
+
+    let subtitle = match split_request.subtitle_mode {
+        None => None,
+        Some(mode) => {
+            let subtitle_tracks: Vec<SubtitleTrack> = return_something_from_progress_holder(&key, &PROGHOLDER, |me| {
+                me.clone_var::<Vec<SubtitleTrack>>("subtitles")
+            }).unwrap_or_default();
+            match pick_subtitle_track(&subtitle_tracks, &split_request.subtitle_languages) {
+                Some(track) => Some((track.clone(), mode)),
+                None => return Err(format!(
+                    "Failed to find subtitles for languages: {:?}",
+                    split_request.subtitle_languages
+                )),
+            }
+        }
+    };
+
+    // the reframing is the same for every clip, and is checked
+    // against the size of the source before anything gets cut
+    let video_filters = if split_request.video_filters.is_empty() {
+        vec![]
+    } else {
+        match media_info.as_ref() {
+            Some(info) if info.has_video => build_video_filters(
+                &split_request.video_filters,
+                info.width.unwrap_or(0),
+                info.height.unwrap_or(0),
+            )?,
+            Some(_) => return Err("Cannot crop, scale, pad, or rotate a file without video".into()),
+            None => return Err("Failed to find the size of the source video".into()),
+        }
+    };
+
+    // overlays are skipped for audio only files, since the
+    // watermark from the config applies to every clip
+    let has_video = media_info.as_ref().map_or(true, |info| info.has_video);
+    let overlay = match split_request.watermark {
+        Some(ref watermark) if has_video => {
+            let import_dirs = get_config().map(|c| c.import_dirs).unwrap_or_default();
+            let image_path = resolve_overlay_image(watermark, &import_dirs)?;
+            Some(image_overlay_input(watermark, image_path))
+        },
+        _ => None,
+    };
+    let caption = split_request.caption.as_ref().filter(|_| has_video);
+
+    // dont evict the video we are about to cut
+    ensure_disk_limits(&output_dir, Some(&input_path)).await?;
+
+    let mut cut_video_outpaths = vec![];
+    let mut clips = vec![];
+    for (i, clip_range) in clip_ranges.iter().enumerate() {
+        let clip_name = match clip_ranges.len() {
+            1 => output_file_name.clone(),
+            _ => format!("{}-{}", output_file_name, i + 1),
+        };
+        let res: Result<PathBuf, String> = async {
+            // the first loudnorm pass measures the range,
+            // the second one is part of the cut
+            let filters = ClipFilters {
+                video: video_filters.clone(),
+                overlay: overlay.clone(),
+                text: vec![],
+                audio: match split_request.loudness_target {
+                    Some(target) if has_audio => loudness_filter(&input_string, clip_range, target).await?,
+                    _ => None,
+                },
+            };
+            // the name is sanitized so it cannot escape the output_dir,
+            // and if a clip with that name already exists we pick
+            // a suffixed name rather than overwriting it
+            let cut_video_outpath = unique_clip_path(&output_dir, &clip_name, "mp4")?;
+            let res = cut_clip_range(
+                &key,
+                &input_string,
+                &cut_video_outpath,
+                &output_dir,
+                clip_range,
+                subtitle.as_ref(),
+                &filters,
+                caption,
+                (i, clip_ranges.len()),
+            ).await;
+            if res.is_err() {
+                // dont leave a partial clip behind
+                let _ = std::fs::remove_file(&cut_video_outpath);
+            }
+            res.map(|_| cut_video_outpath)
+        }.await;
+        let cut_video_outpath = match res {
+            Ok(path) => path,
+            Err(e) => {
+                // the clips cut before this one would not
+                // be recorded anywhere, so they go too
+                for cut_video_outpath in cut_video_outpaths.iter() {
+                    let _ = std::fs::remove_file(cut_video_outpath);
+                }
+                return Err(e);
+            },
+        };
+        clips.push(Clip {
+            location: cut_video_outpath.clone(),
+            start: clip_range.start,
+            duration: clip_range.duration,
+            created_at: Some(unix_timestamp_now()),
+            ..Default::default()
+        });
+        cut_video_outpaths.push(cut_video_outpath);
+    }
+
+    let mut progvars = ProgressVars::default();
+    if let Some(first) = cut_video_outpaths.first() {
+        progvars.insert_var("cut_video", Box::new(first.clone()));
+    }
+    progvars.insert_var("cut_videos", Box::new(cut_video_outpaths));
+    progvars.insert_var("clips", Box::new(clips));
+    if !silence_trims.is_empty() {
+        progvars.insert_var("silence_trims", Box::new(silence_trims));
+    }
+    Ok(Some(progvars))
+}
+
+/// runs ffmpeg to cut a single range of the input into the output path.
+/// clip_position is (index of this clip, total number of clips)
+/// and is used to report the overall progress of the stage
+pub async fn cut_clip_range(
+    key: &String,
+    input_string: &String,
+    cut_video_outpath: &PathBuf,
+    output_dir: &PathBuf,
+    clip_range: &ClipRange,
+    subtitle: Option<&(SubtitleTrack, SubtitleMode)>,
+    filters: &ClipFilters,
+    caption: Option<&TextOverlay>,
+    clip_position: (usize, usize),
+) -> Result<(), String> {
     let output_file_name = match cut_video_outpath.to_str() {
         Some(o) => o.to_string(),
         None => {
@@ -48,6 +296,78 @@ pub async fn cut_video(
         }
     };
 
+    // the subtitles get shifted to the clip range
+    // and written next to the clip for ffmpeg to read
+    let shifted_subtitle = match subtitle {
+        None => None,
+        Some((track, mode)) => {
+            let shifted_path = write_shifted_subtitles(track, cut_video_outpath, clip_range).await?;
+            Some((shifted_path, track.language.clone(), *mode))
+        }
+    };
+    // same for the caption text, so it doesnt
+    // need to be escaped inside the filter
+    let mut filters = filters.clone();
+    let caption_path = match caption {
+        None => None,
+        Some(caption) => {
+            let caption_path = cut_video_outpath.with_extension("caption.txt");
+            let caption_string = caption_path.to_str().map_or_else(
+                || Err(format!("File path contains invalid characters: {:?}", caption_path)),
+                |s| Ok(s.to_string()))?;
+            tokio::fs::write(&caption_path, &caption.text).await.map_err(
+                |e| format!("Failed to write caption {:?}: {}", caption_path, e))?;
+            filters.text.push(caption_filter(caption, caption_string));
+            Some(caption_path)
+        }
+    };
+    let res = run_ffmpeg_cut(
+        key,
+        input_string,
+        output_file_name,
+        output_dir,
+        clip_range,
+        shifted_subtitle.as_ref(),
+        &filters,
+        clip_position,
+    ).await;
+    if let Some((shifted_path, _, _)) = shifted_subtitle {
+        let _ = tokio::fs::remove_file(shifted_path).await;
+    }
+    if let Some(caption_path) = caption_path {
+        let _ = tokio::fs::remove_file(caption_path).await;
+    }
+    res
+}
+
+pub async fn write_shifted_subtitles(
+    track: &SubtitleTrack,
+    cut_video_outpath: &PathBuf,
+    clip_range: &ClipRange,
+) -> Result<PathBuf, String> {
+    let contents = tokio::fs::read_to_string(&track.location).await.map_err(
+        |e| format!("Failed to read subtitles {:?}: {}", track.location, e))?;
+    let start_millis = (clip_range.start.unwrap_or(0.0) * 1000.0) as u64;
+    let duration_millis = clip_range.duration.map(|d| (d * 1000.0) as u64);
+    let cues = shift_cues(parse_srt(contents), start_millis, duration_millis);
+
+    let shifted_path = cut_video_outpath.with_extension(format!("{}.srt", track.language));
+    tokio::fs::write(&shifted_path, write_srt(&cues)).await.map_err(
+        |e| format!("Failed to write subtitles {:?}: {}", shifted_path, e))?;
+    Ok(shifted_path)
+}
+
+pub async fn run_ffmpeg_cut(
+    key: &String,
+    input_string: &String,
+    output_file_name: String,
+    output_dir: &PathBuf,
+    clip_range: &ClipRange,
+    subtitle: Option<&(PathBuf, String, SubtitleMode)>,
+    filters: &ClipFilters,
+    clip_position: (usize, usize),
+) -> Result<(), String> {
+
     let mut exe_and_args = vec![
         "ffmpeg".into(),
         "-loglevel".into(),
@@ -57,20 +377,111 @@ pub async fn cut_video(
         "-progress".into(),
         "pipe:1".into(),
     ];
-    exe_and_args.push("-i".into());
-    exe_and_args.push(input_string);
-    if let Some(ref start) = split_request.start {
+    // seek on the input so that the clip timestamps start at 0.
+    // since we re-encode, this is still frame accurate, and it
+    // means the shifted subtitles line up with the clip
+    if let Some(ref start) = clip_range.start {
         exe_and_args.push("-ss".into());
         exe_and_args.push(start.to_string());
     }
-    if let Some(ref duration) = split_request.duration {
+    exe_and_args.push("-i".into());
+    exe_and_args.push(input_string.clone());
+
+    // options that come before an -i apply to that input,
+    // so everything for the output is collected separately
+    // and added after the last input
+    let mut output_args: Vec<String> = vec![];
+    let mut maps: Vec<String> = vec![];
+    let mut next_input = 1;
+    // subtitles are burned in after reframing so they
+    // are not cropped off, and are sized for the output
+    let mut video_filters: Vec<String> = filters.video.clone();
+    match subtitle {
+        Some((subtitle_path, language, SubtitleMode::Soft)) => {
+            let subtitle_string = subtitle_path.to_str().map_or_else(
+                || Err(format!("File path contains invalid characters: {:?}", subtitle_path)),
+                |s| Ok(s.to_string()))?;
+            exe_and_args.push("-i".into());
+            exe_and_args.push(subtitle_string);
+            maps.push(format!("{}:0", next_input));
+            next_input += 1;
+            output_args.push("-scodec".into());
+            output_args.push("mov_text".into());
+            output_args.push("-metadata:s:s:0".into());
+            output_args.push(format!("language={}", language));
+        },
+        Some((subtitle_path, _, SubtitleMode::Burn)) => {
+            let subtitle_string = subtitle_path.to_str().map_or_else(
+                || Err(format!("File path contains invalid characters: {:?}", subtitle_path)),
+                |s| Ok(s.to_string()))?;
+            video_filters.push(format!("subtitles={}", escape_filter_path(subtitle_string)));
+        },
+        None => {},
+    }
+
+    match filters.overlay {
+        Some(ref overlay) => {
+            let overlay_string = overlay.path.to_str().map_or_else(
+                || Err(format!("File path contains invalid characters: {:?}", overlay.path)),
+                |s| Ok(s.to_string()))?;
+            exe_and_args.push("-i".into());
+            exe_and_args.push(overlay_string);
+            // the watermark needs a filter graph with two inputs
+            let base_filters = match video_filters.is_empty() {
+                true => "null".to_string(),
+                false => video_filters.join(","),
+            };
+            let mut overlay_filters = vec![overlay.overlay.clone()];
+            overlay_filters.extend(filters.text.iter().cloned());
+            let filter_graph = format!(
+                "[0:v]{}[base];[{}:v]{}[watermark];[base][watermark]{}[video]",
+                base_filters, next_input, overlay.image_filters.join(","), overlay_filters.join(","),
+            );
+            exe_and_args.push("-filter_complex".into());
+            exe_and_args.push(filter_graph);
+            maps.insert(0, "[video]".into());
+            maps.insert(1, "0:a?".into());
+        },
+        None => {
+            video_filters.extend(filters.text.iter().cloned());
+            if !video_filters.is_empty() {
+                exe_and_args.push("-vf".into());
+                exe_and_args.push(video_filters.join(","));
+            }
+            // once anything is mapped, ffmpeg
+            // stops picking the streams itself
+            if !maps.is_empty() {
+                maps.insert(0, "0:v?".into());
+                maps.insert(1, "0:a?".into());
+            }
+        },
+    }
+    for map in maps {
+        exe_and_args.push("-map".into());
+        exe_and_args.push(map);
+    }
+    exe_and_args.extend(output_args);
+    if let Some(ref duration) = clip_range.duration {
         exe_and_args.push("-t".into());
         exe_and_args.push(duration.to_string());
     }
+    if let Some(ref audio_filter) = filters.audio {
+        exe_and_args.push("-af".into());
+        exe_and_args.push(audio_filter.clone());
+        exe_and_args.push("-ar".into());
+        exe_and_args.push(LOUDNORM_SAMPLE_RATE.to_string());
+    }
+
     exe_and_args.push("-acodec".into());
     exe_and_args.push("aac".into());
     exe_and_args.push("-vcodec".into());
     exe_and_args.push("h264".into());
+    // so that fsck does not adopt the clip as a source
+    // if the source it was cut from is ever removed
+    exe_and_args.push("-metadata".into());
+    exe_and_args.push(format!("comment={}", CLIP_MARKER));
+    // the output path was reserved by unique_clip_path, so
+    // the only thing this overwrites is that empty file
     exe_and_args.push("-y".into());
     exe_and_args.push(output_file_name);
     println!("running with commands:\n{:#?}", exe_and_args);
@@ -80,10 +491,12 @@ pub async fn cut_video(
     // pass that reader into the following future spawned on tokio
     let (child, mut reader, _) = setup_child_and_reader(cmd)?;
 
-    let duration_millis = match split_request.duration {
+    let duration_millis = match clip_range.duration {
         None => 1, // TODO: find the total duration of the input file via ffprobe
-        Some(d) => d * 1000,
+        Some(d) => (d * 1000.0) as u32,
     };
+    let (clip_index, num_clips) = clip_position;
+    let key = key.clone();
     tokio::spawn(async move {
         loop {
             let thing = reader.next_line().await;
@@ -109,6 +522,9 @@ pub async fn cut_video(
                 let mut progress = time_millis as f64 / duration_millis as f64;
                 if progress > 1.0 { progress = 1.0 };
                 println!("time_millis: {}, duration_millis: {}, progress: {}", time_millis, duration_millis, progress);
+                // if we are cutting several clips, each one is
+                // an equal part of the overall progress
+                let progress = (clip_index as f64 + progress) / num_clips as f64;
                 use_me_from_progress_holder(&key, &PROGHOLDER, |me| {
                     me.inc_progress_percent_normalized(progress);
                 });
@@ -120,14 +536,48 @@ pub async fn cut_video(
     // itself. as we await this child process, the above async future can run
     // whenever the reader finds a next line. But after here we actually return
     // our TaskResult that is read by the progresslib2
-    let child_status = child.await;
-
-    handle_child_exit(child_status).map_or_else(
-        |e| Err(e),
-        |_| {
-            let mut progvars = ProgressVars::default();
-            progvars.insert_var("cut_video", Box::new(cut_video_outpath));
-            Ok(Some(progvars))
-        },
-    )
+    let child_status = wait_for_child_within_disk_limits(child, output_dir.clone()).await;
+    handle_child_exit(child_status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_chapters() -> Vec<Chapter> {
+        vec![
+            Chapter { start_time: 0.0, end_time: 10.5, title: Some("intro".into()) },
+            Chapter { start_time: 10.5, end_time: 60.0, title: Some("main".into()) },
+        ]
+    }
+
+    #[test]
+    fn clip_ranges_from_start_and_duration() {
+        let split_request = SplitRequest {
+            start: Some(5),
+            duration: Some(10),
+            ..Default::default()
+        };
+        let ranges = resolve_clip_ranges(&split_request, &test_chapters()).unwrap();
+        assert_eq!(ranges, vec![ClipRange { start: Some(5.0), duration: Some(10.0) }]);
+    }
+
+    #[test]
+    fn clip_ranges_from_chapters() {
+        let mut split_request = SplitRequest {
+            chapter: Some(1),
+            ..Default::default()
+        };
+        let ranges = resolve_clip_ranges(&split_request, &test_chapters()).unwrap();
+        assert_eq!(ranges, vec![ClipRange { start: Some(10.5), duration: Some(49.5) }]);
+
+        split_request.chapter = Some(2);
+        assert!(resolve_clip_ranges(&split_request, &test_chapters()).is_err());
+
+        split_request.all_chapters = true;
+        let ranges = resolve_clip_ranges(&split_request, &test_chapters()).unwrap();
+        assert_eq!(ranges.len(), 2);
+        assert_eq!(ranges[0], ClipRange { start: Some(0.0), duration: Some(10.5) });
+        assert!(resolve_clip_ranges(&split_request, &[]).is_err());
+    }
 }
@@ -3,10 +3,154 @@ use std::path::PathBuf;
 use std::{fmt::Display, collections::HashMap};
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
+use super::loudness::validate_loudness_target;
+use super::overlay::ImageOverlay;
+use super::overlay::TextOverlay;
+use super::overlay::validate_image_overlay;
+use super::overlay::validate_text_overlay;
+
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct Config {
     pub download_dir: PathBuf,
     pub frontend_dir: PathBuf,
+    /// url schemes that are allowed to be passed to youtube-dl.
+    /// defaults to http and https
+    #[serde(default = "default_allowed_schemes")]
+    pub allowed_schemes: Vec<String>,
+    /// if non-empty, only urls whose host matches one of these
+    /// patterns can be downloaded. a pattern of "example.com"
+    /// matches example.com and any of its subdomains
+    #[serde(default)]
+    pub domain_allowlist: Vec<String>,
+    /// urls whose host matches any of these patterns are rejected
+    #[serde(default)]
+    pub domain_denylist: Vec<String>,
+    /// by default we refuse to download from hosts that resolve
+    /// to loopback/private/link-local addresses
+    #[serde(default)]
+    pub allow_private_addresses: bool,
+    /// jobs fail if the download dir grows beyond this many bytes
+    #[serde(default)]
+    pub max_download_dir_bytes: Option<u64>,
+    /// jobs fail if the disk that holds the download
+    /// dir has less than this many bytes available
+    #[serde(default)]
+    pub min_free_space_bytes: Option<u64>,
+    /// if one of the above limits is exceeded before a job starts,
+    /// delete the least recently clipped source videos until
+    /// we are back within the limits
+    #[serde(default)]
+    pub evict_least_recently_clipped: bool,
+    /// youtube-dl format selector used when a download
+    /// request does not specify any format options
+    #[serde(default)]
+    pub default_format: Option<String>,
+    /// keep the info.json that youtube-dl writes next
+    /// to the video instead of deleting it after reading it
+    #[serde(default)]
+    pub keep_info_json: bool,
+    /// directories that /import is allowed to import files from.
+    /// importing server side files is disabled if this is empty
+    #[serde(default)]
+    pub import_dirs: Vec<PathBuf>,
+    /// uploads to /import that are larger than this are rejected.
+    /// defaults to 16GiB, set it to null to allow any size
+    #[serde(default = "default_max_upload_bytes")]
+    pub max_upload_bytes: Option<u64>,
+    /// normalize the loudness of every clip to this many LUFS,
+    /// unless the request sets its own loudness_target
+    #[serde(default)]
+    pub loudness_target: Option<f64>,
+    /// an image drawn on every clip, unless the request sets
+    /// its own watermark. its path can be anywhere on the server
+    #[serde(default)]
+    pub watermark: Option<ImageOverlay>,
+    /// text drawn on every clip, unless the request sets its own caption
+    #[serde(default)]
+    pub caption: Option<TextOverlay>,
+}
+
+/// fields that users can edit to organize their library
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Annotations {
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub notes: Option<String>,
+    /// names of the collections this belongs to
+    #[serde(default)]
+    pub collections: Vec<String>,
+}
+
+impl Annotations {
+    pub fn has_tag<S: AsRef<str>>(&self, tag: S) -> bool {
+        self.tags.iter().any(|t| t.eq_ignore_ascii_case(tag.as_ref()))
+    }
+
+    pub fn in_collection<S: AsRef<str>>(&self, collection: S) -> bool {
+        self.collections.iter().any(|c| c == collection.as_ref())
+    }
+}
+
+/// a clip that was cut from a downloaded video
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+pub struct Clip {
+    pub location: PathBuf,
+    /// in seconds, relative to the source video
+    pub start: Option<f64>,
+    pub duration: Option<f64>,
+    pub created_at: Option<u64>,
+    pub thumbnail_location: Option<PathBuf>,
+    /// the animated gif or webp of the clip, if one was asked for
+    pub animation_location: Option<PathBuf>,
+    #[serde(default)]
+    pub annotations: Annotations,
+}
+
+impl Clip {
+    /// clip names are unique within the download dir,
+    /// so the file name without the extension is the id
+    pub fn id(&self) -> Option<String> {
+        self.location.file_stem()?.to_str().map(|s| s.to_string())
+    }
+}
+
+/// a sprite sheet of frames from the video, and a WebVTT
+/// track that says which tile to show for which time range
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Storyboard {
+    pub location: PathBuf,
+    pub vtt_location: PathBuf,
+    /// seconds between tiles
+    pub interval: f64,
+    /// of the video, in seconds
+    pub duration: f64,
+    pub tiles: u32,
+    pub tile_width: u32,
+    pub tile_height: u32,
+    pub columns: u32,
+    pub rows: u32,
+}
+
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+pub struct SubtitleTrack {
+    pub language: String,
+    pub location: PathBuf,
+}
+
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+pub struct Chapter {
+    pub start_time: f64,
+    pub end_time: f64,
+    pub title: Option<String>,
+}
+
+pub fn default_allowed_schemes() -> Vec<String> {
+    vec!["http".into(), "https".into()]
+}
+
+pub fn default_max_upload_bytes() -> Option<u64> {
+    Some(16 * 1024 * 1024 * 1024)
 }
 
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
@@ -15,8 +159,86 @@ pub struct DownloadedVideo {
     pub thumbnail_location: Option<PathBuf>,
     pub title: Option<String>,
     pub description: Option<String>,
+    /// unix timestamp (seconds) of when this video was added to the library
+    #[serde(default)]
+    pub added_at: Option<u64>,
+    /// unix timestamp (seconds) of the last time a job used this video
+    #[serde(default)]
+    pub last_clipped_at: Option<u64>,
+    /// the youtube-dl format that was downloaded
+    #[serde(default)]
+    pub format: Option<String>,
+    /// the format selector it was downloaded with. None
+    /// means youtube-dl picked its default format
+    #[serde(default)]
+    pub format_selector: Option<String>,
+    #[serde(default)]
+    pub uploader: Option<String>,
+    #[serde(default)]
+    pub upload_date: Option<String>,
+    /// in seconds
+    #[serde(default)]
+    pub duration: Option<f64>,
+    #[serde(default)]
+    pub view_count: Option<u64>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub chapters: Vec<Chapter>,
+    #[serde(default)]
+    pub webpage_url: Option<String>,
+    /// every url that this video was requested with
+    #[serde(default)]
+    pub source_urls: Vec<String>,
+    #[serde(default)]
+    pub extractor: Option<String>,
+    #[serde(default)]
+    pub video_id: Option<String>,
+    /// only set if the config says to keep the info.json
+    #[serde(default)]
+    pub info_json_location: Option<PathBuf>,
+    #[serde(default)]
+    pub subtitles: Vec<SubtitleTrack>,
+    #[serde(default)]
+    pub annotations: Annotations,
+    #[serde(default)]
+    pub clips: Vec<Clip>,
+    #[serde(default)]
+    pub storyboard: Option<Storyboard>,
+    /// peaks of the audio at several zoom levels
+    #[serde(default)]
+    pub waveform_location: Option<PathBuf>,
+    /// times (in seconds) where a new scene starts. suggested
+    /// cut points. None if the video wasnt analyzed yet
+    #[serde(default)]
+    pub scenes: Option<Vec<f64>>,
+    /// the analyses (storyboard, waveform, scenes) that failed
+    /// on this video, so that they are not tried again
+    #[serde(default)]
+    pub failed_analyses: Vec<String>,
 }
 
+impl DownloadedVideo {
+    /// the id the routes use to refer to this video. it is the
+    /// file name of the downloaded video without the extension,
+    /// which is the unique key it was downloaded with
+    pub fn id(&self) -> Option<String> {
+        self.location.file_stem()?.to_str().map(|s| s.to_string())
+    }
+
+    /// the url this video was first requested with
+    pub fn url(&self) -> Option<&String> {
+        self.source_urls.first().or(self.webpage_url.as_ref())
+    }
+
+    pub fn analysis_failed(&self, analysis: &str) -> bool {
+        self.failed_analyses.iter().any(|a| a == analysis)
+    }
+}
+
+/// keyed by extractor:video_id so that different urls of the
+/// same video end up in the same entry. videos that youtube-dl
+/// did not give an id for are keyed by their normalized url
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct DownloadedVideos {
     #[serde(flatten)]
@@ -78,6 +300,15 @@ pub fn initialize_data<P: AsRef<Path>>(path: P) -> Result<DownloadedVideos, Stri
 pub fn initialize_config<P: AsRef<Path>>(path: P) -> Result<Config, String> {
     let res: Result<Config, String> = initialize_object(path);
     if let Ok(ref config) = &res {
+        if let Some(target) = config.loudness_target {
+            validate_loudness_target(target).map_err(|e| format!("Invalid loudness_target in config: {}", e))?;
+        }
+        if let Some(ref watermark) = config.watermark {
+            validate_image_overlay(watermark).map_err(|e| format!("Invalid watermark in config: {}", e))?;
+        }
+        if let Some(ref caption) = config.caption {
+            validate_text_overlay(caption).map_err(|e| format!("Invalid caption in config: {}", e))?;
+        }
         if !config.download_dir.exists() {
             std::fs::create_dir_all(&config.download_dir).map_err(string_error)?;
         }
@@ -0,0 +1,227 @@
+use std::io::Error;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::time::Duration;
+use futures::future::Either;
+use futures::future::select;
+use futures_timer::Delay;
+use tokio::process::Child;
+use tokio::fs;
+
+use super::Annotations;
+use super::Config;
+use super::DATAHOLDER;
+use super::SEARCHINDEX;
+use super::FAILED_TO_ACQUIRE_LOCK;
+use super::DATA_STORE_PATH;
+use super::create_command;
+use super::data_store;
+use super::fmt_string_error;
+use super::get_config;
+use super::handle_child_exit;
+use super::in_use_video_keys;
+
+/// how often we check the disk while a child process
+/// is writing into the download dir
+pub const DISK_WATCH_INTERVAL_MILLIS: u64 = 2000;
+
+pub fn limits_enabled(config: &Config) -> bool {
+    config.max_download_dir_bytes.is_some() || config.min_free_space_bytes.is_some()
+}
+
+/// parses the output of `df -Pk <dir>` and returns
+/// the available space in bytes
+pub fn parse_df_output<S: AsRef<str>>(output: S) -> Option<u64> {
+    // first line is the header, the line after is the one we want:
+    // Filesystem 1024-blocks Used Available Capacity Mounted on
+    let line = output.as_ref().lines().skip(1).find(|l| !l.trim().is_empty())?;
+    let available_kb = line.split_whitespace().nth(3)?;
+    let available_kb = available_kb.parse::<u64>().ok()?;
+    Some(available_kb * 1024)
+}
+
+pub async fn free_space_bytes<P: AsRef<Path>>(dir: P) -> Result<u64, String> {
+    let dir_string = dir.as_ref().to_str().map_or_else(
+        || Err(format!("File path contains invalid characters: {:?}", dir.as_ref())),
+        |s| Ok(s))?;
+    let mut cmd = create_command(&["df", "-Pk", dir_string]);
+    let output = cmd.output().await.map_err(
+        |e| fmt_string_error("Failed to run df", e))?;
+    handle_child_exit(Ok(output.status))?;
+    let output_string = String::from_utf8_lossy(&output.stdout);
+    parse_df_output(&output_string).map_or_else(
+        || Err(format!("Failed to parse df output: {}", output_string)),
+        |o| Ok(o))
+}
+
+/// sums up the size of all of the files in the download dir,
+/// including the ones in its subdirs, ie: the assets
+pub async fn dir_size_bytes<P: AsRef<Path>>(dir: P) -> Result<u64, String> {
+    let mut dirs = vec![dir.as_ref().to_path_buf()];
+    let mut total = 0;
+    while let Some(dir) = dirs.pop() {
+        let mut readdir_entries = fs::read_dir(&dir).await.map_err(
+            |e| fmt_string_error("Failed to read dir", e))?;
+        while let Some(direntry) = readdir_entries.next_entry().await.map_err(
+            |e| fmt_string_error("Failed to iterate over dir", e))?
+        {
+            // symlinks are not followed, so this cant loop
+            if let Ok(metadata) = direntry.metadata().await {
+                if metadata.is_file() {
+                    total += metadata.len();
+                } else if metadata.is_dir() {
+                    dirs.push(direntry.path());
+                }
+            }
+        }
+    }
+    Ok(total)
+}
+
+/// returns an error describing which limit was exceeded, if any
+pub async fn check_disk_limits<P: AsRef<Path>>(dir: P, config: &Config) -> Result<(), String> {
+    if let Some(max_bytes) = config.max_download_dir_bytes {
+        let used = dir_size_bytes(&dir).await?;
+        if used > max_bytes {
+            return Err(format!(
+                "Download directory uses {} bytes which exceeds the quota of {} bytes",
+                used, max_bytes
+            ));
+        }
+    }
+    if let Some(min_free) = config.min_free_space_bytes {
+        let free = free_space_bytes(&dir).await?;
+        if free < min_free {
+            return Err(format!(
+                "Only {} bytes are free which is below the minimum of {} bytes",
+                free, min_free
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// removes the source video that was clipped the longest
+/// time ago from the data store, and deletes its files.
+/// source videos at the exclude path, and the ones that running
+/// jobs are using, are never evicted.
+/// returns false if there was nothing left to evict
+pub async fn evict_least_recently_clipped(exclude: Option<&Path>) -> Result<bool, String> {
+    let evicted = {
+        let mut guard = DATAHOLDER.lock().map_err(|_| FAILED_TO_ACQUIRE_LOCK.to_string())?;
+        // jobs mark their video while holding the library lock,
+        // so this has to be read while holding it too
+        let in_use = in_use_video_keys();
+        let videos = guard.as_mut();
+        let oldest_key = videos.iter()
+            .filter(|(_, video)| Some(video.location.as_path()) != exclude)
+            .filter(|(key, _)| !in_use.contains(key))
+            // the clips and annotations are the users work, and they are
+            // only tracked on their source. those sources are never evicted
+            .filter(|(_, video)| video.clips.is_empty() && video.annotations == Annotations::default())
+            .min_by_key(|(_, video)| video.last_clipped_at.unwrap_or(0))
+            .map(|(key, _)| key.clone());
+        let evicted = match oldest_key {
+            None => return Ok(false),
+            Some(key) => {
+                if let Ok(mut index) = SEARCHINDEX.lock() {
+                    index.remove(&key);
+                }
+                videos.remove(&key)
+            },
+        };
+        let _ = data_store::write_json_data(DATA_STORE_PATH, &guard);
+        evicted
+    };
+
+    if let Some(video) = evicted {
+        println!("evicting source video: {:?}", video.location);
+        let _ = fs::remove_file(&video.location).await;
+        if let Some(thumbnail_location) = video.thumbnail_location {
+            let _ = fs::remove_file(thumbnail_location).await;
+        }
+        if let Some(info_json_location) = video.info_json_location {
+            let _ = fs::remove_file(info_json_location).await;
+        }
+        for subtitle in video.subtitles {
+            let _ = fs::remove_file(subtitle.location).await;
+        }
+        if let Some(waveform_location) = video.waveform_location {
+            let _ = fs::remove_file(waveform_location).await;
+        }
+        if let Some(storyboard) = video.storyboard {
+            let _ = fs::remove_file(storyboard.location).await;
+            let _ = fs::remove_file(storyboard.vtt_location).await;
+        }
+    }
+    Ok(true)
+}
+
+/// should be called before a job writes anything into the download dir.
+/// if the limits are exceeded, and eviction is enabled, we evict
+/// source videos until we are back within the limits
+pub async fn ensure_disk_limits<P: AsRef<Path>>(dir: P, exclude: Option<&Path>) -> Result<(), String> {
+    let config = get_config()?;
+    if !limits_enabled(&config) {
+        return Ok(());
+    }
+    loop {
+        let err = match check_disk_limits(&dir, &config).await {
+            Ok(_) => return Ok(()),
+            Err(e) => e,
+        };
+        if !config.evict_least_recently_clipped {
+            return Err(err);
+        }
+        if !evict_least_recently_clipped(exclude).await? {
+            return Err(err);
+        }
+    }
+}
+
+/// periodically checks the disk limits, and only returns
+/// once one of them is exceeded
+pub async fn watch_disk_limits(dir: PathBuf, config: Config) -> String {
+    loop {
+        Delay::new(Duration::from_millis(DISK_WATCH_INTERVAL_MILLIS)).await;
+        if let Err(e) = check_disk_limits(&dir, &config).await {
+            return e;
+        }
+    }
+}
+
+/// awaits the child process, but kills it if the disk limits
+/// are exceeded while it is running
+pub async fn wait_for_child_within_disk_limits(
+    child: Child,
+    dir: PathBuf,
+) -> Result<ExitStatus, Error> {
+    let config = match get_config() {
+        Ok(config) if limits_enabled(&config) => config,
+        _ => return child.await,
+    };
+
+    let watcher = Box::pin(watch_disk_limits(dir, config));
+    match select(child, watcher).await {
+        Either::Left((child_status, _)) => child_status,
+        Either::Right((err, mut child)) => {
+            let _ = child.kill();
+            // let the child get reaped before we return
+            let _ = child.await;
+            Err(Error::new(std::io::ErrorKind::Other, err))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_parse_df_output() {
+        let output = "Filesystem     1024-blocks      Used Available Capacity Mounted on\n/dev/sda1        1000000    400000    600000      40% /\n";
+        assert_eq!(parse_df_output(output), Some(600000 * 1024));
+        assert_eq!(parse_df_output("Filesystem 1024-blocks Used Available Capacity Mounted on\n"), None);
+    }
+}
@@ -15,10 +15,22 @@ use tokio::io::Lines;
 use tokio::fs;
 use tokio::io::{BufReader, AsyncBufReadExt};
 use std::{path::{PathBuf, Path}, process::Stdio, fmt::Display};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::HashMap;
+use std::collections::HashSet;
+use futures::channel::oneshot;
+use futures::Future;
+use futures::future::FutureExt;
+use futures::future::Shared;
 
 #[path = "./youtubedl_stage.rs"]
 mod youtubedl_stage;
 use youtubedl_stage::download_video;
+use youtubedl_stage::fetch_metadata;
+use youtubedl_stage::format_selector;
+use youtubedl_stage::YtDlOptions;
+pub use youtubedl_stage::validate_format_selector;
+pub use youtubedl_stage::YtDlMetadata;
 
 #[path = "./cut_video_stage.rs"]
 mod cut_video_stage;
@@ -28,17 +40,165 @@ use cut_video_stage::cut_video;
 mod transcode_clip_stage;
 use transcode_clip_stage::transcode_clip;
 
+#[path = "./clip_name.rs"]
+mod clip_name;
+pub use clip_name::sanitize_clip_name;
+
+#[path = "./disk_guard.rs"]
+mod disk_guard;
+pub use disk_guard::ensure_disk_limits;
+
+#[path = "./subtitles.rs"]
+mod subtitles;
+pub use subtitles::validate_subtitle_language;
+use subtitles::pick_subtitle_track;
+
+#[path = "./pagination.rs"]
+mod pagination;
+pub use pagination::Page;
+use pagination::SortOrder;
+use pagination::SortValue;
+
+#[path = "./video_search.rs"]
+mod video_search;
+use video_search::SearchIndex;
+pub use video_search::VideoQuery;
+
+#[path = "./annotations.rs"]
+mod annotations;
+use annotations::normalize_annotations;
+pub use annotations::ClipEntry;
+pub use annotations::ClipQuery;
+pub use annotations::CollectionSummary;
+
+#[path = "./url_validation.rs"]
+mod url_validation;
+
+#[path = "./silence.rs"]
+mod silence;
+
+#[path = "./loudness.rs"]
+mod loudness;
+pub use loudness::validate_loudness_target;
+
+#[path = "./video_filters.rs"]
+mod video_filters;
+pub use video_filters::validate_video_filters;
+pub use video_filters::VideoFilterRequest;
+
+#[path = "./overlay.rs"]
+mod overlay;
+pub use overlay::validate_image_overlay;
+pub use overlay::validate_text_overlay;
+pub use overlay::ImageOverlay;
+pub use overlay::TextOverlay;
+
+#[path = "./animated_export.rs"]
+mod animated_export;
+use animated_export::export_animations;
+pub use animated_export::validate_animation_request;
+pub use animated_export::AnimationRequest;
+
+#[path = "./content_id.rs"]
+mod content_id;
+use content_id::normalize_url;
+use content_id::video_matches_url;
+
+#[path = "./media_probe.rs"]
+mod media_probe;
+
+#[path = "./library_check.rs"]
+mod library_check;
+pub use library_check::check_library;
+pub use library_check::startup_library_check;
+use library_check::referenced_files;
+pub use library_check::FsckRequest;
+
+#[path = "./thumbnails.rs"]
+mod thumbnails;
+use thumbnails::generate_thumbnails;
+pub use thumbnails::ThumbnailSelection;
+
+#[path = "./storyboard.rs"]
+mod storyboard;
+use storyboard::generate_storyboard;
+
+#[path = "./waveform.rs"]
+mod waveform;
+use waveform::generate_waveform;
+pub use waveform::pick_waveform_level;
+pub use waveform::read_waveform;
+
+#[path = "./scenes.rs"]
+mod scenes;
+use scenes::analyze_scenes;
+
+#[path = "./import.rs"]
+mod import;
+pub use import::importable_extension;
+pub use import::import_media_file;
+pub use import::import_server_file;
+pub use import::new_upload_path;
+pub use import::ImportMode;
+pub use import::ImportRequest;
+
+#[path = "./uploads.rs"]
+mod uploads;
+pub use uploads::append_upload_chunk;
+pub use uploads::cancel_upload;
+pub use uploads::complete_upload;
+pub use uploads::create_upload;
+pub use uploads::get_upload_status;
+pub use uploads::CreateUploadRequest;
+pub use uploads::UploadError;
+use uploads::UploadSessions;
+
 #[path = "./data_store.rs"]
 mod data_store;
 use data_store::initialize_config;
 use data_store::initialize_data;
 use data_store::Config;
 use data_store::DownloadedVideos;
-use data_store::DownloadedVideo;
+pub use data_store::DownloadedVideo;
+pub use data_store::Annotations;
+pub use data_store::Chapter;
+pub use data_store::Clip;
+pub use data_store::Storyboard;
+pub use data_store::SubtitleTrack;
 
 pub const FAILED_TO_ACQUIRE_LOCK: &'static str = "Failed to acquire lock";
+
+/// errors of the library operations that the routes
+/// need to tell apart. a lock we cant take is our fault,
+/// anything else is a problem with the request
+#[derive(Debug, PartialEq)]
+pub enum LibraryError {
+    LockFailed,
+    Invalid(String),
+}
+
+impl From<String> for LibraryError {
+    fn from(e: String) -> Self {
+        LibraryError::Invalid(e)
+    }
+}
+
+impl From<LibraryError> for String {
+    fn from(e: LibraryError) -> Self {
+        match e {
+            LibraryError::LockFailed => FAILED_TO_ACQUIRE_LOCK.into(),
+            LibraryError::Invalid(e) => e,
+        }
+    }
+}
 pub const DATA_STORE_PATH: &'static str = "vidclipper_data.json";
 pub const CONFIG_PATH: &'static str = "vidclipper_config.json";
+pub const UPLOADS_PATH: &'static str = "vidclipper_uploads.json";
+/// how long a /info lookup is cached for
+pub const METADATA_CACHE_SECONDS: u64 = 60 * 60;
+pub const METADATA_CACHE_MAX_ENTRIES: usize = 500;
+/// how many finished jobs we keep the info of
+pub const FINISHED_JOBS_MAX_ENTRIES: usize = 500;
 
 lazy_static! {
     pub static ref PROGHOLDER: Mutex<ProgressHolder<String>> = Mutex::new(
@@ -46,6 +206,16 @@ lazy_static! {
     );
     static ref DATAHOLDER: Mutex<DownloadedVideos> = Mutex::new(DownloadedVideos::default());
     static ref CONFIGHOLDER: RwLock<Config> = RwLock::new(Config::default());
+    static ref SEARCHINDEX: Mutex<SearchIndex> = Mutex::new(SearchIndex::default());
+    static ref JOBHOLDER: Mutex<HashMap<String, JobInfo>> = Mutex::new(HashMap::new());
+    static ref UPLOADHOLDER: Mutex<UploadSessions> = Mutex::new(UploadSessions::default());
+    /// normalized url -> the download of that url that is currently running
+    static ref INFLIGHTDOWNLOADS: Mutex<HashMap<String, InFlightDownload>> = Mutex::new(HashMap::new());
+    /// normalized url -> (unix timestamp of when it was fetched, metadata)
+    static ref METADATACACHE: Mutex<HashMap<String, (u64, YtDlMetadata)>> = Mutex::new(HashMap::new());
+    /// keys of the videos whose storyboard, waveform
+    /// and scenes are being made after their job finished
+    static ref ANALYZINGVIDEOS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
 }
 
 pub fn string_error(e: impl Display) -> String {
@@ -56,19 +226,158 @@ pub fn fmt_string_error<S: AsRef<str>>(s: S, e: impl Display) -> String {
     format!("{}: {}", s.as_ref(), e)
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+pub fn unix_timestamp_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct DownloadRequest {
     pub url: String,
     pub name: Option<String>,
     pub start: Option<u32>,
     pub duration: Option<u32>,
     pub transcode_extension: Option<String>,
+    /// a raw youtube-dl format selector. takes precedence
+    /// over max_height and audio_only
+    pub format: Option<String>,
+    pub max_height: Option<u32>,
+    #[serde(default)]
+    pub audio_only: bool,
+    /// index into the chapters of the video. if set, start
+    /// and duration are taken from that chapter
+    pub chapter: Option<usize>,
+    /// cut every chapter of the video into its own clip
+    #[serde(default)]
+    pub all_chapters: bool,
+    /// subtitle languages to download, in order of preference
+    #[serde(default)]
+    pub subtitle_languages: Vec<String>,
+    /// also allow youtube-dl to download automatically generated subtitles
+    #[serde(default)]
+    pub auto_subtitles: bool,
+    /// if set, the clip gets subtitles, either burned
+    /// into the video or as a separate subtitle stream
+    pub subtitle_mode: Option<SubtitleMode>,
+    /// how the frame for the clip thumbnails is picked.
+    /// defaults to the first scene change
+    #[serde(default)]
+    pub thumbnail: ThumbnailSelection,
+    /// trim the silence at the start and end of the clip
+    #[serde(default)]
+    pub trim_silence: bool,
+    /// normalize the loudness of the clip to this many LUFS.
+    /// defaults to loudness_target from the config
+    pub loudness_target: Option<f64>,
+    /// crop, rotate, target_aspect, scale, and pad
+    #[serde(flatten)]
+    pub video_filters: VideoFilterRequest,
+    /// an image drawn on top of the clip.
+    /// defaults to the watermark from the config
+    pub watermark: Option<ImageOverlay>,
+    /// text drawn on top of the clip.
+    /// defaults to the caption from the config
+    pub caption: Option<TextOverlay>,
+    /// also export every clip as an animated gif or webp
+    pub animation: Option<AnimationRequest>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SubtitleMode {
+    Soft,
+    Burn,
+}
+
+/// what we know about a job besides its progress
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JobInfo {
+    pub created_at: u64,
+    pub name: Option<String>,
+    pub url: String,
+    /// ids of the clips the job made, once they are cut. these can
+    /// differ from the name if a clip with that name already existed
+    #[serde(default)]
+    pub clips: Vec<String>,
+    /// set once the last stage is done, or a stage failed
+    #[serde(default)]
+    pub finished: bool,
+    /// the source video the job is using, once it is in the library
+    #[serde(skip)]
+    pub video_key: Option<String>,
+}
+
+/// what a job that downloaded a video hands to the
+/// jobs that were waiting for the same video
+#[derive(Clone, Debug)]
+pub struct SharedDownload {
+    pub video_key: String,
+    pub original_download_path: PathBuf,
+    pub chapters: Vec<Chapter>,
+    pub subtitles: Vec<SubtitleTrack>,
+}
+
+pub struct InFlightDownload {
+    /// what the download was started with. later jobs can only
+    /// wait for it if these options give them what they need
+    pub options: YtDlOptions,
+    pub result: Shared<oneshot::Receiver<Result<SharedDownload, String>>>,
+}
+
+/// removes the in flight download when the job that is downloading
+/// it finishes, or when its download stage gets dropped
+pub struct InFlightDownloadGuard(String);
+
+impl Drop for InFlightDownloadGuard {
+    fn drop(&mut self) {
+        if let Ok(mut guard) = INFLIGHTDOWNLOADS.lock() {
+            guard.remove(&self.0);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProgressSort {
+    Created,
+    Name,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ProgressQuery {
+    pub sort: Option<ProgressSort>,
+    pub order: Option<SortOrder>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    pub cursor: Option<String>,
+}
+
+impl ProgressQuery {
+    pub fn is_empty(&self) -> bool {
+        self.sort.is_none() && self.order.is_none() && self.limit.is_none() &&
+            self.offset.is_none() && self.cursor.is_none()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProgressEntry {
+    pub key: String,
+    pub info: Option<JobInfo>,
+    pub progress: serde_json::Value,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct SplitRequest {
     pub start: Option<u32>,
     pub duration: Option<u32>,
+    pub chapter: Option<usize>,
+    pub all_chapters: bool,
+    pub subtitle_languages: Vec<String>,
+    pub subtitle_mode: Option<SubtitleMode>,
+    pub trim_silence: bool,
+    pub loudness_target: Option<f64>,
+    pub video_filters: VideoFilterRequest,
+    pub watermark: Option<ImageOverlay>,
+    pub caption: Option<TextOverlay>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -91,6 +400,25 @@ pub fn random_download_name() -> String {
     random_string(8)
 }
 
+/// a video already in the library never gets youtube-dl ran on it
+/// again, so if it was downloaded without the subtitles this request
+/// wants, the cut stage would fail later on. returns false for
+/// urls that arent in the library yet, or only in another format
+pub fn library_video_missing_subtitles(download_request: &DownloadRequest) -> bool {
+    let format = match get_config() {
+        Ok(config) => format_selector(download_request, &config),
+        Err(_) => return false,
+    };
+    let normalized_url = normalize_url(&download_request.url);
+    match DATAHOLDER.lock() {
+        Err(_) => false,
+        Ok(guard) => guard.as_ref().values()
+            .find(|video| video_matches_url(video, &normalized_url) && video.format_selector == format)
+            .map(|video| pick_subtitle_track(&video.subtitles, &download_request.subtitle_languages).is_none())
+            .unwrap_or(false),
+    }
+}
+
 /// provide an array/vec of string references where the first
 /// element in the array is the executable name, and everything after
 /// that is the arguments. note that options like: "-o ./src" should
@@ -190,25 +518,31 @@ pub fn create_download_item(
     key: &String,
     download_request: DownloadRequest,
 ) -> ProgressItem {
-    let url = download_request.url;
-    let name = download_request.name;
+    let url = download_request.url.clone();
+    let name = download_request.name.clone();
     let name = match name {
         None => format!("clip.{}", &key),
         Some(ref s) => s.clone(),
     };
 
-    // if the url already has been downloaded
-    // we can skip the download stage
-    let url_exists_at = match DATAHOLDER.lock() {
-        Err(_) => None, // do nothing
-        Ok(mut guard) => match guard.as_mut().get_mut(&url) {
-            Some(path) => Some(path.location.to_owned()),
-            None => None
-        }
-    };
-    let download_dir = match CONFIGHOLDER.read() {
-        Err(_) => PathBuf::from("."),
-        Ok(config) => config.download_dir.to_owned(),
+    let (download_dir, format, loudness_target, watermark, caption) = match CONFIGHOLDER.read() {
+        Err(_) => (
+            PathBuf::from("."),
+            None,
+            download_request.loudness_target,
+            download_request.watermark.clone(),
+            download_request.caption.clone(),
+        ),
+        Ok(config) => (
+            config.download_dir.to_owned(),
+            format_selector(&download_request, &config),
+            download_request.loudness_target.or(config.loudness_target),
+            // only the watermark of the config can
+            // use images outside of the import_dirs
+            download_request.watermark.clone().or_else(|| config.watermark.clone().map(
+                |watermark| ImageOverlay { trusted: true, ..watermark })),
+            download_request.caption.clone().or_else(|| config.caption.clone()),
+        ),
     };
 
     // temporarily removing transcode stage.
@@ -222,67 +556,211 @@ pub fn create_download_item(
     //     }
     // );
 
-    let should_do_cut_stage = download_request.start.is_some() || download_request.duration.is_some();
-    let cut_stage = make_stage!(cut_video;
+    let should_do_cut_stage = download_request.start.is_some() ||
+        download_request.duration.is_some() ||
+        download_request.chapter.is_some() ||
+        download_request.all_chapters ||
+        download_request.trim_silence ||
+        download_request.loudness_target.is_some() ||
+        !download_request.video_filters.is_empty() ||
+        download_request.watermark.is_some() ||
+        download_request.caption.is_some() ||
+        download_request.animation.is_some();
+    let cut_future = cut_video(
         key.clone(),
         download_dir.clone(),
         name,
         SplitRequest {
             start: download_request.start,
             duration: download_request.duration,
+            chapter: download_request.chapter,
+            all_chapters: download_request.all_chapters,
+            subtitle_languages: download_request.subtitle_languages.clone(),
+            subtitle_mode: download_request.subtitle_mode,
+            trim_silence: download_request.trim_silence,
+            loudness_target,
+            video_filters: download_request.video_filters.clone(),
+            watermark,
+            caption,
         }
     );
+    let clips_key = key.clone();
+    let cut_task = async move {
+        let res = cut_future.await;
+        // remember the clips on the video they were cut from
+        // so they can be annotated and listed later
+        if let Ok(Some(progvars)) = &res {
+            let clips = progvars.clone_var::<Vec<Clip>>("clips").unwrap_or_default();
+            set_job_clips(&clips_key, &clips);
+            let video_key = return_something_from_progress_holder(&clips_key, &PROGHOLDER, |me| {
+                me.clone_var::<String>("video_key")
+            });
+            if let Some(video_key) = video_key {
+                record_clips(&video_key, clips);
+            }
+        }
+        res
+    };
+    let cut_stage = job_stage(key, "cut_video", cut_task);
+    let thumbnail_stage = job_stage(key, "generate_thumbnails", generate_thumbnails(
+        key.clone(),
+        download_request.thumbnail,
+    ));
+    let animation_stage = job_stage(key, "export_animations", export_animations(
+        key.clone(),
+        download_request.animation.clone(),
+    ));
+    let ytdl_options = YtDlOptions {
+        format,
+        write_subtitles: !download_request.subtitle_languages.is_empty() ||
+            download_request.auto_subtitles ||
+            download_request.subtitle_mode.is_some(),
+        subtitle_languages: download_request.subtitle_languages,
+        auto_subtitles: download_request.auto_subtitles,
+    };
+
+    // if the url already has been downloaded
+    // we can skip the download stage.
+    // the in flight lock is held while checking the library so that
+    // a download cannot finish in between the two checks
+    let normalized_url = normalize_url(&url);
+    let mut inflight_guard = INFLIGHTDOWNLOADS.lock().ok();
+    let url_exists_at = match DATAHOLDER.lock() {
+        Err(_) => None, // do nothing
+        Ok(mut guard) => {
+            // different urls can point to the same video,
+            // ie: youtu.be/x and youtube.com/watch?v=x
+            // a video that was downloaded in another format, ie: audio only,
+            // is downloaded again rather than clipped in the wrong format
+            guard.as_mut().iter_mut()
+                .find(|(_, video)| video_matches_url(video, &normalized_url) &&
+                    video.format_selector == ytdl_options.format)
+                .map(|(video_key, video)| {
+                    // while the library is still locked, so it cannot be evicted in between
+                    mark_video_in_use(key, video_key);
+                    video.last_clipped_at = Some(unix_timestamp_now());
+                    (video_key.clone(), video.location.to_owned(), video.chapters.clone(), video.subtitles.clone())
+                })
+        }
+    };
+    // if another job is already downloading this url, wait for it
+    // instead of downloading it again. otherwise, let later jobs
+    // know that this job is downloading it
+    let mut download_sender = None;
+    let inflight_download = match (&url_exists_at, inflight_guard.as_mut()) {
+        (Some(_), _) | (None, None) => None,
+        (None, Some(inflight)) => match inflight.get(&normalized_url) {
+            Some(download) if download.options.satisfies(&ytdl_options) => Some(download.result.clone()),
+            // its being downloaded without the subtitles/format we need
+            Some(_) => None,
+            None => {
+                let (sender, receiver) = oneshot::channel();
+                inflight.insert(normalized_url.clone(), InFlightDownload {
+                    options: ytdl_options.clone(),
+                    result: receiver.shared(),
+                });
+                download_sender = Some((sender, InFlightDownloadGuard(normalized_url.clone())));
+                None
+            },
+        },
+    };
+    drop(inflight_guard);
 
     let mut progitem = ProgressItem::new();
-    if let None = url_exists_at {
+    if let Some((video_key, original_download_path, chapters, subtitle_tracks)) = url_exists_at {
+        // if the url does already exist, we want to
+        // put a variable of the path where the other steps
+        // can find this url
+        progitem.insert_var("video_key", Box::new(video_key));
+        progitem.insert_var("original_download_path", Box::new(original_download_path));
+        progitem.insert_var("chapters", Box::new(chapters));
+        progitem.insert_var("subtitles", Box::new(subtitle_tracks));
+    } else if let Some(inflight_download) = inflight_download {
+        let key_clone = key.clone();
+        let wait_task = async move {
+            let shared_download = inflight_download.await.map_err(
+                |_| "The download this job was waiting for was cancelled".to_string())??;
+            {
+                let guard = DATAHOLDER.lock().map_err(|_| FAILED_TO_ACQUIRE_LOCK.to_string())?;
+                if !guard.as_ref().contains_key(&shared_download.video_key) {
+                    return Err("The video this job was waiting for was evicted".into());
+                }
+                mark_video_in_use(&key_clone, &shared_download.video_key);
+            }
+            let mut progvars = ProgressVars::default();
+            progvars.insert_var("video_key", Box::new(shared_download.video_key));
+            progvars.insert_var("original_download_path", Box::new(shared_download.original_download_path));
+            progvars.insert_var("chapters", Box::new(shared_download.chapters));
+            progvars.insert_var("subtitles", Box::new(shared_download.subtitles));
+            Ok(Some(progvars))
+        };
+        progitem.register_stage(job_stage(key, "wait_for_download", wait_task));
+    } else {
         let key_clone = key.clone();
         let url_clone = url.clone();
         let download_task = async move {
-            let res = download_video(key_clone, url, download_dir).await;
+            let format_selector = ytdl_options.format.clone();
+            let mut res = download_video(key_clone.clone(), url, download_dir, ytdl_options).await;
             let mut should_write_data_store = false;
-            if let Ok(Some(progvars)) = &res {
+            let mut duplicate_files = vec![];
+            let mut shared_download = None;
+            if let Ok(Some(progvars)) = &mut res {
                 let original_download_path = progvars.clone_var::<PathBuf>("original_download_path");
                 let original_thumbnail_path = progvars.clone_var::<PathBuf>("original_thumbnail_path");
-                let ytdl_title = progvars.clone_var::<String>("ytdl_title");
-                let ytdl_description = progvars.clone_var::<String>("ytdl_description");
+                let info_json_path = progvars.clone_var::<PathBuf>("info_json_path");
+                let subtitle_tracks = progvars.clone_var::<Vec<SubtitleTrack>>("subtitles").unwrap_or_default();
+                let ytdl_metadata = progvars.clone_var::<YtDlMetadata>("ytdl_metadata").unwrap_or_default();
                 if original_download_path.is_some() {
                     should_write_data_store = true;
-                    match DATAHOLDER.lock() {
-                        Err(_) => {} // do nothing :shrug:
-                        Ok(mut guard) => {
-                            guard.as_mut().insert(url_clone, DownloadedVideo {
-                                location: original_download_path.unwrap(),
-                                thumbnail_location: original_thumbnail_path,
-                                title: ytdl_title,
-                                description: ytdl_description,
-                            });
-                        }
+                    let mut downloaded_video = downloaded_video_from_metadata(
+                        original_download_path.unwrap(),
+                        ytdl_metadata,
+                    );
+                    downloaded_video.thumbnail_location = original_thumbnail_path;
+                    downloaded_video.added_at = Some(unix_timestamp_now());
+                    downloaded_video.last_clipped_at = Some(unix_timestamp_now());
+                    downloaded_video.source_urls = vec![url_clone.clone()];
+                    downloaded_video.info_json_location = info_json_path;
+                    downloaded_video.subtitles = subtitle_tracks;
+                    downloaded_video.format_selector = format_selector;
+                    if let Ok((video_key, stored_video, duplicates)) = store_downloaded_video(&key_clone, &url_clone, downloaded_video) {
+                        // if we already had this video under a different url,
+                        // the later stages should use the one we already had
+                        progvars.insert_var("video_key", Box::new(video_key.clone()));
+                        progvars.insert_var("original_download_path", Box::new(stored_video.location.clone()));
+                        progvars.insert_var("subtitles", Box::new(stored_video.subtitles.clone()));
+                        duplicate_files = duplicates;
+                        shared_download = Some(SharedDownload {
+                            video_key,
+                            original_download_path: stored_video.location,
+                            chapters: stored_video.chapters,
+                            subtitles: stored_video.subtitles,
+                        });
                     }
                 }
             }
+            for duplicate_file in duplicate_files {
+                let _ = fs::remove_file(duplicate_file).await;
+            }
 
             if should_write_data_store {
-                // write the data back to json
-                // after this stage returns. no point for the
-                // progress to wait for this to finish
-                tokio::spawn(async move {
-                    match DATAHOLDER.lock() {
-                        Err(_) => {},
-                        Ok(guard) => {
-                            let _ = data_store::write_json_data(DATA_STORE_PATH, &guard);
-                        },
-                    }
-                });
+                write_data_store_later();
+            }
+            // the video is in the library by now, so the jobs that
+            // were waiting on this download can continue. dropping the
+            // guard removes this download from the in flight downloads
+            if let Some((sender, _inflight_guard)) = download_sender {
+                let shared_result = match (&res, shared_download) {
+                    (Err(e), _) => Err(e.clone()),
+                    (Ok(_), Some(shared_download)) => Ok(shared_download),
+                    (Ok(_), None) => Err("Download did not produce a video".to_string()),
+                };
+                let _ = sender.send(shared_result);
             }
             res
         };
-        let download_stage = Stage::make("download_video", download_task);
+        let download_stage = job_stage(key, "download_video", download_task);
         progitem.register_stage(download_stage);
-    } else if let Some(original_download_path) = url_exists_at {
-        // if the url does already exist, we want to
-        // put a variable of the path where the other steps
-        // can find this url
-        progitem.insert_var("original_download_path", Box::new(original_download_path));
     }
 
     // the download_stage only happens if we havent downloaded
@@ -293,14 +771,384 @@ pub fn create_download_item(
     if should_do_cut_stage {
         progitem.register_stage(cut_stage);
     }
+    if download_request.animation.is_some() {
+        progitem.register_stage(animation_stage);
+    }
+    // runs even without a cut stage, in case
+    // the source video did not come with a thumbnail
+    progitem.register_stage(thumbnail_stage);
     // progitem.register_stage(transcode_stage);
+    let finish_key = key.clone();
+    progitem.register_stage(Stage::make("finish", async move {
+        let video_key = return_something_from_progress_holder(&finish_key, &PROGHOLDER, |me| {
+            me.clone_var::<String>("video_key")
+        });
+        // started before the job is finished, so
+        // the video stays in use the whole time
+        if let Some(video_key) = video_key {
+            analyze_video_later(video_key);
+        }
+        finish_job(&finish_key);
+        Ok(None)
+    }));
     progitem
 }
 
+/// makes a stage of the job with this key. if the stage
+/// fails, the stages after it do not run, so the job
+/// is marked as finished here instead of by the last stage
+pub fn job_stage<F>(key: &String, name: &str, task: F) -> Stage
+    where F: Future<Output = TaskResult> + Send + 'static
+{
+    let key = key.clone();
+    Stage::make(name, async move {
+        let res = task.await;
+        if res.is_err() {
+            finish_job(&key);
+        }
+        res
+    })
+}
+
+pub fn finish_job(key: &String) {
+    let pruned = match JOBHOLDER.lock() {
+        Err(_) => return,
+        Ok(mut guard) => {
+            if let Some(job) = guard.get_mut(key) {
+                job.finished = true;
+            }
+            prune_finished_jobs(&mut guard, FINISHED_JOBS_MAX_ENTRIES)
+        },
+    };
+    // the progress of a pruned job goes too, otherwise
+    // /get would list progresses that have no job info
+    if pruned.is_empty() {
+        return;
+    }
+    if let Ok(mut guard) = PROGHOLDER.lock() {
+        for pruned_key in pruned.iter() {
+            guard.progresses.remove(pruned_key);
+        }
+    }
+}
+
+/// forgets the oldest finished jobs until at most max_finished are left.
+/// jobs that are still running are always kept. returns the keys
+/// of the jobs that were forgotten
+pub fn prune_finished_jobs(jobs: &mut HashMap<String, JobInfo>, max_finished: usize) -> Vec<String> {
+    let mut finished: Vec<(u64, String)> = jobs.iter()
+        .filter(|(_, job)| job.finished)
+        .map(|(key, job)| (job.created_at, key.clone()))
+        .collect();
+    if finished.len() <= max_finished {
+        return vec![];
+    }
+    finished.sort();
+    let remove_count = finished.len() - max_finished;
+    let pruned: Vec<String> = finished.into_iter().take(remove_count).map(|(_, key)| key).collect();
+    for key in pruned.iter() {
+        jobs.remove(key);
+    }
+    pruned
+}
+
+/// the keys of the source videos that running jobs, or the analyses
+/// after them, are using. these must not be evicted from under them
+pub fn in_use_video_keys() -> Vec<String> {
+    let mut video_keys: Vec<String> = match JOBHOLDER.lock() {
+        Err(_) => vec![],
+        Ok(guard) => guard.values()
+            .filter(|job| !job.finished)
+            .filter_map(|job| job.video_key.clone())
+            .collect(),
+    };
+    if let Ok(guard) = ANALYZINGVIDEOS.lock() {
+        video_keys.extend(guard.iter().cloned());
+    }
+    video_keys
+}
+
+/// makes the storyboard, waveform and scenes of the video in the
+/// background, so that jobs dont wait for them. does nothing if
+/// they are already being made by an earlier job
+pub fn analyze_video_later(video_key: String) {
+    match ANALYZINGVIDEOS.lock() {
+        Err(_) => return,
+        Ok(mut guard) => if !guard.insert(video_key.clone()) {
+            return;
+        },
+    }
+    tokio::spawn(async move {
+        generate_storyboard(&video_key).await;
+        generate_waveform(&video_key).await;
+        analyze_scenes(&video_key).await;
+        if let Ok(mut guard) = ANALYZINGVIDEOS.lock() {
+            guard.remove(&video_key);
+        }
+    });
+}
+
+fn video_used_by_other_jobs(job_key: &String, video_key: &String) -> bool {
+    match JOBHOLDER.lock() {
+        Err(_) => true,
+        Ok(guard) => guard.iter().any(|(key, job)| {
+            key != job_key && !job.finished && job.video_key.as_ref() == Some(video_key)
+        }),
+    }
+}
+
+/// should be called while DATAHOLDER is locked, so that the video
+/// cannot be evicted between the job finding it and marking it
+pub fn mark_video_in_use(job_key: &String, video_key: &String) {
+    if let Ok(mut guard) = JOBHOLDER.lock() {
+        if let Some(job) = guard.get_mut(job_key) {
+            job.video_key = Some(video_key.clone());
+        }
+    }
+}
+
+/// fills in everything we know about the video from its youtube-dl metadata
+pub fn downloaded_video_from_metadata(location: PathBuf, ytdl_metadata: YtDlMetadata) -> DownloadedVideo {
+    DownloadedVideo {
+        location,
+        title: ytdl_metadata.title,
+        description: ytdl_metadata.description,
+        format: ytdl_metadata.format_id,
+        uploader: ytdl_metadata.uploader,
+        upload_date: ytdl_metadata.upload_date,
+        duration: ytdl_metadata.duration,
+        view_count: ytdl_metadata.view_count,
+        tags: ytdl_metadata.tags,
+        chapters: ytdl_metadata.chapters,
+        webpage_url: ytdl_metadata.webpage_url,
+        extractor: ytdl_metadata.extractor,
+        video_id: ytdl_metadata.id,
+        ..Default::default()
+    }
+}
+
+/// write the data back to json in the background.
+/// no point for the caller to wait for this to finish
+pub fn write_data_store_later() {
+    tokio::spawn(async move {
+        match DATAHOLDER.lock() {
+            Err(_) => {},
+            Ok(guard) => {
+                let _ = data_store::write_json_data(DATA_STORE_PATH, &guard);
+            },
+        }
+    });
+}
+
+/// adds a freshly downloaded video to the library, keyed by its content key.
+/// if we already had a video with the same key, the existing one is kept
+/// and the url is remembered on it. returns the key, the video that is
+/// in the library, and the files of the new download that are not needed
+pub fn store_downloaded_video(
+    job_key: &String,
+    url: &String,
+    video: DownloadedVideo,
+) -> Result<(String, DownloadedVideo, Vec<PathBuf>), String> {
+    let video_key = content_id::video_content_key(&video).unwrap_or_else(|| normalize_url(url));
+    let mut duplicate_files = vec![];
+    let stored_video = {
+        let mut guard = DATAHOLDER.lock().map_err(|_| FAILED_TO_ACQUIRE_LOCK.to_string())?;
+        mark_video_in_use(job_key, &video_key);
+        match guard.as_mut().get_mut(&video_key) {
+            None => {
+                guard.as_mut().insert(video_key.clone(), video.clone());
+                video
+            },
+            Some(existing) => {
+                if !existing.source_urls.contains(url) {
+                    existing.source_urls.push(url.clone());
+                }
+                existing.last_clipped_at = video.last_clipped_at;
+                if existing.format_selector != video.format_selector {
+                    // it was downloaded again in the format this job asked for.
+                    // the old file stays around if other jobs are still using it
+                    let old_location = std::mem::replace(&mut existing.location, video.location.clone());
+                    existing.format_selector = video.format_selector.clone();
+                    existing.format = video.format.clone();
+                    if !video_used_by_other_jobs(job_key, &video_key) {
+                        duplicate_files.push(old_location);
+                    }
+                }
+                duplicate_files.extend(merge_duplicate_files(existing, video));
+                existing.clone()
+            },
+        }
+    };
+    if let Ok(mut index) = SEARCHINDEX.lock() {
+        index.insert(&video_key, &stored_video);
+    }
+    Ok((video_key, stored_video, duplicate_files))
+}
+
+/// moves the files of the duplicate that the existing video does not
+/// have yet over to it. returns the files of the duplicate that are
+/// not needed anymore
+pub fn merge_duplicate_files(existing: &mut DownloadedVideo, video: DownloadedVideo) -> Vec<PathBuf> {
+    let mut duplicate_files = vec![];
+    // keep subtitles in languages we didnt have yet
+    for track in video.subtitles {
+        if existing.subtitles.iter().any(|t| t.language == track.language) {
+            duplicate_files.push(track.location);
+        } else {
+            existing.subtitles.push(track);
+        }
+    }
+    if existing.thumbnail_location.is_none() {
+        existing.thumbnail_location = video.thumbnail_location;
+    } else {
+        duplicate_files.extend(video.thumbnail_location);
+    }
+    if existing.info_json_location.is_none() {
+        existing.info_json_location = video.info_json_location;
+    } else {
+        duplicate_files.extend(video.info_json_location);
+    }
+    if existing.waveform_location.is_none() {
+        existing.waveform_location = video.waveform_location;
+    } else {
+        duplicate_files.extend(video.waveform_location);
+    }
+    if existing.storyboard.is_none() {
+        existing.storyboard = video.storyboard;
+    } else if let Some(storyboard) = video.storyboard {
+        duplicate_files.push(storyboard.location);
+        duplicate_files.push(storyboard.vtt_location);
+    }
+    duplicate_files.push(video.location);
+    // both entries can point at the same file
+    let kept_files: Vec<&PathBuf> = referenced_files(existing).into_iter().map(|(_, path)| path).collect();
+    duplicate_files.retain(|f| !kept_files.contains(&f));
+    duplicate_files
+}
+
+/// lets the client find out what the clips of a job ended up being called
+pub fn set_job_clips(key: &String, clips: &[Clip]) {
+    if let Ok(mut guard) = JOBHOLDER.lock() {
+        if let Some(job) = guard.get_mut(key) {
+            job.clips = clips.iter().filter_map(|c| c.id()).collect();
+        }
+    }
+}
+
+pub fn record_clips(video_key: &String, clips: Vec<Clip>) {
+    if clips.is_empty() {
+        return;
+    }
+    match DATAHOLDER.lock() {
+        Err(_) => return,
+        Ok(mut guard) => match guard.as_mut().get_mut(video_key) {
+            Some(video) => video.clips.extend(clips),
+            None => return,
+        },
+    }
+    write_data_store_later();
+}
+
+/// stores the thumbnails the generate_thumbnails stage made.
+/// clips are matched by their location
+pub fn set_thumbnails(video_key: &String, video_thumbnail: Option<PathBuf>, clips: &[Clip]) {
+    match DATAHOLDER.lock() {
+        Err(_) => return,
+        Ok(mut guard) => match guard.as_mut().get_mut(video_key) {
+            None => return,
+            Some(video) => {
+                if video_thumbnail.is_some() {
+                    video.thumbnail_location = video_thumbnail;
+                }
+                for clip in clips.iter().filter(|c| c.thumbnail_location.is_some()) {
+                    if let Some(stored) = video.clips.iter_mut().find(|c| c.location == clip.location) {
+                        stored.thumbnail_location = clip.thumbnail_location.clone();
+                    }
+                }
+            },
+        },
+    }
+    write_data_store_later();
+}
+
+/// stores the animations the export_animations stage
+/// made. clips are matched by their location
+pub fn set_animations(video_key: &String, clips: &[Clip]) {
+    match DATAHOLDER.lock() {
+        Err(_) => return,
+        Ok(mut guard) => match guard.as_mut().get_mut(video_key) {
+            None => return,
+            Some(video) => {
+                for clip in clips.iter().filter(|c| c.animation_location.is_some()) {
+                    if let Some(stored) = video.clips.iter_mut().find(|c| c.location == clip.location) {
+                        stored.animation_location = clip.animation_location.clone();
+                    }
+                }
+            },
+        },
+    }
+    write_data_store_later();
+}
+
+pub fn set_storyboard(video_key: &String, storyboard: Storyboard) {
+    match DATAHOLDER.lock() {
+        Err(_) => return,
+        Ok(mut guard) => match guard.as_mut().get_mut(video_key) {
+            Some(video) => video.storyboard = Some(storyboard),
+            None => return,
+        },
+    }
+    write_data_store_later();
+}
+
+pub fn set_waveform(video_key: &String, waveform_location: PathBuf) {
+    match DATAHOLDER.lock() {
+        Err(_) => return,
+        Ok(mut guard) => match guard.as_mut().get_mut(video_key) {
+            Some(video) => video.waveform_location = Some(waveform_location),
+            None => return,
+        },
+    }
+    write_data_store_later();
+}
+
+pub fn set_scenes(video_key: &String, scenes: Vec<f64>) {
+    match DATAHOLDER.lock() {
+        Err(_) => return,
+        Ok(mut guard) => match guard.as_mut().get_mut(video_key) {
+            Some(video) => video.scenes = Some(scenes),
+            None => return,
+        },
+    }
+    write_data_store_later();
+}
+
+pub fn set_analysis_failed(video_key: &String, analysis: &str) {
+    match DATAHOLDER.lock() {
+        Err(_) => return,
+        Ok(mut guard) => match guard.as_mut().get_mut(video_key) {
+            Some(video) if !video.analysis_failed(analysis) => video.failed_analyses.push(analysis.to_string()),
+            _ => return,
+        },
+    }
+    write_data_store_later();
+}
+
+/// starts the job and returns its key
 pub fn start_download(
     download_request: DownloadRequest
-) -> Result<(), String>{
+) -> Result<String, String> {
     let unique_key = random_string(16);
+    if let Ok(mut guard) = JOBHOLDER.lock() {
+        guard.insert(unique_key.clone(), JobInfo {
+            created_at: unix_timestamp_now(),
+            name: download_request.name.clone(),
+            url: download_request.url.clone(),
+            clips: vec![],
+            finished: false,
+            video_key: None,
+        });
+    }
     let mut progitem = create_download_item(&unique_key, download_request);
     match PROGHOLDER.lock() {
         Err(_) => Err(FAILED_TO_ACQUIRE_LOCK.into()),
@@ -311,16 +1159,55 @@ pub fn start_download(
             // to use the progholder it will fail. Thats why internally, the progress item
             // uses try_lock to avoid blocking, and it has retry capabilities.
             progitem.start(unique_key.clone(), &PROGHOLDER);
-            guard.progresses.insert(unique_key, progitem);
-            Ok(())
+            guard.progresses.insert(unique_key.clone(), progitem);
+            Ok(unique_key)
         }
     }
 }
 
+/// checks the url against the scheme/domain rules in the config,
+/// and makes sure it does not resolve to an internal address.
+/// should be called before anything passes the url to youtube-dl
+pub async fn validate_download_url<S: AsRef<str>>(
+    url: S
+) -> Result<(), String> {
+    let config = get_config()?;
+    url_validation::validate_url(url, &config).await
+}
+
+/// looks up the youtube-dl metadata of a url without downloading it.
+/// results are cached per normalized url for METADATA_CACHE_SECONDS
+pub async fn get_url_metadata(url: String) -> Result<YtDlMetadata, String> {
+    let now = unix_timestamp_now();
+    let normalized_url = normalize_url(&url);
+    if let Ok(guard) = METADATACACHE.lock() {
+        if let Some((fetched_at, metadata)) = guard.get(&normalized_url) {
+            if now.saturating_sub(*fetched_at) < METADATA_CACHE_SECONDS {
+                return Ok(metadata.clone());
+            }
+        }
+    }
+
+    let metadata = fetch_metadata(&url).await?;
+    if let Ok(mut guard) = METADATACACHE.lock() {
+        guard.retain(|_, (fetched_at, _)| now.saturating_sub(*fetched_at) < METADATA_CACHE_SECONDS);
+        if guard.len() >= METADATA_CACHE_MAX_ENTRIES {
+            let oldest_url = guard.iter()
+                .min_by_key(|(_, (fetched_at, _))| *fetched_at)
+                .map(|(url, _)| url.clone());
+            if let Some(oldest_url) = oldest_url {
+                guard.remove(&oldest_url);
+            }
+        }
+        guard.insert(normalized_url, (now, metadata.clone()));
+    }
+    Ok(metadata)
+}
+
 pub fn list_all_downloaded_videos(
-) -> Result<Vec<(String, DownloadedVideo)>, String> {
+) -> Result<Vec<(String, DownloadedVideo)>, LibraryError> {
     let mut downloaded_map_clone = match DATAHOLDER.lock() {
-        Err(_) => return Err(FAILED_TO_ACQUIRE_LOCK.into()),
+        Err(_) => return Err(LibraryError::LockFailed),
         Ok(guard) => {
             // its probably faster to clone here
             // and iterate after the lock is done
@@ -337,6 +1224,129 @@ pub fn list_all_downloaded_videos(
     Ok(out_vec)
 }
 
+/// searches, sorts and paginates the downloaded videos
+/// according to the query
+pub fn query_downloaded_videos(
+    query: &VideoQuery,
+) -> Result<Page<(String, DownloadedVideo)>, LibraryError> {
+    let all_videos = list_all_downloaded_videos()?;
+    let index = SEARCHINDEX.lock().map_err(|_| LibraryError::LockFailed)?;
+    Ok(video_search::query_videos(all_videos, &index, query)?)
+}
+
+/// sorts and paginates the progresses that the progresslib2
+/// server extension returns, which are keyed by job key
+pub fn query_progresses(
+    progresses: serde_json::Map<String, serde_json::Value>,
+    query: &ProgressQuery,
+) -> Result<Page<ProgressEntry>, String> {
+    let cursor = match query.cursor {
+        Some(ref c) => Some(pagination::decode_cursor(c)?),
+        None => None,
+    };
+    let jobs = JOBHOLDER.lock().map_err(|_| FAILED_TO_ACQUIRE_LOCK.to_string())?.clone();
+
+    let sort = query.sort.unwrap_or(ProgressSort::Created);
+    let order = query.order.unwrap_or(match sort {
+        ProgressSort::Created => SortOrder::Desc,
+        ProgressSort::Name => SortOrder::Asc,
+    });
+    let entries = progresses.into_iter().map(|(key, progress)| {
+        let info = jobs.get(&key).cloned();
+        let value = match sort {
+            ProgressSort::Created => info.as_ref().map(|i| i.created_at as f64).into(),
+            ProgressSort::Name => info.as_ref().and_then(|i| i.name.clone()).into(),
+        };
+        (value, key.clone(), ProgressEntry { key, info, progress })
+    }).collect::<Vec<(SortValue, String, ProgressEntry)>>();
+
+    Ok(pagination::paginate(entries, order, cursor.as_ref(), query.offset.unwrap_or(0), query.limit))
+}
+
+/// finds a downloaded video by the id the routes use for it.
+/// returns the key it is stored under, and the video
+pub fn find_downloaded_video<S: AsRef<str>>(
+    id: S,
+) -> Result<Option<(String, DownloadedVideo)>, String> {
+    let guard = DATAHOLDER.lock().map_err(|_| FAILED_TO_ACQUIRE_LOCK.to_string())?;
+    let found = guard.as_ref().iter()
+        .find(|(_, video)| video.id().as_deref() == Some(id.as_ref()))
+        .map(|(url, video)| (url.clone(), video.clone()));
+    Ok(found)
+}
+
+/// replaces the annotations of the video with this id.
+/// returns None if there is no such video
+pub fn set_video_annotations<S: AsRef<str>>(
+    id: S,
+    annotations: Annotations,
+) -> Result<Option<Annotations>, LibraryError> {
+    let annotations = normalize_annotations(annotations)?;
+    let updated = {
+        let mut guard = DATAHOLDER.lock().map_err(|_| LibraryError::LockFailed)?;
+        let found = guard.as_mut().iter_mut()
+            .find(|(_, video)| video.id().as_deref() == Some(id.as_ref()));
+        match found {
+            None => return Ok(None),
+            Some((url, video)) => {
+                video.annotations = annotations.clone();
+                (url.clone(), video.clone())
+            },
+        }
+    };
+    // user tags and notes are searchable too
+    if let Ok(mut index) = SEARCHINDEX.lock() {
+        index.insert(&updated.0, &updated.1);
+    }
+    write_data_store_later();
+    Ok(Some(annotations))
+}
+
+/// finds a clip by its id. returns the key of the
+/// video it was cut from, and the clip
+pub fn find_clip<S: AsRef<str>>(
+    id: S,
+) -> Result<Option<(String, Clip)>, String> {
+    let guard = DATAHOLDER.lock().map_err(|_| FAILED_TO_ACQUIRE_LOCK.to_string())?;
+    for (url, video) in guard.as_ref().iter() {
+        if let Some(clip) = video.clips.iter().find(|c| c.id().as_deref() == Some(id.as_ref())) {
+            return Ok(Some((url.clone(), clip.clone())));
+        }
+    }
+    Ok(None)
+}
+
+/// replaces the annotations of the clip with this id.
+/// returns None if there is no such clip
+pub fn set_clip_annotations<S: AsRef<str>>(
+    id: S,
+    annotations: Annotations,
+) -> Result<Option<Annotations>, LibraryError> {
+    let annotations = normalize_annotations(annotations)?;
+    {
+        let mut guard = DATAHOLDER.lock().map_err(|_| LibraryError::LockFailed)?;
+        let found = guard.as_mut().values_mut()
+            .flat_map(|video| video.clips.iter_mut())
+            .find(|c| c.id().as_deref() == Some(id.as_ref()));
+        match found {
+            None => return Ok(None),
+            Some(clip) => clip.annotations = annotations.clone(),
+        }
+    }
+    write_data_store_later();
+    Ok(Some(annotations))
+}
+
+pub fn query_clips(query: &ClipQuery) -> Result<Page<ClipEntry>, LibraryError> {
+    let all_videos = list_all_downloaded_videos()?;
+    Ok(annotations::query_clips(all_videos, query)?)
+}
+
+pub fn list_collections() -> Result<Vec<CollectionSummary>, String> {
+    let guard = DATAHOLDER.lock().map_err(|_| FAILED_TO_ACQUIRE_LOCK.to_string())?;
+    Ok(annotations::summarize_collections(guard.as_ref().values()))
+}
+
 pub fn get_config() -> Result<Config, String> {
     let config_guard = CONFIGHOLDER.read().map_err(string_error)?;
     Ok(config_guard.to_owned())
@@ -377,10 +1387,54 @@ pub fn initialize() -> Result<(), String> {
     let data_map = data.as_mut();
     let downloaded_videos_map = guard.as_mut();
 
-    for (key, value) in data_map.drain() {
-        downloaded_videos_map.insert(key, value);
+    let mut migrated_keys = false;
+    let mut duplicate_files = vec![];
+    for (key, mut value) in data_map.drain() {
+        // videos from before we tracked when they were added.
+        // the file modification time is the best guess we have
+        if value.added_at.is_none() {
+            value.added_at = std::fs::metadata(&value.location)
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs());
+        }
+        // videos used to be keyed by the url they were downloaded from
+        if value.source_urls.is_empty() && key.contains("://") {
+            value.source_urls.push(key.clone());
+        }
+        let new_key = content_id::video_content_key(&value)
+            .unwrap_or_else(|| value.url().map_or(key.clone(), normalize_url));
+        if new_key != key {
+            migrated_keys = true;
+        }
+        match downloaded_videos_map.get_mut(&new_key) {
+            None => {
+                downloaded_videos_map.insert(new_key, value);
+            },
+            // the same video was downloaded from two different urls.
+            // keep the first one, but remember the other url and its clips
+            Some(existing) => {
+                existing.source_urls.append(&mut value.source_urls);
+                existing.clips.append(&mut value.clips);
+                duplicate_files.extend(merge_duplicate_files(existing, value));
+            },
+        }
+    }
+    for duplicate_file in duplicate_files {
+        let _ = std::fs::remove_file(duplicate_file);
     }
 
+    let mut index = SEARCHINDEX.lock().map_err(string_error)?;
+    *index = SearchIndex::build(downloaded_videos_map.iter());
+
+    if migrated_keys {
+        data_store::write_json_data(DATA_STORE_PATH, &guard)?;
+    }
+    drop(guard);
+
+    uploads::initialize_uploads()?;
+
     Ok(())
 }
 
@@ -407,4 +1461,29 @@ mod test {
             }
         });
     }
+
+    #[test]
+    fn prune_finished_jobs_keeps_running_and_newest() {
+        let job = |created_at, finished| JobInfo {
+            created_at,
+            name: None,
+            url: "url".into(),
+            clips: vec![],
+            finished,
+            video_key: None,
+        };
+        let mut jobs = HashMap::new();
+        jobs.insert("a".to_string(), job(1, true));
+        jobs.insert("b".to_string(), job(2, false));
+        jobs.insert("c".to_string(), job(3, true));
+        jobs.insert("d".to_string(), job(4, true));
+        assert_eq!(prune_finished_jobs(&mut jobs, 2), vec!["a".to_string()]);
+        let mut keys: Vec<&String> = jobs.keys().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["b", "c", "d"]);
+        let mut pruned = prune_finished_jobs(&mut jobs, 0);
+        pruned.sort();
+        assert_eq!(pruned, vec!["c".to_string(), "d".to_string()]);
+        assert_eq!(jobs.keys().collect::<Vec<_>>(), vec!["b"]);
+    }
 }
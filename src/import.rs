@@ -0,0 +1,187 @@
+use std::path::Path;
+use std::path::PathBuf;
+use serde::Deserialize;
+use tokio::fs;
+
+use super::DownloadedVideo;
+use super::DATAHOLDER;
+use super::SEARCHINDEX;
+use super::LibraryError;
+use super::get_config;
+use super::random_string;
+use super::unix_timestamp_now;
+use super::write_data_store_later;
+use super::content_id::local_content_key;
+use super::disk_guard::ensure_disk_limits;
+use super::media_probe::probe_media;
+use super::thumbnails::generate_thumbnail;
+use super::thumbnails::thumbnail_time;
+use super::youtubedl_stage::VALID_AUDIO_EXTENSIONS;
+use super::youtubedl_stage::VALID_VIDEO_EXTENSIONS;
+
+#[derive(Debug, Deserialize)]
+pub struct ImportRequest {
+    /// a path on the server. must be inside one
+    /// of the import_dirs from the config
+    pub path: PathBuf,
+    pub title: Option<String>,
+    /// hard link the file into the download dir instead of copying it.
+    /// falls back to copying if the file is on a different filesystem
+    #[serde(default)]
+    pub hard_link: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImportMode {
+    Copy,
+    HardLink,
+    /// for uploads, which are already in the download dir
+    Move,
+}
+
+/// returns the lowercased extension if it is one we can clip
+pub fn importable_extension<P: AsRef<Path>>(path: P) -> Result<String, String> {
+    let extension = path.as_ref().extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .unwrap_or_default();
+    let is_valid = VALID_VIDEO_EXTENSIONS.iter().chain(VALID_AUDIO_EXTENSIONS.iter())
+        .any(|e| *e == extension);
+    if !is_valid {
+        return Err(format!("Cannot import files with extension '{}'", extension));
+    }
+    Ok(extension)
+}
+
+/// resolves the path (including any symlinks or ..) and makes
+/// sure it is a file inside one of the allowed import dirs
+pub fn validate_import_path<P: AsRef<Path>>(path: P, import_dirs: &[PathBuf]) -> Result<PathBuf, String> {
+    if import_dirs.is_empty() {
+        return Err("Importing server side files is disabled, set import_dirs in the config to enable it".into());
+    }
+    let path = path.as_ref();
+    let resolved = path.canonicalize().map_err(
+        |e| format!("Failed to find {:?}: {}", path, e))?;
+    let is_allowed = import_dirs.iter()
+        .filter_map(|dir| dir.canonicalize().ok())
+        .any(|dir| resolved.starts_with(dir));
+    if !is_allowed {
+        return Err(format!("{:?} is not inside any of the import_dirs", path));
+    }
+    if !resolved.is_file() {
+        return Err(format!("{:?} is not a file", path));
+    }
+    Ok(resolved)
+}
+
+/// where an upload gets written to while it is being received.
+/// it is in the download dir so that moving it into the library
+/// afterwards is just a rename
+pub fn new_upload_path(download_dir: &PathBuf) -> PathBuf {
+    upload_path(download_dir, random_string(16))
+}
+
+pub fn upload_path<S: AsRef<str>>(download_dir: &PathBuf, id: S) -> PathBuf {
+    download_dir.join(format!("{}.upload", id.as_ref()))
+}
+
+async fn place_file(source: &Path, destination: &Path, mode: ImportMode) -> Result<(), String> {
+    let res = match mode {
+        ImportMode::Move => fs::rename(source, destination).await,
+        ImportMode::HardLink => match fs::hard_link(source, destination).await {
+            Ok(_) => Ok(()),
+            Err(_) => fs::copy(source, destination).await.map(|_| ()),
+        },
+        ImportMode::Copy => fs::copy(source, destination).await.map(|_| ()),
+    };
+    res.map_err(|e| format!("Failed to import {:?}: {}", source, e))
+}
+
+/// probes the file, puts it into the download dir, generates a thumbnail
+/// for it, and adds it to the library so it can be clipped like a download.
+/// the extension is passed separately since uploads dont have one yet
+pub async fn import_media_file(
+    source: &Path,
+    extension: &str,
+    title: Option<String>,
+    mode: ImportMode,
+) -> Result<(String, DownloadedVideo), LibraryError> {
+    let media_info = probe_media(source).await.map_err(
+        |e| format!("{:?} is not a media file we can read: {}", source, e))?;
+
+    let config = get_config()?;
+    ensure_disk_limits(&config.download_dir, None).await?;
+    let key = random_string(16);
+    let location = config.download_dir.join(format!("{}.{}", key, extension));
+    place_file(source, &location, mode).await?;
+
+    let thumbnail_location = if media_info.has_video {
+        let thumbnail_path = location.with_extension("jpg");
+        match generate_thumbnail(&location, &thumbnail_path, thumbnail_time(media_info.duration)).await {
+            Ok(_) => Some(thumbnail_path),
+            Err(e) => {
+                println!("Failed to generate thumbnail for {:?}: {}", location, e);
+                None
+            },
+        }
+    } else {
+        None
+    };
+
+    let video = DownloadedVideo {
+        location,
+        thumbnail_location,
+        title,
+        duration: media_info.duration,
+        added_at: Some(unix_timestamp_now()),
+        last_clipped_at: Some(unix_timestamp_now()),
+        ..Default::default()
+    };
+    let video_key = local_content_key(&key);
+    {
+        let mut guard = DATAHOLDER.lock().map_err(|_| LibraryError::LockFailed)?;
+        guard.as_mut().insert(video_key.clone(), video.clone());
+    }
+    if let Ok(mut index) = SEARCHINDEX.lock() {
+        index.insert(&video_key, &video);
+    }
+    write_data_store_later();
+    Ok((video_key, video))
+}
+
+/// imports a file that is already on the server
+pub async fn import_server_file(request: ImportRequest) -> Result<(String, DownloadedVideo), LibraryError> {
+    let config = get_config()?;
+    let source = validate_import_path(&request.path, &config.import_dirs)?;
+    let extension = importable_extension(&source)?;
+    let title = request.title.or_else(|| {
+        source.file_stem().and_then(|s| s.to_str()).map(|s| s.to_string())
+    });
+    let mode = if request.hard_link { ImportMode::HardLink } else { ImportMode::Copy };
+    import_media_file(&source, &extension, title, mode).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_import_paths() {
+        assert_eq!(importable_extension("a/b.MP4").unwrap(), "mp4");
+        assert!(importable_extension("a/b.m4a").is_ok());
+        assert!(importable_extension("a/b.txt").is_err());
+        assert!(importable_extension("a/b").is_err());
+
+        let import_dir = std::env::temp_dir().join(format!("vidclipper-import-{}", random_string(8)));
+        std::fs::create_dir_all(import_dir.join("dir")).unwrap();
+        std::fs::write(import_dir.join("a.mp4"), "").unwrap();
+        let allowed = vec![import_dir.join("dir")];
+        let outside = import_dir.join("dir").join("..").join("a.mp4");
+        assert!(validate_import_path(&outside, &[]).is_err());
+        assert!(validate_import_path(&outside, &allowed).is_err());
+        assert!(validate_import_path(&outside, &[import_dir.clone()]).is_ok());
+        assert!(validate_import_path(import_dir.join("dir"), &[import_dir.clone()]).is_err());
+        assert!(validate_import_path(import_dir.join("missing.mp4"), &[import_dir.clone()]).is_err());
+        let _ = std::fs::remove_dir_all(&import_dir);
+    }
+}
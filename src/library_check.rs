@@ -0,0 +1,425 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use super::DownloadedVideo;
+use super::DATAHOLDER;
+use super::SEARCHINDEX;
+use super::DATA_STORE_PATH;
+use super::FAILED_TO_ACQUIRE_LOCK;
+use super::data_store;
+use super::get_config;
+use super::list_all_downloaded_videos;
+use super::unix_timestamp_now;
+use super::downloaded_video_from_metadata;
+use super::content_id::local_content_key;
+use super::content_id::video_content_key;
+use super::media_probe::probe_media;
+use super::subtitles::get_subtitle_tracks_from_vec;
+use super::subtitles::VALID_SUBTITLE_EXTENSIONS;
+use super::uploads::upload_locations;
+use super::youtubedl_stage::extract_metadata;
+use super::youtubedl_stage::VALID_AUDIO_EXTENSIONS;
+use super::youtubedl_stage::VALID_THUMBNAIL_EXTENSIONS;
+use super::youtubedl_stage::VALID_VIDEO_EXTENSIONS;
+
+/// files that were modified more recently than this are
+/// probably still being written by a job, so they are
+/// never reported as orphans
+pub const ORPHAN_MIN_AGE_SECONDS: u64 = 10 * 60;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct FsckRequest {
+    /// remove entries whose files no longer exist
+    #[serde(default)]
+    pub prune_missing: bool,
+    /// add orphan videos in the download dir to the library
+    #[serde(default)]
+    pub adopt_orphans: bool,
+    /// dont run ffprobe on every video and clip
+    #[serde(default)]
+    pub skip_media_check: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileKind {
+    Video,
+    Thumbnail,
+    InfoJson,
+    Subtitle,
+    Clip,
+    Storyboard,
+    Waveform,
+    Animation,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct MissingFile {
+    pub video_key: String,
+    pub kind: FileKind,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CorruptFile {
+    pub video_key: String,
+    pub kind: FileKind,
+    pub path: PathBuf,
+    pub error: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct FsckReport {
+    pub missing: Vec<MissingFile>,
+    /// files in the download dir that no video or clip refers to
+    pub orphans: Vec<PathBuf>,
+    pub corrupt: Vec<CorruptFile>,
+    /// keys of the videos that were removed from the library
+    pub pruned: Vec<String>,
+    /// keys of the orphan videos that were added to the library
+    pub adopted: Vec<String>,
+}
+
+/// an orphan video, along with the orphan files
+/// youtube-dl wrote next to it
+#[derive(Debug, Default, PartialEq)]
+pub struct OrphanVideo {
+    pub location: PathBuf,
+    pub thumbnail_location: Option<PathBuf>,
+    pub info_json_location: Option<PathBuf>,
+    pub subtitle_locations: Vec<PathBuf>,
+}
+
+pub fn referenced_files(video: &DownloadedVideo) -> Vec<(FileKind, &PathBuf)> {
+    let mut files = vec![(FileKind::Video, &video.location)];
+    files.extend(video.thumbnail_location.iter().map(|p| (FileKind::Thumbnail, p)));
+    files.extend(video.info_json_location.iter().map(|p| (FileKind::InfoJson, p)));
+    files.extend(video.subtitles.iter().map(|s| (FileKind::Subtitle, &s.location)));
+    files.extend(video.clips.iter().map(|c| (FileKind::Clip, &c.location)));
+    files.extend(video.clips.iter().filter_map(|c| c.thumbnail_location.as_ref()).map(|p| (FileKind::Thumbnail, p)));
+    files.extend(video.clips.iter().filter_map(|c| c.animation_location.as_ref()).map(|p| (FileKind::Animation, p)));
+    files.extend(video.waveform_location.iter().map(|p| (FileKind::Waveform, p)));
+    for storyboard in video.storyboard.iter() {
+        files.push((FileKind::Storyboard, &storyboard.location));
+        files.push((FileKind::Storyboard, &storyboard.vtt_location));
+    }
+    files
+}
+
+pub fn find_missing_files<F: Fn(&Path) -> bool>(
+    videos: &[(String, DownloadedVideo)],
+    exists: F,
+) -> Vec<MissingFile> {
+    let mut missing = vec![];
+    for (video_key, video) in videos {
+        for (kind, path) in referenced_files(video) {
+            if !exists(path) {
+                missing.push(MissingFile { video_key: video_key.clone(), kind, path: path.clone() });
+            }
+        }
+    }
+    missing
+}
+
+/// the download dir is flat, so files are
+/// compared by their file name
+pub fn find_orphan_files(
+    videos: &[(String, DownloadedVideo)],
+    files: Vec<PathBuf>,
+) -> Vec<PathBuf> {
+    let referenced: HashSet<&std::ffi::OsStr> = videos.iter()
+        .flat_map(|(_, video)| referenced_files(video))
+        .filter_map(|(_, path)| path.file_name())
+        .collect();
+    files.into_iter()
+        .filter(|f| f.file_name().map_or(false, |name| !referenced.contains(name)))
+        .collect()
+}
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension().map_or(false, |ext| extensions.iter().any(|e| *e == ext))
+}
+
+/// groups the orphan files by the video they belong to. youtube-dl names
+/// them <stem>.<ext>, <stem>.info.json, <stem>.<language>.srt, etc.
+pub fn group_orphan_videos(orphans: &[PathBuf]) -> Vec<OrphanVideo> {
+    let mut orphan_videos = vec![];
+    for location in orphans {
+        let is_media = has_extension(location, &VALID_VIDEO_EXTENSIONS) ||
+            has_extension(location, &VALID_AUDIO_EXTENSIONS);
+        let stem = match location.file_stem().and_then(|s| s.to_str()) {
+            Some(s) if is_media => format!("{}.", s),
+            _ => continue,
+        };
+        let mut orphan_video = OrphanVideo { location: location.clone(), ..Default::default() };
+        let siblings = orphans.iter().filter(|p| *p != location && p.file_name()
+            .and_then(|n| n.to_str())
+            .map_or(false, |n| n.starts_with(&stem)));
+        for sibling in siblings {
+            if sibling.to_str().map_or(false, |s| s.ends_with(".info.json")) {
+                orphan_video.info_json_location = Some(sibling.clone());
+            } else if has_extension(sibling, &VALID_THUMBNAIL_EXTENSIONS) &&
+                sibling.file_stem() == location.file_stem() {
+                orphan_video.thumbnail_location = Some(sibling.clone());
+            } else if sibling.to_str().map_or(false, |s| s.contains(".storyboard.")) {
+                // the storyboard track is a vtt too, but it is not a subtitle
+                continue;
+            } else if has_extension(sibling, &VALID_SUBTITLE_EXTENSIONS) {
+                orphan_video.subtitle_locations.push(sibling.clone());
+            }
+        }
+        orphan_videos.push(orphan_video);
+    }
+    orphan_videos
+}
+
+/// removes the references to the missing files from the video.
+/// returns false if the video file itself is missing,
+/// in which case the whole entry should be removed
+pub fn prune_missing_files(video: &mut DownloadedVideo, missing: &[&MissingFile]) -> bool {
+    let is_missing = |path: &PathBuf| missing.iter().any(|m| &m.path == path);
+    if is_missing(&video.location) {
+        return false;
+    }
+    if video.thumbnail_location.as_ref().map_or(false, is_missing) {
+        video.thumbnail_location = None;
+    }
+    if video.info_json_location.as_ref().map_or(false, is_missing) {
+        video.info_json_location = None;
+    }
+    video.subtitles.retain(|s| !is_missing(&s.location));
+    if video.waveform_location.as_ref().map_or(false, is_missing) {
+        video.waveform_location = None;
+    }
+    // its no use with only one of the two files
+    if video.storyboard.as_ref().map_or(false, |s| is_missing(&s.location) || is_missing(&s.vtt_location)) {
+        video.storyboard = None;
+    }
+    video.clips.retain(|c| !is_missing(&c.location));
+    for clip in video.clips.iter_mut() {
+        if clip.thumbnail_location.as_ref().map_or(false, is_missing) {
+            clip.thumbnail_location = None;
+        }
+        if clip.animation_location.as_ref().map_or(false, is_missing) {
+            clip.animation_location = None;
+        }
+    }
+    true
+}
+
+fn modified_at(metadata: &std::fs::Metadata) -> Option<u64> {
+    metadata.modified().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+}
+
+/// the files directly inside the download dir that
+/// are old enough to not belong to a running job
+async fn list_settled_files(download_dir: &PathBuf) -> Result<Vec<PathBuf>, String> {
+    let mut entries = fs::read_dir(download_dir).await.map_err(
+        |e| format!("Failed to read dir {:?}: {}", download_dir, e))?;
+    let now = unix_timestamp_now();
+    let mut files = vec![];
+    while let Some(entry) = entries.next_entry().await.map_err(
+        |e| format!("Failed to iterate over dir: {}", e))? {
+        let metadata = match entry.metadata().await {
+            Ok(m) => m,
+            Err(_) => continue,
+        };
+        let is_settled = modified_at(&metadata)
+            .map_or(true, |m| now.saturating_sub(m) >= ORPHAN_MIN_AGE_SECONDS);
+        if metadata.is_file() && is_settled {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// returns None if the orphan is a clip that lost its source,
+/// those are not sources themselves
+async fn adopt_orphan_video(orphan: &OrphanVideo) -> Option<DownloadedVideo> {
+    let media_info = probe_media(&orphan.location).await.ok();
+    if media_info.as_ref().map_or(false, |i| i.is_clip) {
+        return None;
+    }
+    let mut video = match orphan.info_json_location {
+        Some(ref info_json_location) => {
+            let metadata = extract_metadata(info_json_location, true).await;
+            downloaded_video_from_metadata(orphan.location.clone(), metadata)
+        },
+        None => DownloadedVideo { location: orphan.location.clone(), ..Default::default() },
+    };
+    video.thumbnail_location = orphan.thumbnail_location.clone();
+    video.info_json_location = orphan.info_json_location.clone();
+    video.subtitles = get_subtitle_tracks_from_vec(orphan.subtitle_locations.clone());
+    video.added_at = std::fs::metadata(&orphan.location).ok().as_ref().and_then(modified_at);
+    if video.title.is_none() {
+        video.title = orphan.location.file_stem().and_then(|s| s.to_str()).map(|s| s.to_string());
+    }
+    if video.duration.is_none() {
+        video.duration = media_info.and_then(|i| i.duration);
+    }
+    Some(video)
+}
+
+/// compares the library with what is actually in the download dir.
+/// reports missing, orphan and (unless skipped) corrupt files, and
+/// optionally prunes the missing ones and adopts the orphan videos
+pub async fn check_library(request: FsckRequest) -> Result<FsckReport, String> {
+    let config = get_config()?;
+    let videos = list_all_downloaded_videos()?;
+    let mut report = FsckReport::default();
+
+    report.missing = find_missing_files(&videos, |p| p.exists());
+    let mut files = list_settled_files(&config.download_dir).await?;
+    // uploads that are still in progress belong to their upload, not a video
+    let upload_files = upload_locations();
+    files.retain(|f| !upload_files.iter().any(|u| u.file_name() == f.file_name()));
+    report.orphans = find_orphan_files(&videos, files);
+
+    if !request.skip_media_check {
+        for (video_key, video) in videos.iter() {
+            let media_files = referenced_files(video).into_iter()
+                .filter(|(kind, path)| (*kind == FileKind::Video || *kind == FileKind::Clip) && path.exists());
+            for (kind, path) in media_files {
+                if let Err(error) = probe_media(path).await {
+                    report.corrupt.push(CorruptFile { video_key: video_key.clone(), kind, path: path.clone(), error });
+                }
+            }
+        }
+    }
+
+    let mut adopted_videos = vec![];
+    if request.adopt_orphans {
+        for orphan in group_orphan_videos(&report.orphans) {
+            let video = match adopt_orphan_video(&orphan).await {
+                Some(video) => video,
+                None => continue,
+            };
+            let video_key = video_content_key(&video).unwrap_or_else(|| {
+                let stem = orphan.location.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+                local_content_key(stem)
+            });
+            adopted_videos.push((video_key, video));
+        }
+    }
+
+    if !request.prune_missing && adopted_videos.is_empty() {
+        return Ok(report);
+    }
+    let mut guard = DATAHOLDER.lock().map_err(|_| FAILED_TO_ACQUIRE_LOCK.to_string())?;
+    let mut index = SEARCHINDEX.lock().map_err(|_| FAILED_TO_ACQUIRE_LOCK.to_string())?;
+    let library = guard.as_mut();
+    if request.prune_missing {
+        let missing_keys: HashSet<&String> = report.missing.iter().map(|m| &m.video_key).collect();
+        for video_key in missing_keys {
+            let missing: Vec<&MissingFile> = report.missing.iter()
+                .filter(|m| &m.video_key == video_key)
+                .collect();
+            let keep = match library.get_mut(video_key) {
+                Some(video) => prune_missing_files(video, &missing),
+                None => continue,
+            };
+            if !keep {
+                library.remove(video_key);
+                index.remove(video_key);
+                report.pruned.push(video_key.clone());
+            }
+        }
+    }
+    for (video_key, video) in adopted_videos {
+        // the same video is already in the library, so this is a leftover copy
+        if library.contains_key(&video_key) {
+            continue;
+        }
+        let adopted_files: Vec<PathBuf> = referenced_files(&video).into_iter().map(|(_, p)| p.clone()).collect();
+        report.orphans.retain(|o| !adopted_files.contains(o));
+        index.insert(&video_key, &video);
+        library.insert(video_key.clone(), video);
+        report.adopted.push(video_key);
+    }
+    drop(index);
+    data_store::write_json_data(DATA_STORE_PATH, &guard)?;
+    Ok(report)
+}
+
+/// runs a report only check in the background
+/// when the server starts, and logs what it found
+pub async fn startup_library_check() {
+    match check_library(FsckRequest::default()).await {
+        Err(e) => println!("Failed to check library: {}", e),
+        Ok(report) => {
+            for missing in report.missing.iter() {
+                println!("library check: missing {:?} file {:?} of {}", missing.kind, missing.path, missing.video_key);
+            }
+            for orphan in report.orphans.iter() {
+                println!("library check: orphan file {:?}", orphan);
+            }
+            for corrupt in report.corrupt.iter() {
+                println!("library check: corrupt {:?} file {:?} of {}: {}", corrupt.kind, corrupt.path, corrupt.video_key, corrupt.error);
+            }
+            if !report.missing.is_empty() || !report.orphans.is_empty() || !report.corrupt.is_empty() {
+                println!("library check: POST /admin/fsck with prune_missing or adopt_orphans to repair");
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::Clip;
+    use super::super::SubtitleTrack;
+
+    fn test_videos() -> Vec<(String, DownloadedVideo)> {
+        let mut video = DownloadedVideo::default();
+        video.location = "dl/abc.mp4".into();
+        video.thumbnail_location = Some("dl/abc.jpg".into());
+        video.subtitles = vec![SubtitleTrack { language: "en".into(), location: "dl/abc.en.srt".into() }];
+        video.clips = vec![Clip { location: "dl/myclip.mp4".into(), ..Default::default() }];
+        vec![("youtube:abc".into(), video)]
+    }
+
+    #[test]
+    fn finds_missing_and_orphan_files() {
+        let videos = test_videos();
+        let missing = find_missing_files(&videos, |p| p != Path::new("dl/abc.jpg") && p != Path::new("dl/myclip.mp4"));
+        assert_eq!(missing.len(), 2);
+        assert_eq!(missing[0].kind, FileKind::Thumbnail);
+        assert_eq!(missing[1].kind, FileKind::Clip);
+
+        let files = vec!["dl/abc.mp4".into(), "dl/abc.en.srt".into(), "dl/stray.webm".into()];
+        assert_eq!(find_orphan_files(&videos, files), vec![PathBuf::from("dl/stray.webm")]);
+
+        let mut video = videos[0].1.clone();
+        let missing_refs: Vec<&MissingFile> = missing.iter().collect();
+        assert!(prune_missing_files(&mut video, &missing_refs));
+        assert!(video.thumbnail_location.is_none());
+        assert!(video.clips.is_empty());
+        assert_eq!(video.subtitles.len(), 1);
+
+        let missing_video = MissingFile { video_key: "youtube:abc".into(), kind: FileKind::Video, path: "dl/abc.mp4".into() };
+        assert!(!prune_missing_files(&mut video, &[&missing_video]));
+    }
+
+    #[test]
+    fn groups_orphan_videos() {
+        let orphans: Vec<PathBuf> = vec![
+            "dl/0a1b2c3d4e5f6g7h.webm".into(), "dl/0a1b2c3d4e5f6g7h.info.json".into(),
+            "dl/0a1b2c3d4e5f6g7h.storyboard.jpg".into(), "dl/0a1b2c3d4e5f6g7h.storyboard.vtt".into(),
+            "dl/0a1b2c3d4e5f6g7h.jpg".into(),
+            "dl/0a1b2c3d4e5f6g7h.en.srt".into(), "dl/0a1b2c3d4e5f6g7hi.png".into(), "dl/notes.txt".into(),
+        ];
+        assert_eq!(group_orphan_videos(&orphans), vec![OrphanVideo {
+            location: "dl/0a1b2c3d4e5f6g7h.webm".into(),
+            thumbnail_location: Some("dl/0a1b2c3d4e5f6g7h.jpg".into()),
+            info_json_location: Some("dl/0a1b2c3d4e5f6g7h.info.json".into()),
+            subtitle_locations: vec!["dl/0a1b2c3d4e5f6g7h.en.srt".into()],
+        }]);
+    }
+}
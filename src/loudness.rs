@@ -0,0 +1,171 @@
+use serde::Deserialize;
+
+use super::create_command;
+use super::cut_video_stage::ClipRange;
+
+/// loudnorm only accepts integrated loudness targets in this range (LUFS)
+pub const MIN_LOUDNESS_TARGET: f64 = -70.0;
+pub const MAX_LOUDNESS_TARGET: f64 = -5.0;
+/// in dBTP
+pub const LOUDNORM_TRUE_PEAK: f64 = -1.5;
+/// in LU
+pub const LOUDNORM_LOUDNESS_RANGE: f64 = 11.0;
+/// loudnorm resamples to 192kHz internally, so the output
+/// rate has to be set explicitly
+pub const LOUDNORM_SAMPLE_RATE: u32 = 48000;
+
+pub fn validate_loudness_target(target: f64) -> Result<(), String> {
+    if !(MIN_LOUDNESS_TARGET..=MAX_LOUDNESS_TARGET).contains(&target) {
+        return Err(format!(
+            "Loudness target must be between {} and {} LUFS",
+            MIN_LOUDNESS_TARGET, MAX_LOUDNESS_TARGET,
+        ));
+    }
+    Ok(())
+}
+
+/// what the first loudnorm pass measured. it prints these as strings
+#[derive(Debug, Deserialize)]
+struct LoudnormOutput {
+    input_i: String,
+    input_tp: String,
+    input_lra: String,
+    input_thresh: String,
+    target_offset: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoudnessMeasurement {
+    pub input_i: f64,
+    pub input_tp: f64,
+    pub input_lra: f64,
+    pub input_thresh: f64,
+    pub target_offset: f64,
+}
+
+/// loudnorm prints its measurements as a json object at the
+/// end of its log, after a line like: [Parsed_loudnorm_0 @ 0x55]
+pub fn parse_loudnorm_output<S: AsRef<str>>(output: S) -> Result<LoudnessMeasurement, String> {
+    let output = output.as_ref();
+    let json_string = match (output.rfind('{'), output.rfind('}')) {
+        (Some(start), Some(end)) if start < end => &output[start..=end],
+        _ => return Err("Failed to find the loudnorm measurements".into()),
+    };
+    let measured: LoudnormOutput = serde_json::from_str(json_string).map_err(
+        |e| format!("Failed to parse the loudnorm measurements: {}", e))?;
+    let parse = |value: &String| value.trim().parse::<f64>().map_err(
+        |_| format!("Invalid loudnorm measurement: {}", value));
+    Ok(LoudnessMeasurement {
+        input_i: parse(&measured.input_i)?,
+        input_tp: parse(&measured.input_tp)?,
+        input_lra: parse(&measured.input_lra)?,
+        input_thresh: parse(&measured.input_thresh)?,
+        target_offset: parse(&measured.target_offset)?,
+    })
+}
+
+fn loudnorm_targets(target: f64) -> String {
+    format!("loudnorm=I={}:TP={}:LRA={}", target, LOUDNORM_TRUE_PEAK, LOUDNORM_LOUDNESS_RANGE)
+}
+
+/// the -af filter of the second pass. with the measured values, loudnorm
+/// can apply a single linear gain instead of compressing dynamically
+pub fn loudnorm_filter(target: f64, measured: &LoudnessMeasurement) -> String {
+    format!(
+        "{}:measured_I={}:measured_TP={}:measured_LRA={}:measured_thresh={}:offset={}:linear=true",
+        loudnorm_targets(target),
+        measured.input_i, measured.input_tp, measured.input_lra,
+        measured.input_thresh, measured.target_offset,
+    )
+}
+
+/// the first pass. runs loudnorm over the range of the input
+/// without writing anything, just to measure it
+pub async fn measure_loudness(input_string: &String, range: &ClipRange, target: f64) -> Result<LoudnessMeasurement, String> {
+    let mut exe_and_args: Vec<String> = vec![
+        "ffmpeg".into(),
+        // loudnorm prints its measurements at the info level
+        "-loglevel".into(), "info".into(),
+        "-hide_banner".into(),
+        "-nostats".into(),
+    ];
+    if let Some(start) = range.start {
+        exe_and_args.push("-ss".into());
+        exe_and_args.push(start.to_string());
+    }
+    exe_and_args.push("-i".into());
+    exe_and_args.push(input_string.clone());
+    if let Some(duration) = range.duration {
+        exe_and_args.push("-t".into());
+        exe_and_args.push(duration.to_string());
+    }
+    exe_and_args.push("-vn".into());
+    exe_and_args.push("-af".into());
+    exe_and_args.push(format!("{}:print_format=json", loudnorm_targets(target)));
+    exe_and_args.push("-f".into());
+    exe_and_args.push("null".into());
+    exe_and_args.push("-".into());
+    let mut cmd = create_command(&exe_and_args[..]);
+    let output = cmd.output().await.map_err(
+        |e| format!("Failed to run ffmpeg: {}", e))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        let last_line = stderr.lines().last().unwrap_or_default();
+        return Err(format!("Loudness measurement failed: {}", last_line));
+    }
+    parse_loudnorm_output(stderr)
+}
+
+/// measures the range and returns the -af filter that normalizes it.
+/// None if there is nothing to normalize, ie: the range is silent
+pub async fn loudness_filter(input_string: &String, range: &ClipRange, target: f64) -> Result<Option<String>, String> {
+    let measured = measure_loudness(input_string, range, target).await?;
+    if !measured.input_i.is_finite() || !measured.input_thresh.is_finite() {
+        return Ok(None);
+    }
+    Ok(Some(loudnorm_filter(target, &measured)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_loudnorm_measurements() {
+        let output = r#"
+[Parsed_loudnorm_0 @ 0x5581]
+{
+	"input_i" : "-27.61",
+	"input_tp" : "-4.47",
+	"input_lra" : "18.06",
+	"input_thresh" : "-39.20",
+	"output_i" : "-16.58",
+	"output_tp" : "-1.50",
+	"output_lra" : "14.78",
+	"output_thresh" : "-27.71",
+	"normalization_type" : "dynamic",
+	"target_offset" : "0.58"
+}
+"#;
+        let measured = parse_loudnorm_output(output).unwrap();
+        assert_eq!(measured, LoudnessMeasurement {
+            input_i: -27.61,
+            input_tp: -4.47,
+            input_lra: 18.06,
+            input_thresh: -39.2,
+            target_offset: 0.58,
+        });
+        assert_eq!(
+            loudnorm_filter(-16.0, &measured),
+            "loudnorm=I=-16:TP=-1.5:LRA=11:measured_I=-27.61:measured_TP=-4.47:measured_LRA=18.06:measured_thresh=-39.2:offset=0.58:linear=true",
+        );
+
+        let silent = parse_loudnorm_output(output.replace("-27.61", "-inf")).unwrap();
+        assert!(!silent.input_i.is_finite());
+        assert!(parse_loudnorm_output("no json here").is_err());
+
+        assert!(validate_loudness_target(-16.0).is_ok());
+        assert!(validate_loudness_target(-4.0).is_err());
+        assert!(validate_loudness_target(-71.0).is_err());
+    }
+}
@@ -23,6 +23,8 @@ async fn main() -> std::io::Result<()> {
         return Err(error.into());
     }
     let config = config_res.unwrap();
+    // only reports what is wrong, use /admin/fsck to repair
+    tokio::spawn(download_manager::startup_library_check());
 
     let local = tokio::task::LocalSet::new();
     let sys = actix_web::rt::System::run_in_tokio("server", &local);
@@ -30,7 +32,26 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .route("/download", web_post!(download))
             .route("/get", web_post!(get_progresses))
+            .route("/info", web_post!(get_info))
             .route("/videos", web_get!(list_source_videos))
+            .route("/videos/{id}/chapters", web_get!(get_video_chapters))
+            .route("/videos/{id}/waveform", web_get!(get_video_waveform))
+            .route("/videos/{id}/scenes", web_get!(get_video_scenes))
+            .route("/videos/{id}/annotations", web_get!(get_video_annotations))
+            .route("/videos/{id}/annotations", web_put!(put_video_annotations))
+            .route("/videos/{id}/annotations", web_delete!(delete_video_annotations))
+            .route("/clips", web_get!(list_clips))
+            .route("/clips/{id}/annotations", web_get!(get_clip_annotations))
+            .route("/clips/{id}/annotations", web_put!(put_clip_annotations))
+            .route("/clips/{id}/annotations", web_delete!(delete_clip_annotations))
+            .route("/collections", web_get!(list_collections))
+            .route("/admin/fsck", web_post!(check_library))
+            .route("/import", web_post!(import_video))
+            .route("/uploads", web_post!(create_upload))
+            .route("/uploads/{id}", web_get!(get_upload_status))
+            .route("/uploads/{id}", web_patch!(append_upload_chunk))
+            .route("/uploads/{id}", web_delete!(cancel_upload))
+            .route("/uploads/{id}/complete", web_post!(complete_upload))
             .service(Files::new("/img/", config.download_dir.clone()))
             .service(Files::new("/", config.frontend_dir.clone()).index_file("index.html"))
     })
@@ -53,3 +74,21 @@ macro_rules! web_post {
         web::post().to($token)
     };
 }
+#[macro_export]
+macro_rules! web_put {
+    ($token:tt) => {
+        web::put().to($token)
+    };
+}
+#[macro_export]
+macro_rules! web_patch {
+    ($token:tt) => {
+        web::patch().to($token)
+    };
+}
+#[macro_export]
+macro_rules! web_delete {
+    ($token:tt) => {
+        web::delete().to($token)
+    };
+}
@@ -0,0 +1,186 @@
+use std::path::Path;
+use serde::Deserialize;
+
+use super::create_command;
+
+/// the clips we cut get this as their comment tag, so a clip
+/// that lost its source can be told apart from a source video
+pub const CLIP_MARKER: &'static str = "vidclipper clip";
+
+/// what ffprobe tells us about a media file
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MediaInfo {
+    /// in seconds
+    pub duration: Option<f64>,
+    /// the size as displayed. ffmpeg rotates videos that are stored
+    /// sideways, so filters see this size rather than the coded one
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub has_video: bool,
+    pub has_audio: bool,
+    /// true if the file has the CLIP_MARKER
+    pub is_clip: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct FfprobeStream {
+    codec_type: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    tags: Option<FfprobeTags>,
+    #[serde(default)]
+    side_data_list: Vec<FfprobeSideData>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct FfprobeTags {
+    /// older files say how they are rotated in a tag
+    rotate: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct FfprobeSideData {
+    /// newer ones have a display matrix, where this can be negative
+    rotation: Option<f64>,
+}
+
+impl FfprobeStream {
+    /// true if the video is stored sideways
+    fn is_rotated_sideways(&self) -> bool {
+        let tag_rotation = self.tags.as_ref()
+            .and_then(|t| t.rotate.as_ref())
+            .and_then(|r| r.trim().parse::<f64>().ok());
+        let side_data_rotation = self.side_data_list.iter().find_map(|d| d.rotation);
+        match side_data_rotation.or(tag_rotation) {
+            Some(rotation) => (rotation.round() as i64).rem_euclid(180) == 90,
+            None => false,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct FfprobeFormat {
+    /// ffprobe prints numbers as strings
+    duration: Option<String>,
+    tags: Option<FfprobeFormatTags>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct FfprobeFormatTags {
+    comment: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct FfprobeOutput {
+    #[serde(default)]
+    streams: Vec<FfprobeStream>,
+    format: Option<FfprobeFormat>,
+}
+
+pub fn parse_ffprobe_output<S: AsRef<str>>(json_string: S) -> Result<MediaInfo, String> {
+    let output: FfprobeOutput = serde_json::from_str(json_string.as_ref()).map_err(
+        |e| format!("Failed to parse ffprobe output: {}", e))?;
+    let format = output.format.unwrap_or_default();
+    let mut info = MediaInfo {
+        duration: format.duration.and_then(|d| d.parse::<f64>().ok()),
+        is_clip: format.tags.and_then(|t| t.comment).as_deref() == Some(CLIP_MARKER),
+        ..Default::default()
+    };
+    for stream in output.streams {
+        match stream.codec_type.as_deref() {
+            Some("video") => {
+                // the first video stream is the one players show
+                if !info.has_video && stream.is_rotated_sideways() {
+                    info.width = stream.height;
+                    info.height = stream.width;
+                } else if !info.has_video {
+                    info.width = stream.width;
+                    info.height = stream.height;
+                }
+                info.has_video = true;
+            },
+            Some("audio") => info.has_audio = true,
+            _ => {},
+        }
+    }
+    if !info.has_video && !info.has_audio {
+        return Err("File does not have any audio or video streams".into());
+    }
+    Ok(info)
+}
+
+/// runs ffprobe on the file. fails if ffprobe cannot
+/// read it, which usually means the file is corrupt
+pub async fn probe_media<P: AsRef<Path>>(path: P) -> Result<MediaInfo, String> {
+    let path_string = match path.as_ref().to_str() {
+        Some(s) => s.to_string(),
+        None => return Err(format!("File path contains invalid characters: {:?}", path.as_ref())),
+    };
+    let exe_and_args = vec![
+        "ffprobe",
+        "-v", "error",
+        "-show_entries", "format=duration:format_tags=comment:stream=codec_type,width,height:stream_tags=rotate:stream_side_data=rotation",
+        "-of", "json",
+        &path_string,
+    ];
+    let mut cmd = create_command(&exe_and_args[..]);
+    let output = cmd.output().await.map_err(
+        |e| format!("Failed to run ffprobe: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("ffprobe failed: {}", stderr.trim()));
+    }
+    parse_ffprobe_output(String::from_utf8_lossy(&output.stdout))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_parse_ffprobe_output() {
+        let json_string = r#"{
+            "programs": [],
+            "streams": [
+                { "codec_type": "video", "width": 1280, "height": 720 },
+                { "codec_type": "audio" },
+                { "codec_type": "video", "width": 320, "height": 240 }
+            ],
+            "format": { "duration": "12.500000" }
+        }"#;
+        let info = parse_ffprobe_output(json_string).unwrap();
+        assert_eq!(info, MediaInfo {
+            duration: Some(12.5),
+            width: Some(1280),
+            height: Some(720),
+            has_video: true,
+            has_audio: true,
+            is_clip: false,
+        });
+
+        let clip = r#"{ "streams": [{ "codec_type": "audio" }], "format": { "tags": { "comment": "vidclipper clip" } } }"#;
+        assert!(parse_ffprobe_output(clip).unwrap().is_clip);
+
+        let audio_only = parse_ffprobe_output(r#"{ "streams": [{ "codec_type": "audio" }], "format": {} }"#).unwrap();
+        assert!(!audio_only.has_video);
+        assert_eq!(audio_only.duration, None);
+
+        let rotated_tag = r#"{ "streams": [{ "codec_type": "video", "width": 1920, "height": 1080, "tags": { "rotate": "90" } }] }"#;
+        let rotated_matrix = r#"{ "streams": [{
+            "codec_type": "video", "width": 1920, "height": 1080,
+            "side_data_list": [{ "side_data_type": "Display Matrix", "rotation": -90 }]
+        }] }"#;
+        let upside_down = r#"{ "streams": [{ "codec_type": "video", "width": 1920, "height": 1080, "tags": { "rotate": "180" } }] }"#;
+        for (json_string, size) in [
+            (rotated_tag, (1080, 1920)),
+            (rotated_matrix, (1080, 1920)),
+            (upside_down, (1920, 1080)),
+        ] {
+            let info = parse_ffprobe_output(json_string).unwrap();
+            assert_eq!((info.width.unwrap(), info.height.unwrap()), size);
+        }
+
+        assert!(parse_ffprobe_output(r#"{ "streams": [] }"#).is_err());
+        assert!(parse_ffprobe_output("not json").is_err());
+    }
+}
@@ -0,0 +1,265 @@
+use std::path::Path;
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+
+use super::import::validate_import_path;
+use super::subtitles::escape_filter_path;
+use super::uploads::received_upload_path;
+
+/// images that can be overlaid on a clip
+pub const OVERLAY_IMAGE_EXTENSIONS: [&str; 5] = ["png", "jpg", "jpeg", "webp", "bmp"];
+/// pixels between an overlay and the edge of the frame
+pub const OVERLAY_MARGIN: u32 = 20;
+pub const DEFAULT_CAPTION_SIZE: u32 = 48;
+pub const MAX_CAPTION_SIZE: u32 = 500;
+pub const MAX_CAPTION_LENGTH: usize = 500;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlayPosition {
+    TopLeft,
+    Top,
+    TopRight,
+    Center,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+/// an image, ie: a logo, drawn on top of every frame of the clip
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ImageOverlay {
+    /// a path on the server. for requests, it must be inside
+    /// one of the import_dirs, the config can use any path
+    pub path: Option<PathBuf>,
+    /// or an image that was sent to /uploads. it does not
+    /// need to be completed, only fully received
+    pub upload_id: Option<String>,
+    /// defaults to bottom_right
+    pub position: Option<OverlayPosition>,
+    /// from 0 to 1, defaults to 1
+    pub opacity: Option<f64>,
+    /// scale the image to this many pixels wide
+    pub width: Option<u32>,
+    /// set for the watermark from the config, which
+    /// is allowed to use paths outside of the import_dirs
+    #[serde(skip)]
+    pub trusted: bool,
+}
+
+/// text drawn on top of every frame of the clip
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TextOverlay {
+    pub text: String,
+    /// a font family name, ie: "DejaVu Sans". defaults to ffmpeg's default font
+    pub font: Option<String>,
+    /// in pixels, defaults to DEFAULT_CAPTION_SIZE
+    pub size: Option<u32>,
+    /// an ffmpeg color, ie: white, yellow@0.8, 0xffcc00. defaults to white
+    pub color: Option<String>,
+    /// defaults to bottom
+    pub position: Option<OverlayPosition>,
+}
+
+/// the watermark image as an ffmpeg input, and
+/// the filters that go with it
+#[derive(Clone, Debug, PartialEq)]
+pub struct OverlayInput {
+    pub path: PathBuf,
+    /// applied to the image before it is overlaid
+    pub image_filters: Vec<String>,
+    /// the overlay filter itself
+    pub overlay: String,
+}
+
+pub fn overlay_image_extension<P: AsRef<Path>>(path: P) -> Result<String, String> {
+    let extension = path.as_ref().extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .unwrap_or_default();
+    if !OVERLAY_IMAGE_EXTENSIONS.iter().any(|e| *e == extension) {
+        return Err(format!("Cannot overlay images with extension '{}'", extension));
+    }
+    Ok(extension)
+}
+
+/// colors and font names end up inside the filter
+/// string, so only let through what they can contain
+fn validate_filter_value<S: AsRef<str>>(kind: &str, value: S, extra_chars: &str) -> Result<(), String> {
+    let value = value.as_ref();
+    let is_valid = !value.is_empty() && value.len() <= 64 &&
+        value.chars().all(|c| c.is_ascii_alphanumeric() || extra_chars.contains(c));
+    if !is_valid {
+        return Err(format!("Invalid {} '{}'", kind, value));
+    }
+    Ok(())
+}
+
+pub fn validate_image_overlay(overlay: &ImageOverlay) -> Result<(), String> {
+    match (&overlay.path, &overlay.upload_id) {
+        (Some(path), None) => { overlay_image_extension(path)?; },
+        (None, Some(upload_id)) => validate_filter_value("upload id", upload_id, "")?,
+        _ => return Err("The watermark needs either a path or an upload_id".into()),
+    }
+    if let Some(opacity) = overlay.opacity {
+        if !(0.0..=1.0).contains(&opacity) {
+            return Err("The watermark opacity must be between 0 and 1".into());
+        }
+    }
+    if overlay.width == Some(0) {
+        return Err("The watermark width must be positive".into());
+    }
+    Ok(())
+}
+
+pub fn validate_text_overlay(overlay: &TextOverlay) -> Result<(), String> {
+    if overlay.text.trim().is_empty() || overlay.text.chars().count() > MAX_CAPTION_LENGTH {
+        return Err(format!("The caption must have between 1 and {} characters", MAX_CAPTION_LENGTH));
+    }
+    if let Some(ref font) = overlay.font {
+        validate_filter_value("font", font, " -_")?;
+    }
+    if let Some(ref color) = overlay.color {
+        validate_filter_value("caption color", color, "#@.")?;
+    }
+    if let Some(size) = overlay.size {
+        if size == 0 || size > MAX_CAPTION_SIZE {
+            return Err(format!("The caption size must be between 1 and {}", MAX_CAPTION_SIZE));
+        }
+    }
+    Ok(())
+}
+
+/// the x and y expressions that put something of size (item_w, item_h)
+/// at the position inside of a frame of size (main_w, main_h).
+/// the overlay and drawtext filters name these sizes differently
+pub fn position_expressions(
+    position: OverlayPosition,
+    (main_w, main_h): (&str, &str),
+    (item_w, item_h): (&str, &str),
+) -> (String, String) {
+    let m = OVERLAY_MARGIN;
+    let left = m.to_string();
+    let center_x = format!("({}-{})/2", main_w, item_w);
+    let right = format!("{}-{}-{}", main_w, item_w, m);
+    let top = m.to_string();
+    let center_y = format!("({}-{})/2", main_h, item_h);
+    let bottom = format!("{}-{}-{}", main_h, item_h, m);
+    match position {
+        OverlayPosition::TopLeft => (left, top),
+        OverlayPosition::Top => (center_x, top),
+        OverlayPosition::TopRight => (right, top),
+        OverlayPosition::Center => (center_x, center_y),
+        OverlayPosition::BottomLeft => (left, bottom),
+        OverlayPosition::Bottom => (center_x, bottom),
+        OverlayPosition::BottomRight => (right, bottom),
+    }
+}
+
+/// finds the image of the watermark, and makes sure a
+/// request cannot read files outside of the import_dirs
+pub fn resolve_overlay_image(overlay: &ImageOverlay, import_dirs: &[PathBuf]) -> Result<PathBuf, String> {
+    let path = match (&overlay.path, &overlay.upload_id) {
+        (Some(path), _) if overlay.trusted => path.clone(),
+        (Some(path), _) => validate_import_path(path, import_dirs)?,
+        (None, Some(upload_id)) => received_upload_path(upload_id)?,
+        (None, None) => return Err("The watermark needs either a path or an upload_id".into()),
+    };
+    if !path.is_file() {
+        return Err(format!("Failed to find the watermark image {:?}", path));
+    }
+    Ok(path)
+}
+
+pub fn image_overlay_input(overlay: &ImageOverlay, path: PathBuf) -> OverlayInput {
+    let mut image_filters = vec![];
+    if let Some(width) = overlay.width {
+        image_filters.push(format!("scale={}:-1", width));
+    }
+    image_filters.push("format=rgba".into());
+    if let Some(opacity) = overlay.opacity.filter(|o| *o < 1.0) {
+        image_filters.push(format!("colorchannelmixer=aa={}", opacity));
+    }
+    let position = overlay.position.unwrap_or(OverlayPosition::BottomRight);
+    let (x, y) = position_expressions(position, ("W", "H"), ("w", "h"));
+    OverlayInput {
+        path,
+        image_filters,
+        overlay: format!("overlay={}:{}", x, y),
+    }
+}
+
+/// the caption text is read from a file rather than put into the
+/// filter, which avoids escaping it. expansion is turned off so
+/// text like %{...} is drawn as is
+pub fn caption_filter<S: AsRef<str>>(overlay: &TextOverlay, textfile: S) -> String {
+    let position = overlay.position.unwrap_or(OverlayPosition::Bottom);
+    let (x, y) = position_expressions(position, ("w", "h"), ("text_w", "text_h"));
+    let mut filter = format!(
+        "drawtext=textfile={}:expansion=none:fontsize={}:fontcolor={}:x={}:y={}",
+        escape_filter_path(textfile),
+        overlay.size.unwrap_or(DEFAULT_CAPTION_SIZE),
+        overlay.color.as_deref().unwrap_or("white"),
+        x, y,
+    );
+    if let Some(ref font) = overlay.font {
+        filter.push_str(&format!(":font='{}'", font));
+    }
+    filter
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_overlay_filters() {
+        let watermark = ImageOverlay {
+            path: Some("logos/logo.png".into()),
+            opacity: Some(0.5),
+            width: Some(120),
+            ..Default::default()
+        };
+        assert!(validate_image_overlay(&watermark).is_ok());
+        let input = image_overlay_input(&watermark, "logos/logo.png".into());
+        assert_eq!(input.image_filters, vec!["scale=120:-1", "format=rgba", "colorchannelmixer=aa=0.5"]);
+        assert_eq!(input.overlay, "overlay=W-w-20:H-h-20");
+
+        let caption = TextOverlay {
+            text: "hello: world".into(),
+            font: Some("DejaVu Sans".into()),
+            position: Some(OverlayPosition::Top),
+            ..Default::default()
+        };
+        assert!(validate_text_overlay(&caption).is_ok());
+        assert_eq!(
+            caption_filter(&caption, "dl/abc.caption.txt"),
+            "drawtext=textfile=dl/abc.caption.txt:expansion=none:fontsize=48:fontcolor=white:x=(w-text_w)/2:y=20:font='DejaVu Sans'",
+        );
+    }
+
+    #[test]
+    fn validates_overlays() {
+        assert!(validate_image_overlay(&ImageOverlay::default()).is_err());
+        let both = ImageOverlay { path: Some("a.png".into()), upload_id: Some("abc".into()), ..Default::default() };
+        assert!(validate_image_overlay(&both).is_err());
+        let not_image = ImageOverlay { path: Some("a.mp4".into()), ..Default::default() };
+        assert!(validate_image_overlay(&not_image).is_err());
+        let too_opaque = ImageOverlay { upload_id: Some("abc".into()), opacity: Some(1.5), ..Default::default() };
+        assert!(validate_image_overlay(&too_opaque).is_err());
+
+        let mut caption = TextOverlay { text: "  ".into(), ..Default::default() };
+        assert!(validate_text_overlay(&caption).is_err());
+        caption.text = "hi".into();
+        caption.color = Some("white:fontfile=/etc/passwd".into());
+        assert!(validate_text_overlay(&caption).is_err());
+        caption.color = Some("#ffcc00@0.8".into());
+        assert!(validate_text_overlay(&caption).is_ok());
+        caption.font = Some("Sans'".into());
+        assert!(validate_text_overlay(&caption).is_err());
+
+        // requests cannot read files from outside the import_dirs
+        let watermark = ImageOverlay { path: Some("/etc/hostname.png".into()), ..Default::default() };
+        assert!(resolve_overlay_image(&watermark, &[]).is_err());
+    }
+}
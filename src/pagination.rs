@@ -0,0 +1,179 @@
+use std::cmp::Ordering;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+/// the value an item is sorted by
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SortValue {
+    Number(f64),
+    Text(String),
+    Missing,
+}
+
+impl From<Option<f64>> for SortValue {
+    fn from(value: Option<f64>) -> Self {
+        value.map_or(SortValue::Missing, SortValue::Number)
+    }
+}
+
+impl From<Option<String>> for SortValue {
+    fn from(value: Option<String>) -> Self {
+        value.map_or(SortValue::Missing, SortValue::Text)
+    }
+}
+
+/// points at the last item of the previous page. the next
+/// page starts with the first item that sorts after it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Cursor {
+    pub value: SortValue,
+    pub key: String,
+}
+
+/// items that are missing the value always go last,
+/// regardless of the order
+pub fn compare_sort_values(a: &SortValue, b: &SortValue, order: SortOrder) -> Ordering {
+    let ordering = match (a, b) {
+        (SortValue::Missing, SortValue::Missing) => return Ordering::Equal,
+        (_, SortValue::Missing) => return Ordering::Less,
+        (SortValue::Missing, _) => return Ordering::Greater,
+        (SortValue::Number(a), SortValue::Number(b)) => a.partial_cmp(b).unwrap_or(Ordering::Equal),
+        (SortValue::Text(a), SortValue::Text(b)) => a.cmp(b),
+        // shouldnt happen since every item is sorted by the same field
+        (SortValue::Number(_), SortValue::Text(_)) => Ordering::Less,
+        (SortValue::Text(_), SortValue::Number(_)) => Ordering::Greater,
+    };
+    match order {
+        SortOrder::Asc => ordering,
+        SortOrder::Desc => ordering.reverse(),
+    }
+}
+
+/// the key is always the last tie breaker so the order is
+/// stable between requests
+pub fn compare_entries(
+    a_value: &SortValue,
+    a_key: &String,
+    b_value: &SortValue,
+    b_key: &String,
+    order: SortOrder,
+) -> Ordering {
+    compare_sort_values(a_value, b_value, order).then_with(|| a_key.cmp(b_key))
+}
+
+/// cursors are handed to clients as an opaque hex string
+pub fn encode_cursor(cursor: &Cursor) -> String {
+    let json_string = serde_json::to_string(cursor).unwrap_or_default();
+    json_string.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn decode_cursor<S: AsRef<str>>(cursor: S) -> Result<Cursor, String> {
+    let cursor = cursor.as_ref();
+    let invalid = || format!("Invalid cursor: {}", cursor);
+    if cursor.len() % 2 != 0 || !cursor.is_ascii() {
+        return Err(invalid());
+    }
+    let mut bytes = Vec::with_capacity(cursor.len() / 2);
+    for i in (0..cursor.len()).step_by(2) {
+        let byte = u8::from_str_radix(&cursor[i..(i + 2)], 16).map_err(|_| invalid())?;
+        bytes.push(byte);
+    }
+    serde_json::from_slice(&bytes).map_err(|_| invalid())
+}
+
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// only set if there are more items after this page
+    pub next_cursor: Option<String>,
+}
+
+/// sorts the entries, skips everything up to and including the cursor,
+/// then skips offset more entries, and returns at most limit entries
+pub fn paginate<T>(
+    entries: Vec<(SortValue, String, T)>,
+    order: SortOrder,
+    cursor: Option<&Cursor>,
+    offset: usize,
+    limit: Option<usize>,
+) -> Page<T> {
+    let mut entries = entries;
+    entries.sort_by(|(a_value, a_key, _), (b_value, b_key, _)| {
+        compare_entries(a_value, a_key, b_value, b_key, order)
+    });
+
+    let remaining = entries.into_iter().filter(|(value, key, _)| match cursor {
+        None => true,
+        Some(c) => compare_entries(value, key, &c.value, &c.key, order) == Ordering::Greater,
+    }).skip(offset);
+
+    let limit = limit.unwrap_or(usize::MAX);
+    let mut page = vec![];
+    let mut has_more = false;
+    let mut last = None;
+    for (value, key, item) in remaining {
+        if page.len() >= limit {
+            has_more = true;
+            break;
+        }
+        last = Some(Cursor { value, key });
+        page.push(item);
+    }
+
+    let next_cursor = match (has_more, last) {
+        (true, Some(last)) => Some(encode_cursor(&last)),
+        _ => None,
+    };
+    Page { items: page, next_cursor }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<(SortValue, String, u32)> {
+        vec![
+            (SortValue::Number(3.0), "c".into(), 3),
+            (SortValue::Number(1.0), "a".into(), 1),
+            (SortValue::Missing, "d".into(), 4),
+            (SortValue::Number(1.0), "b".into(), 2),
+        ]
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        let cursor = Cursor { value: SortValue::Text("héllo".into()), key: "k".into() };
+        let encoded = encode_cursor(&cursor);
+        assert!(encoded.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(decode_cursor(&encoded).unwrap(), cursor);
+        assert!(decode_cursor("zz").is_err());
+        assert!(decode_cursor("abc").is_err());
+    }
+
+    #[test]
+    fn paginates_with_cursor() {
+        let page = paginate(entries(), SortOrder::Asc, None, 0, Some(2));
+        assert_eq!(page.items, vec![1, 2]);
+        let cursor = decode_cursor(page.next_cursor.unwrap()).unwrap();
+
+        let page = paginate(entries(), SortOrder::Asc, Some(&cursor), 0, Some(2));
+        assert_eq!(page.items, vec![3, 4]);
+        assert!(page.next_cursor.is_none());
+
+        // missing values still go last when descending
+        let page = paginate(entries(), SortOrder::Desc, None, 0, None);
+        assert_eq!(page.items, vec![3, 1, 2, 4]);
+
+        // the cursor still works if the item it points at was removed
+        let mut without_b = entries();
+        without_b.retain(|(_, key, _)| key != "b");
+        let cursor = Cursor { value: SortValue::Number(1.0), key: "b".into() };
+        let page = paginate(without_b, SortOrder::Asc, Some(&cursor), 0, None);
+        assert_eq!(page.items, vec![3, 4]);
+    }
+}
@@ -1,97 +1,635 @@
+use actix_web::HttpRequest;
 use actix_web::HttpResponse;
 use actix_web::web;
+use actix_web::http::header::CONTENT_TYPE;
+use actix_multipart::Multipart;
+use futures::StreamExt;
+use tokio::io::AsyncWriteExt;
 use progresslib2_server_extension::get_all_progresses_json;
 use progresslib2_server_extension::GetProgressRequest;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 
 use super::download_manager;
 use super::download_manager::DownloadRequest;
+use super::download_manager::VideoQuery;
+use super::download_manager::ProgressQuery;
+use super::download_manager::Page;
+use super::download_manager::Annotations;
+use super::download_manager::ClipQuery;
+use super::download_manager::FsckRequest;
+use super::download_manager::DownloadedVideo;
+use super::download_manager::ImportMode;
+use super::download_manager::ImportRequest;
+use super::download_manager::CreateUploadRequest;
+use super::download_manager::UploadError;
+use super::download_manager::LibraryError;
+use actix_web::dev::Body;
+use actix_web::dev::ResponseBody;
 
 
-pub async fn get_progresses(item: Option<web::Json<GetProgressRequest>>) -> HttpResponse {
+/// paginated responses set this header if there is a next page.
+/// pass its value as the cursor query param to get the next page
+pub const NEXT_CURSOR_HEADER: &'static str = "X-Next-Cursor";
+/// resumable uploads send the offset each chunk starts at in this header
+pub const UPLOAD_OFFSET_HEADER: &'static str = "Upload-Offset";
+/// /download responds with the requested clip name, and the
+/// key of the job that makes it in this header. the clips can end up
+/// with a -N suffix if the name was taken, the final ids are in the
+/// clips of the job info that /get returns for this key
+pub const JOB_KEY_HEADER: &'static str = "X-Job-Key";
+/// json bodies of /import are tiny, they only contain a path
+pub const MAX_IMPORT_JSON_BYTES: usize = 64 * 1024;
+
+pub async fn get_progresses(
+    item: Option<web::Json<GetProgressRequest>>,
+    query: web::Query<ProgressQuery>,
+) -> HttpResponse {
     let json_request_option = item.map_or_else(|| None, |o| Some(o.0));
-    get_all_progresses_json(json_request_option, &download_manager::PROGHOLDER)
+    let mut response = get_all_progresses_json(json_request_option, &download_manager::PROGHOLDER);
+    // without any pagination params we return
+    // exactly what the server extension gives us
+    if query.is_empty() || !response.status().is_success() {
+        return response;
+    }
+
+    let body_bytes = match response.take_body() {
+        ResponseBody::Body(Body::Bytes(b)) => b,
+        ResponseBody::Other(Body::Bytes(b)) => b,
+        _ => return make_internal_error("Failed to read progresses"),
+    };
+    let progresses = match serde_json::from_slice(&body_bytes) {
+        Err(e) => return make_internal_error(format!("Failed to parse progresses: {}", e)),
+        Ok(p) => p,
+    };
+    let page = match download_manager::query_progresses(progresses, &query) {
+        Err(e) => return make_bad_request(e),
+        Ok(p) => p,
+    };
+
+    make_page_response(page)
+}
+
+pub fn make_page_response<T: Serialize>(page: Page<T>) -> HttpResponse {
+    let json_string = match serde_json::to_string(&page.items) {
+        Err(e) => return make_internal_error(format!("Failed to serialize output: {}", e)),
+        Ok(s) => s,
+    };
+
+    let mut response = HttpResponse::Ok();
+    if let Some(next_cursor) = page.next_cursor {
+        response.header(NEXT_CURSOR_HEADER, next_cursor);
+    }
+    response.body(json_string).into()
 }
 
 pub async fn download(item: web::Json<DownloadRequest>) -> HttpResponse {
     let mut download_request = item.0;
 
     let using_name = match download_request.name {
-        Some(ref name) => name.clone(),
-        None => {
-            let name = download_manager::random_download_name();
-            download_request.name = Some(name.clone());
-            name
-        }
+        Some(ref name) => match download_manager::sanitize_clip_name(name) {
+            Ok(sanitized) => sanitized,
+            Err(e) => return make_bad_request(format!("Invalid clip name: {}", e)),
+        },
+        None => download_manager::random_download_name(),
     };
+    download_request.name = Some(using_name.clone());
+
+    if download_request.chapter.is_some() && download_request.all_chapters {
+        return make_bad_request("Cannot set both chapter and all_chapters");
+    }
+
+    for language in download_request.subtitle_languages.iter() {
+        if let Err(e) = download_manager::validate_subtitle_language(language) {
+            return make_bad_request(e);
+        }
+    }
+
+    if let Some(target) = download_request.loudness_target {
+        if let Err(e) = download_manager::validate_loudness_target(target) {
+            return make_bad_request(e);
+        }
+    }
+
+    if let Err(e) = download_manager::validate_video_filters(&download_request.video_filters) {
+        return make_bad_request(e);
+    }
+
+    if let Some(ref watermark) = download_request.watermark {
+        if let Err(e) = download_manager::validate_image_overlay(watermark) {
+            return make_bad_request(e);
+        }
+    }
+
+    if let Some(ref caption) = download_request.caption {
+        if let Err(e) = download_manager::validate_text_overlay(caption) {
+            return make_bad_request(e);
+        }
+    }
+
+    if let Some(ref animation) = download_request.animation {
+        let has_range = download_request.start.is_some() || download_request.duration.is_some() ||
+            download_request.chapter.is_some() || download_request.all_chapters;
+        if !has_range {
+            return make_bad_request("An animation needs a start, duration, or chapter to make it from");
+        }
+        if let Err(e) = download_manager::validate_animation_request(animation, download_request.duration.map(|d| d as f64)) {
+            return make_bad_request(e);
+        }
+    }
+
+    if let Some(ref format) = download_request.format {
+        if let Err(e) = download_manager::validate_format_selector(format) {
+            return make_bad_request(e);
+        }
+    }
+
+    if let Err(e) = download_manager::validate_download_url(&download_request.url).await {
+        return make_bad_request(format!("Invalid download url: {}", e));
+    }
+
+    if download_request.subtitle_mode.is_some() &&
+        download_manager::library_video_missing_subtitles(&download_request)
+    {
+        return make_bad_request(format!(
+            "This video was downloaded without subtitles for languages: {:?}",
+            download_request.subtitle_languages
+        ));
+    }
 
     // if the above url does not exist, or if it is in an errored state
     // then we can start download
     match download_manager::start_download(
         download_request
     ) {
-        Ok(_) => HttpResponse::Ok().body(using_name).into(),
+        Ok(key) => HttpResponse::Ok().header(JOB_KEY_HEADER, key).body(using_name).into(),
         Err(e) => make_internal_error(format!("Failed to start download: {}", e)),
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct InfoRequest {
+    pub url: String,
+}
+
+pub async fn get_info(item: web::Json<InfoRequest>) -> HttpResponse {
+    let url = item.0.url;
+    if let Err(e) = download_manager::validate_download_url(&url).await {
+        return make_bad_request(format!("Invalid url: {}", e));
+    }
+
+    let metadata = match download_manager::get_url_metadata(url).await {
+        Err(e) => return make_internal_error(format!("Failed to get info: {}", e)),
+        Ok(m) => m,
+    };
+
+    let json_string = match serde_json::to_string(&metadata) {
+        Err(e) => return make_internal_error(format!("Failed to serialize output: {}", e)),
+        Ok(s) => s,
+    };
+
+    HttpResponse::Ok().body(json_string).into()
+}
+
 #[derive(Debug, Default, Serialize)]
 pub struct SourceVideo {
+    pub id: Option<String>,
     pub url: String,
     pub video_data: Option<String>,
     pub thumbnail_data: Option<String>,
+    pub info_json_data: Option<String>,
+    pub storyboard_data: Option<String>,
+    pub storyboard_vtt_data: Option<String>,
     pub title: Option<String>,
     pub description: Option<String>,
+    pub format: Option<String>,
+    pub uploader: Option<String>,
+    pub upload_date: Option<String>,
+    pub duration: Option<f64>,
+    pub view_count: Option<u64>,
+    pub tags: Vec<String>,
+    pub chapters: Vec<download_manager::Chapter>,
+    pub webpage_url: Option<String>,
+    pub extractor: Option<String>,
+    pub video_id: Option<String>,
+    pub added_at: Option<u64>,
+    pub annotations: Annotations,
+}
+
+/// files in the download dir are served under /img/
+/// so turn the path on disk into the path the frontend can use
+pub fn img_path(location: &PathBuf) -> Option<String> {
+    let file_name = location.file_name()?.to_str()?;
+    Some(format!("/img/{}", file_name))
+}
+
+pub fn make_source_video(video_key: String, video_struct: DownloadedVideo) -> SourceVideo {
+    SourceVideo {
+        id: video_struct.id(),
+        url: video_struct.url().cloned().unwrap_or(video_key),
+        video_data: img_path(&video_struct.location),
+        thumbnail_data: video_struct.thumbnail_location.as_ref().and_then(img_path),
+        info_json_data: video_struct.info_json_location.as_ref().and_then(img_path),
+        storyboard_data: video_struct.storyboard.as_ref().and_then(|s| img_path(&s.location)),
+        storyboard_vtt_data: video_struct.storyboard.as_ref().and_then(|s| img_path(&s.vtt_location)),
+        title: video_struct.title,
+        description: video_struct.description,
+        format: video_struct.format,
+        uploader: video_struct.uploader,
+        upload_date: video_struct.upload_date,
+        duration: video_struct.duration,
+        view_count: video_struct.view_count,
+        tags: video_struct.tags,
+        chapters: video_struct.chapters,
+        webpage_url: video_struct.webpage_url,
+        extractor: video_struct.extractor,
+        video_id: video_struct.video_id,
+        added_at: video_struct.added_at,
+        annotations: video_struct.annotations,
+    }
 }
 
-pub async fn list_source_videos() -> HttpResponse {
-    let downloaded_video_list = match download_manager::list_all_downloaded_videos() {
-        Err(e) => return make_internal_error(format!("Failed to list videos: {}", e)),
-        Ok(list) => list,
+pub async fn list_source_videos(query: web::Query<VideoQuery>) -> HttpResponse {
+    let downloaded_video_page = match download_manager::query_downloaded_videos(&query) {
+        Err(e) => return make_library_error_response(e, "Failed to list videos"),
+        Ok(page) => page,
     };
 
     let mut out_vec = vec![];
-    for (url, video_struct) in downloaded_video_list {
-        let thumbnail_path = if let Some(location) = video_struct.thumbnail_location {
-            let filename_string = location.file_name();
-            if filename_string.is_none() {
-                None
-            } else {
-                let filename_string = filename_string.unwrap().to_str();
-                if filename_string.is_none() {
-                    None
-                } else {
-                    let filename_string = filename_string.unwrap();
-                    Some(format!("/img/{}", filename_string))
+    for (video_key, video_struct) in downloaded_video_page.items {
+        out_vec.push(make_source_video(video_key, video_struct));
+    }
+
+    make_page_response(Page {
+        items: out_vec,
+        next_cursor: downloaded_video_page.next_cursor,
+    })
+}
+
+pub async fn get_video_chapters(id: web::Path<String>) -> HttpResponse {
+    let video = match download_manager::find_downloaded_video(id.as_str()) {
+        Err(e) => return make_internal_error(format!("Failed to find video: {}", e)),
+        Ok(None) => return make_not_found(format!("No video with id {}", id.as_str())),
+        Ok(Some((_, video))) => video,
+    };
+
+    let json_string = match serde_json::to_string(&video.chapters) {
+        Err(e) => return make_internal_error(format!("Failed to serialize output: {}", e)),
+        Ok(s) => s,
+    };
+
+    HttpResponse::Ok().body(json_string).into()
+}
+
+pub async fn get_video_scenes(id: web::Path<String>) -> HttpResponse {
+    let video = match download_manager::find_downloaded_video(id.as_str()) {
+        Err(e) => return make_internal_error(format!("Failed to find video: {}", e)),
+        Ok(None) => return make_not_found(format!("No video with id {}", id.as_str())),
+        Ok(Some((_, video))) => video,
+    };
+
+    match video.scenes {
+        None => make_not_found(format!("Scenes of video {} were not detected yet", id.as_str())),
+        Some(scenes) => make_json_response(&scenes),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WaveformQuery {
+    /// the zoom level. the closest level with at least
+    /// this many samples per pixel is returned
+    pub samples_per_pixel: Option<u32>,
+}
+
+pub async fn get_video_waveform(id: web::Path<String>, query: web::Query<WaveformQuery>) -> HttpResponse {
+    let video = match download_manager::find_downloaded_video(id.as_str()) {
+        Err(e) => return make_internal_error(format!("Failed to find video: {}", e)),
+        Ok(None) => return make_not_found(format!("No video with id {}", id.as_str())),
+        Ok(Some((_, video))) => video,
+    };
+    let waveform_location = match video.waveform_location {
+        None => return make_not_found(format!("Video {} does not have a waveform", id.as_str())),
+        Some(l) => l,
+    };
+    let levels = match download_manager::read_waveform(&waveform_location).await {
+        Err(e) => return make_internal_error(format!("Failed to read waveform: {}", e)),
+        Ok(levels) => levels,
+    };
+
+    match download_manager::pick_waveform_level(levels, query.samples_per_pixel) {
+        None => make_not_found(format!("Video {} does not have a waveform", id.as_str())),
+        Some(waveform) => make_json_response(&waveform),
+    }
+}
+
+pub fn make_json_response<T: Serialize>(value: &T) -> HttpResponse {
+    match serde_json::to_string(value) {
+        Err(e) => make_internal_error(format!("Failed to serialize output: {}", e)),
+        Ok(s) => HttpResponse::Ok().body(s).into(),
+    }
+}
+
+pub async fn get_video_annotations(id: web::Path<String>) -> HttpResponse {
+    match download_manager::find_downloaded_video(id.as_str()) {
+        Err(e) => make_internal_error(format!("Failed to find video: {}", e)),
+        Ok(None) => make_not_found(format!("No video with id {}", id.as_str())),
+        Ok(Some((_, video))) => make_json_response(&video.annotations),
+    }
+}
+
+pub async fn put_video_annotations(
+    id: web::Path<String>,
+    item: web::Json<Annotations>,
+) -> HttpResponse {
+    match download_manager::set_video_annotations(id.as_str(), item.0) {
+        Err(e) => make_library_error_response(e, "Failed to set annotations"),
+        Ok(None) => make_not_found(format!("No video with id {}", id.as_str())),
+        Ok(Some(annotations)) => make_json_response(&annotations),
+    }
+}
+
+pub async fn delete_video_annotations(id: web::Path<String>) -> HttpResponse {
+    match download_manager::set_video_annotations(id.as_str(), Annotations::default()) {
+        Err(e) => make_library_error_response(e, "Failed to delete annotations"),
+        Ok(None) => make_not_found(format!("No video with id {}", id.as_str())),
+        Ok(Some(_)) => HttpResponse::NoContent().finish(),
+    }
+}
+
+pub async fn get_clip_annotations(id: web::Path<String>) -> HttpResponse {
+    match download_manager::find_clip(id.as_str()) {
+        Err(e) => make_internal_error(format!("Failed to find clip: {}", e)),
+        Ok(None) => make_not_found(format!("No clip with id {}", id.as_str())),
+        Ok(Some((_, clip))) => make_json_response(&clip.annotations),
+    }
+}
+
+pub async fn put_clip_annotations(
+    id: web::Path<String>,
+    item: web::Json<Annotations>,
+) -> HttpResponse {
+    match download_manager::set_clip_annotations(id.as_str(), item.0) {
+        Err(e) => make_library_error_response(e, "Failed to set annotations"),
+        Ok(None) => make_not_found(format!("No clip with id {}", id.as_str())),
+        Ok(Some(annotations)) => make_json_response(&annotations),
+    }
+}
+
+pub async fn delete_clip_annotations(id: web::Path<String>) -> HttpResponse {
+    match download_manager::set_clip_annotations(id.as_str(), Annotations::default()) {
+        Err(e) => make_library_error_response(e, "Failed to delete annotations"),
+        Ok(None) => make_not_found(format!("No clip with id {}", id.as_str())),
+        Ok(Some(_)) => HttpResponse::NoContent().finish(),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClipResponse {
+    pub id: Option<String>,
+    pub video_data: Option<String>,
+    pub thumbnail_data: Option<String>,
+    pub animation_data: Option<String>,
+    pub source_url: String,
+    pub source_video_id: Option<String>,
+    pub start: Option<f64>,
+    pub duration: Option<f64>,
+    pub created_at: Option<u64>,
+    pub annotations: Annotations,
+}
+
+pub async fn list_clips(query: web::Query<ClipQuery>) -> HttpResponse {
+    let clip_page = match download_manager::query_clips(&query) {
+        Err(e) => return make_library_error_response(e, "Failed to list clips"),
+        Ok(page) => page,
+    };
+
+    let items = clip_page.items.into_iter().map(|entry| ClipResponse {
+        id: entry.clip.id(),
+        video_data: img_path(&entry.clip.location),
+        thumbnail_data: entry.clip.thumbnail_location.as_ref().and_then(img_path),
+        animation_data: entry.clip.animation_location.as_ref().and_then(img_path),
+        source_url: entry.url,
+        source_video_id: entry.video_id,
+        start: entry.clip.start,
+        duration: entry.clip.duration,
+        created_at: entry.clip.created_at,
+        annotations: entry.clip.annotations,
+    }).collect();
+
+    make_page_response(Page {
+        items,
+        next_cursor: clip_page.next_cursor,
+    })
+}
+
+pub async fn list_collections() -> HttpResponse {
+    match download_manager::list_collections() {
+        Err(e) => make_internal_error(format!("Failed to list collections: {}", e)),
+        Ok(collections) => make_json_response(&collections),
+    }
+}
+
+/// imports a media file into the library. either send json with a path
+/// on the server, or a multipart/form-data upload with the file in a
+/// field that has a filename, and optionally a title field
+pub async fn import_video(req: HttpRequest, payload: web::Payload) -> HttpResponse {
+    let is_multipart = req.headers().get(CONTENT_TYPE)
+        .and_then(|c| c.to_str().ok())
+        .map_or(false, |c| c.starts_with("multipart/form-data"));
+    let res = if is_multipart {
+        import_upload(Multipart::new(req.headers(), payload)).await
+    } else {
+        import_server_file(payload).await
+    };
+    match res {
+        Err(e) => make_library_error_response(e, "Failed to import"),
+        Ok((video_key, video)) => make_json_response(&make_source_video(video_key, video)),
+    }
+}
+
+pub async fn import_server_file(mut payload: web::Payload) -> Result<(String, DownloadedVideo), LibraryError> {
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|e| format!("Failed to read request: {}", e))?;
+        if body.len() + chunk.len() > MAX_IMPORT_JSON_BYTES {
+            return Err(LibraryError::Invalid("Request body is too large".into()));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let import_request: ImportRequest = serde_json::from_slice(&body).map_err(
+        |e| format!("Invalid import request: {}", e))?;
+    download_manager::import_server_file(import_request).await
+}
+
+pub async fn import_upload(multipart: Multipart) -> Result<(String, DownloadedVideo), LibraryError> {
+    let config = download_manager::get_config()?;
+    // dont accept the body if it cant fit anyway
+    download_manager::ensure_disk_limits(&config.download_dir, None).await?;
+    let upload_path = download_manager::new_upload_path(&config.download_dir);
+    let res = match read_upload_fields(multipart, &upload_path, config.max_upload_bytes).await {
+        Err(e) => Err(e),
+        Ok((extension, title)) => download_manager::import_media_file(
+            &upload_path,
+            &extension,
+            title,
+            ImportMode::Move,
+        ).await,
+    };
+    // whatever failed, the upload is not needed anymore
+    if res.is_err() {
+        let _ = tokio::fs::remove_file(&upload_path).await;
+    }
+    res
+}
+
+/// writes the file field to the upload_path. returns the
+/// extension of the file, and the title to import it with
+pub async fn read_upload_fields(
+    mut multipart: Multipart,
+    upload_path: &PathBuf,
+    max_upload_bytes: Option<u64>,
+) -> Result<(String, Option<String>), LibraryError> {
+    let mut title = None;
+    let mut uploaded = None;
+    while let Some(field) = multipart.next().await {
+        let mut field = field.map_err(|e| format!("Failed to read upload: {}", e))?;
+        let content_disposition = field.content_disposition();
+        let field_name = content_disposition.as_ref().and_then(|c| c.get_name()).map(|n| n.to_string());
+        let file_name = content_disposition.as_ref().and_then(|c| c.get_filename()).map(|n| n.to_string());
+
+        let file_name = match file_name {
+            Some(f) => f,
+            None => {
+                // a regular form field
+                let mut value = vec![];
+                while let Some(chunk) = field.next().await {
+                    let chunk = chunk.map_err(|e| format!("Failed to read upload: {}", e))?;
+                    if value.len() + chunk.len() > MAX_IMPORT_JSON_BYTES {
+                        return Err(LibraryError::Invalid("Form field is too large".into()));
+                    }
+                    value.extend_from_slice(&chunk);
+                }
+                if field_name.as_deref() == Some("title") {
+                    title = Some(String::from_utf8_lossy(&value).trim().to_string()).filter(|t| !t.is_empty());
                 }
+                continue;
             }
-        } else {
-            None
         };
-        let video_path = if let Some(os_path_str) = video_struct.location.file_name() {
-            if let Some(s) = os_path_str.to_str() {
-                Some(format!("/img/{}", s))
-            } else {
-                None
+        if uploaded.is_some() {
+            return Err(LibraryError::Invalid("Only one file can be uploaded at a time".into()));
+        }
+        let extension = download_manager::importable_extension(&file_name)?;
+        write_upload_field(&mut field, upload_path, max_upload_bytes).await?;
+        let file_title = PathBuf::from(&file_name).file_stem()
+            .and_then(|s| s.to_str())
+            .map(|s| s.to_string());
+        uploaded = Some((extension, file_title));
+    }
+
+    let (extension, file_title) = uploaded.ok_or_else(|| "Upload did not contain a file".to_string())?;
+    Ok((extension, title.or(file_title)))
+}
+
+pub async fn write_upload_field(
+    field: &mut actix_multipart::Field,
+    upload_path: &PathBuf,
+    max_upload_bytes: Option<u64>,
+) -> Result<(), String> {
+    let mut file = tokio::fs::File::create(upload_path).await.map_err(
+        |e| format!("Failed to create {:?}: {}", upload_path, e))?;
+    let mut written: u64 = 0;
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk.map_err(|e| format!("Failed to read upload: {}", e))?;
+        written += chunk.len() as u64;
+        if let Some(max_upload_bytes) = max_upload_bytes {
+            if written > max_upload_bytes {
+                return Err(format!("Upload is larger than {} bytes", max_upload_bytes));
             }
-        } else {
-            None
-        };
-        out_vec.push(SourceVideo {
-            url,
-            video_data: video_path,
-            thumbnail_data: thumbnail_path,
-            title: video_struct.title,
-            description: video_struct.description,
-        });
+        }
+        file.write_all(&chunk).await.map_err(
+            |e| format!("Failed to write {:?}: {}", upload_path, e))?;
+    }
+    file.flush().await.map_err(|e| format!("Failed to write {:?}: {}", upload_path, e))
+}
+
+pub fn make_library_error_response(error: LibraryError, context: &str) -> HttpResponse {
+    match error {
+        LibraryError::LockFailed => make_internal_error(format!("{}: {}", context, download_manager::FAILED_TO_ACQUIRE_LOCK)),
+        LibraryError::Invalid(e) => make_bad_request(format!("{}: {}", context, e)),
     }
+}
 
-    let json_string = match serde_json::to_string(&out_vec) {
-        Err(e) => return make_internal_error(format!("Failed to serialize output: {}", e)),
-        Ok(s) => s,
+pub fn make_upload_error_response(error: UploadError, id: &str) -> HttpResponse {
+    match error {
+        UploadError::NotFound => make_not_found(format!("No upload with id {}", id)),
+        UploadError::Conflict(status) => match serde_json::to_string(&status) {
+            Err(e) => make_internal_error(format!("Failed to serialize output: {}", e)),
+            Ok(s) => HttpResponse::Conflict().body(s),
+        },
+        UploadError::Invalid(e) => make_bad_request(e),
+    }
+}
+
+/// starts a resumable upload. send the chunks with PATCH /uploads/{id},
+/// and then POST /uploads/{id}/complete once all of them were sent
+pub async fn create_upload(item: web::Json<CreateUploadRequest>) -> HttpResponse {
+    match download_manager::create_upload(item.0).await {
+        Err(e) => make_bad_request(format!("Failed to create upload: {}", e)),
+        Ok(status) => make_json_response(&status),
+    }
+}
+
+/// the offset in the response is where the client should resume from
+pub async fn get_upload_status(id: web::Path<String>) -> HttpResponse {
+    match download_manager::get_upload_status(id.as_str()).await {
+        Err(e) => make_upload_error_response(e, id.as_str()),
+        Ok(status) => make_json_response(&status),
+    }
+}
+
+/// the body is the next chunk of the file, and the Upload-Offset header
+/// must be the offset it starts at. responds with 409 and the current
+/// status if the offset is not where the upload is at
+pub async fn append_upload_chunk(
+    req: HttpRequest,
+    id: web::Path<String>,
+    payload: web::Payload,
+) -> HttpResponse {
+    let offset = req.headers().get(UPLOAD_OFFSET_HEADER)
+        .and_then(|o| o.to_str().ok())
+        .and_then(|o| o.trim().parse::<u64>().ok());
+    let offset = match offset {
+        Some(o) => o,
+        None => return make_bad_request(format!("Missing or invalid {} header", UPLOAD_OFFSET_HEADER)),
     };
+    match download_manager::append_upload_chunk(id.to_string(), offset, payload).await {
+        Err(e) => make_upload_error_response(e, id.as_str()),
+        Ok(status) => make_json_response(&status),
+    }
+}
 
-    HttpResponse::Ok().body(json_string).into()
+pub async fn complete_upload(id: web::Path<String>) -> HttpResponse {
+    match download_manager::complete_upload(id.as_str()).await {
+        Err(e) => make_upload_error_response(e, id.as_str()),
+        Ok((video_key, video)) => make_json_response(&make_source_video(video_key, video)),
+    }
+}
+
+pub async fn cancel_upload(id: web::Path<String>) -> HttpResponse {
+    match download_manager::cancel_upload(id.as_str()).await {
+        Err(e) => make_upload_error_response(e, id.as_str()),
+        Ok(_) => HttpResponse::NoContent().finish(),
+    }
+}
+
+/// the body is optional, without it this only reports
+/// and does not change anything
+pub async fn check_library(item: Option<web::Json<FsckRequest>>) -> HttpResponse {
+    let fsck_request = item.map_or_else(FsckRequest::default, |i| i.0);
+    match download_manager::check_library(fsck_request).await {
+        Err(e) => make_internal_error(format!("Failed to check library: {}", e)),
+        Ok(report) => make_json_response(&report),
+    }
 }
 
 pub fn make_internal_error<S: AsRef<str>>(error_message: S) -> HttpResponse {
@@ -99,3 +637,15 @@ pub fn make_internal_error<S: AsRef<str>>(error_message: S) -> HttpResponse {
         error_message.as_ref().to_string()
     )
 }
+
+pub fn make_bad_request<S: AsRef<str>>(error_message: S) -> HttpResponse {
+    HttpResponse::BadRequest().body(
+        error_message.as_ref().to_string()
+    )
+}
+
+pub fn make_not_found<S: AsRef<str>>(error_message: S) -> HttpResponse {
+    HttpResponse::NotFound().body(
+        error_message.as_ref().to_string()
+    )
+}
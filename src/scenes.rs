@@ -0,0 +1,141 @@
+use std::path::Path;
+
+use super::create_command;
+use super::handle_child_exit;
+use super::set_analysis_failed;
+use super::set_scenes;
+use super::setup_child_and_reader;
+use super::DATAHOLDER;
+use super::media_probe::probe_media;
+
+/// how different a frame has to be from the previous
+/// one to count as a scene change, from 0 to 1
+pub const SCENE_CHANGE_THRESHOLD: f64 = 0.4;
+/// scene changes closer together than this (in seconds) are
+/// merged, ie: a flash or a fast cut is not a useful cut point
+pub const MIN_SCENE_GAP: f64 = 1.0;
+/// frames are scaled down to this width before they are compared.
+/// much faster, and small details dont matter for scene changes
+pub const SCENE_ANALYSIS_WIDTH: u32 = 320;
+
+/// showinfo prints a line like this for every frame that the select
+/// filter lets through:
+/// [Parsed_showinfo_2 @ 0x55] n:   0 pts:  12800 pts_time:1.0 pos: ...
+pub fn parse_showinfo_time<S: AsRef<str>>(line: S) -> Option<f64> {
+    let line = line.as_ref();
+    if !line.contains("showinfo") {
+        return None;
+    }
+    let time_str = "pts_time:";
+    let time_index = line.find(time_str)? + time_str.len();
+    line[time_index..].split_whitespace().next()?.parse::<f64>().ok()
+}
+
+/// sorts the scene changes and drops the ones that
+/// are within MIN_SCENE_GAP of the previous one
+pub fn merge_close_scenes(times: Vec<f64>) -> Vec<f64> {
+    let mut times = times;
+    times.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let mut merged: Vec<f64> = vec![];
+    for time in times {
+        let is_close = merged.last().map_or(time < MIN_SCENE_GAP, |last| time - last < MIN_SCENE_GAP);
+        if !is_close {
+            merged.push(time);
+        }
+    }
+    merged
+}
+
+pub const SCENES_ANALYSIS: &'static str = "scenes";
+
+/// runs ffmpeg scene detection over the whole video and returns
+/// the times (in seconds) where a new scene starts
+pub async fn detect_scenes(location: &Path) -> Result<Vec<f64>, String> {
+    let input = match location.to_str() {
+        Some(s) => s.to_string(),
+        None => return Err(format!("File path contains invalid characters: {:?}", location)),
+    };
+    let filters = format!(
+        "scale={}:-2,select='gt(scene,{})',showinfo",
+        SCENE_ANALYSIS_WIDTH, SCENE_CHANGE_THRESHOLD,
+    );
+    let exe_and_args = vec![
+        "ffmpeg",
+        // showinfo logs at the info level
+        "-loglevel", "info",
+        "-hide_banner",
+        "-nostats",
+        "-i", &input,
+        "-an",
+        "-vf", &filters,
+        "-f", "null",
+        "-",
+    ];
+    let cmd = create_command(&exe_and_args[..]);
+    // nothing is written to stdout, the output is null
+    let (child, _, mut stderr_reader) = setup_child_and_reader(cmd)?;
+
+    let mut times = vec![];
+    let mut last_lines = vec![];
+    while let Ok(Some(line)) = stderr_reader.next_line().await {
+        match parse_showinfo_time(&line) {
+            Some(time) => times.push(time),
+            None => {
+                // keep the last few lines in case ffmpeg fails
+                if last_lines.len() == 5 {
+                    last_lines.remove(0);
+                }
+                last_lines.push(line);
+            },
+        }
+    }
+    if let Err(e) = handle_child_exit(child.await) {
+        return Err(format!("Scene detection failed: {}: {}", e, last_lines.join("\n")));
+    }
+    Ok(merge_close_scenes(times))
+}
+
+/// finds the scene changes of the source video if that wasnt done yet.
+/// they are only suggestions for cut points, so a failure
+/// does not fail anything
+pub async fn analyze_scenes(video_key: &String) {
+    let location = match DATAHOLDER.lock() {
+        Err(_) => None,
+        Ok(guard) => guard.as_ref().get(video_key)
+            .filter(|video| video.scenes.is_none() && !video.analysis_failed(SCENES_ANALYSIS))
+            .map(|video| video.location.clone()),
+    };
+    let location = match location {
+        Some(l) => l,
+        None => return,
+    };
+    let res = match probe_media(&location).await {
+        Ok(info) if !info.has_video => Err("File does not have video".to_string()),
+        Ok(_) => detect_scenes(&location).await,
+        Err(e) => Err(e),
+    };
+    match res {
+        Ok(scenes) => set_scenes(video_key, scenes),
+        Err(e) => {
+            println!("Failed to detect scenes of {:?}: {}", location, e);
+            set_analysis_failed(video_key, SCENES_ANALYSIS);
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_scene_changes() {
+        let line = "[Parsed_showinfo_2 @ 0x5581] n:   3 pts:  81920 pts_time:6.4     pos:   123456 fmt:yuv420p";
+        assert_eq!(parse_showinfo_time(line), Some(6.4));
+        assert_eq!(parse_showinfo_time("[Parsed_showinfo_2 @ 0x5581] config in time_base: 1/12800"), None);
+        assert_eq!(parse_showinfo_time("frame=  100 fps=0.0 pts_time:1.0"), None);
+
+        let scenes = merge_close_scenes(vec![12.0, 0.2, 5.0, 5.5, 6.1, 12.9]);
+        assert_eq!(scenes, vec![5.0, 6.1, 12.0]);
+        assert!(merge_close_scenes(vec![]).is_empty());
+    }
+}
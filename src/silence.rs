@@ -0,0 +1,195 @@
+use serde::Serialize;
+
+use super::create_command;
+use super::cut_video_stage::ClipRange;
+
+/// audio quieter than this counts as silence
+pub const SILENCE_NOISE_DB: i32 = -50;
+/// in seconds. shorter pauses are left alone
+pub const SILENCE_MIN_DURATION: f64 = 0.5;
+/// in seconds. this much of the silence is kept on each side
+/// so the clip doesnt start right on the first syllable
+pub const SILENCE_PADDING: f64 = 0.1;
+
+/// a silent part of the clip range. the times are relative
+/// to the start of the range. end is None if the silence
+/// lasts until the end of the input
+#[derive(Clone, Debug, PartialEq)]
+pub struct Silence {
+    pub start: f64,
+    pub end: Option<f64>,
+}
+
+/// how much was trimmed off a clip range, stored
+/// in the progress vars of the job as "silence_trims"
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SilenceTrim {
+    pub original_start: Option<f64>,
+    pub original_duration: Option<f64>,
+    pub start: Option<f64>,
+    pub duration: Option<f64>,
+    /// seconds removed from the start and end
+    pub leading: f64,
+    pub trailing: f64,
+}
+
+fn parse_time_after(line: &str, prefix: &str) -> Option<f64> {
+    let index = line.find(prefix)? + prefix.len();
+    line[index..].split_whitespace().next()?.parse::<f64>().ok()
+}
+
+/// parses the lines silencedetect logs, ie:
+/// [silencedetect @ 0x55] silence_start: 0
+/// [silencedetect @ 0x55] silence_end: 1.5 | silence_duration: 1.5
+pub fn parse_silencedetect_output<S: AsRef<str>>(output: S) -> Vec<Silence> {
+    let mut silences: Vec<Silence> = vec![];
+    for line in output.as_ref().lines().filter(|l| l.contains("silencedetect")) {
+        if let Some(start) = parse_time_after(line, "silence_start:") {
+            silences.push(Silence { start: start.max(0.0), end: None });
+        } else if let Some(end) = parse_time_after(line, "silence_end:") {
+            if let Some(last) = silences.last_mut().filter(|s| s.end.is_none()) {
+                last.end = Some(end);
+            }
+        }
+    }
+    silences
+}
+
+fn round_millis(seconds: f64) -> f64 {
+    (seconds * 1000.0).round() / 1000.0
+}
+
+/// moves the start and end of the range inward past the silence at
+/// either end of it. range_length is the length of the range in seconds,
+/// if it is known. a range that is silent all the way through is kept as is
+pub fn trim_silence(range: &ClipRange, silences: &[Silence], range_length: Option<f64>) -> (ClipRange, SilenceTrim) {
+    // silencedetect times can be a few milliseconds off
+    let epsilon = 0.05;
+    let reaches_end = |s: &Silence| match (s.end, range_length) {
+        (None, _) => true,
+        (Some(end), Some(length)) => end >= length - epsilon,
+        (Some(_), None) => false,
+    };
+
+    let mut leading = match silences.first().filter(|s| s.start <= epsilon) {
+        Some(s) if !reaches_end(s) => (s.end.unwrap_or(0.0) - SILENCE_PADDING).max(0.0),
+        _ => 0.0,
+    };
+    // relative to the start of the range, None is the end of the input
+    let mut end = range.duration;
+    let mut trailing = 0.0;
+    if let Some(s) = silences.last().filter(|s| s.start > epsilon && reaches_end(s)) {
+        let new_end = s.start + SILENCE_PADDING;
+        end = Some(range_length.map_or(new_end, |length| new_end.min(length)));
+        trailing = range_length.map_or(0.0, |length| (length - new_end).max(0.0));
+    }
+    if end.map_or(false, |e| e <= leading) {
+        leading = 0.0;
+        trailing = 0.0;
+        end = range.duration;
+    }
+
+    let trimmed = ClipRange {
+        start: match range.start {
+            start if leading == 0.0 => start,
+            start => Some(round_millis(start.unwrap_or(0.0) + leading)),
+        },
+        duration: end.map(|e| round_millis(e - leading)),
+    };
+    let trim = SilenceTrim {
+        original_start: range.start,
+        original_duration: range.duration,
+        start: trimmed.start,
+        duration: trimmed.duration,
+        leading: round_millis(leading),
+        trailing: round_millis(trailing),
+    };
+    (trimmed, trim)
+}
+
+/// runs ffmpeg silencedetect over the clip range of the input
+pub async fn detect_silences(input_string: &String, range: &ClipRange) -> Result<Vec<Silence>, String> {
+    let mut exe_and_args: Vec<String> = vec![
+        "ffmpeg".into(),
+        // silencedetect logs at the info level
+        "-loglevel".into(), "info".into(),
+        "-hide_banner".into(),
+        "-nostats".into(),
+    ];
+    if let Some(start) = range.start {
+        exe_and_args.push("-ss".into());
+        exe_and_args.push(start.to_string());
+    }
+    exe_and_args.push("-i".into());
+    exe_and_args.push(input_string.clone());
+    if let Some(duration) = range.duration {
+        exe_and_args.push("-t".into());
+        exe_and_args.push(duration.to_string());
+    }
+    exe_and_args.push("-vn".into());
+    exe_and_args.push("-af".into());
+    exe_and_args.push(format!("silencedetect=noise={}dB:d={}", SILENCE_NOISE_DB, SILENCE_MIN_DURATION));
+    exe_and_args.push("-f".into());
+    exe_and_args.push("null".into());
+    exe_and_args.push("-".into());
+    let mut cmd = create_command(&exe_and_args[..]);
+    let output = cmd.output().await.map_err(
+        |e| format!("Failed to run ffmpeg: {}", e))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        let last_line = stderr.lines().last().unwrap_or_default();
+        return Err(format!("Silence detection failed: {}", last_line));
+    }
+    Ok(parse_silencedetect_output(stderr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_silencedetect_output() {
+        let output = "\
+[silencedetect @ 0x5581] silence_start: -0.0123
+[silencedetect @ 0x5581] silence_end: 1.5 | silence_duration: 1.51
+size=N/A time=00:00:10.00 bitrate=N/A
+[silencedetect @ 0x5581] silence_start: 8.25
+";
+        assert_eq!(parse_silencedetect_output(output), vec![
+            Silence { start: 0.0, end: Some(1.5) },
+            Silence { start: 8.25, end: None },
+        ]);
+    }
+
+    #[test]
+    fn trims_leading_and_trailing_silence() {
+        let range = ClipRange { start: Some(10.0), duration: Some(10.0) };
+        let silences = vec![
+            Silence { start: 0.0, end: Some(1.5) },
+            Silence { start: 4.0, end: Some(5.0) },
+            Silence { start: 8.0, end: Some(10.0) },
+        ];
+        let (trimmed, trim) = trim_silence(&range, &silences, Some(10.0));
+        assert_eq!(trimmed, ClipRange { start: Some(11.4), duration: Some(6.7) });
+        assert_eq!((trim.leading, trim.trailing), (1.4, 1.9));
+
+        // silence that lasts until the end of the video
+        let range = ClipRange { start: None, duration: None };
+        let silences = vec![Silence { start: 30.0, end: None }];
+        let (trimmed, trim) = trim_silence(&range, &silences, Some(40.0));
+        assert_eq!(trimmed, ClipRange { start: None, duration: Some(30.1) });
+        assert_eq!(trim.leading, 0.0);
+
+        // nothing to trim
+        let range = ClipRange { start: Some(5.0), duration: Some(10.0) };
+        let silences = vec![Silence { start: 4.0, end: Some(5.0) }];
+        let (trimmed, _) = trim_silence(&range, &silences, Some(10.0));
+        assert_eq!(trimmed, ClipRange { start: Some(5.0), duration: Some(10.0) });
+
+        // all silent
+        let silences = vec![Silence { start: 0.0, end: None }];
+        let (trimmed, trim) = trim_silence(&range, &silences, Some(10.0));
+        assert_eq!(trimmed, ClipRange { start: Some(5.0), duration: Some(10.0) });
+        assert_eq!((trim.leading, trim.trailing), (0.0, 0.0));
+    }
+}
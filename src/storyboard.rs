@@ -0,0 +1,193 @@
+use std::path::Path;
+use std::path::PathBuf;
+
+use super::create_command;
+use super::set_analysis_failed;
+use super::set_storyboard;
+use super::Storyboard;
+use super::DATAHOLDER;
+use super::media_probe::probe_media;
+use super::subtitles::format_srt_timestamp;
+
+pub const STORYBOARD_TILE_WIDTH: u32 = 160;
+pub const STORYBOARD_COLUMNS: u32 = 10;
+/// long videos get a longer interval instead of more tiles,
+/// so the sprite sheet stays a reasonable size
+pub const STORYBOARD_MAX_TILES: u32 = 200;
+/// in seconds
+pub const STORYBOARD_MIN_INTERVAL: f64 = 2.0;
+
+/// the sprite sheet is written next to the source video,
+/// and the vtt track next to the sprite sheet
+pub fn storyboard_paths(location: &Path) -> (PathBuf, PathBuf) {
+    (location.with_extension("storyboard.jpg"), location.with_extension("storyboard.vtt"))
+}
+
+/// figures out how many tiles the storyboard has and how big they are.
+/// the tiles keep the aspect ratio of the video
+pub fn storyboard_layout(duration: f64, width: u32, height: u32) -> Result<Storyboard, String> {
+    if duration <= 0.0 || width == 0 || height == 0 {
+        return Err("Video has no duration or size to make a storyboard from".into());
+    }
+    let interval = (duration / STORYBOARD_MAX_TILES as f64).ceil().max(STORYBOARD_MIN_INTERVAL);
+    let tiles = (duration / interval).ceil() as u32;
+    let tile_height = (STORYBOARD_TILE_WIDTH as f64 * height as f64 / width as f64 / 2.0).round() as u32 * 2;
+    Ok(Storyboard {
+        interval,
+        duration,
+        tiles,
+        tile_width: STORYBOARD_TILE_WIDTH,
+        tile_height: tile_height.max(2),
+        columns: STORYBOARD_COLUMNS.min(tiles),
+        rows: (tiles + STORYBOARD_COLUMNS - 1) / STORYBOARD_COLUMNS,
+        ..Default::default()
+    })
+}
+
+fn format_vtt_timestamp(seconds: f64) -> String {
+    format_srt_timestamp((seconds * 1000.0).round() as u64).replace(',', ".")
+}
+
+/// a WebVTT thumbnails track, with one cue per tile that points
+/// at the part of the sprite sheet to show for that time range
+pub fn write_storyboard_vtt<S: AsRef<str>>(storyboard: &Storyboard, image_name: S) -> String {
+    let mut vtt = String::from("WEBVTT\n");
+    for i in 0..storyboard.tiles {
+        let start = i as f64 * storyboard.interval;
+        let end = (start + storyboard.interval).min(storyboard.duration);
+        let x = (i % storyboard.columns) * storyboard.tile_width;
+        let y = (i / storyboard.columns) * storyboard.tile_height;
+        vtt.push_str(&format!(
+            "\n{} --> {}\n{}#xywh={},{},{},{}\n",
+            format_vtt_timestamp(start),
+            format_vtt_timestamp(end),
+            image_name.as_ref(),
+            x, y, storyboard.tile_width, storyboard.tile_height,
+        ));
+    }
+    vtt
+}
+
+/// runs ffmpeg to take a frame every interval seconds and tile
+/// them into a single image. only keyframes are decoded, which is
+/// much faster than decoding the whole video and close enough
+/// for a scrubbing preview
+pub async fn run_ffmpeg_storyboard(input: &Path, output: &Path, storyboard: &Storyboard) -> Result<(), String> {
+    let (input, output) = match (input.to_str(), output.to_str()) {
+        (Some(i), Some(o)) => (i.to_string(), o.to_string()),
+        _ => return Err("File path contains invalid characters".into()),
+    };
+    let filters = format!(
+        "fps=1/{},scale={}:{},tile={}x{}",
+        storyboard.interval,
+        storyboard.tile_width, storyboard.tile_height,
+        storyboard.columns, storyboard.rows,
+    );
+    let exe_and_args = vec![
+        "ffmpeg",
+        "-loglevel", "error",
+        "-hide_banner",
+        "-skip_frame", "nokey",
+        "-i", &input,
+        "-an",
+        "-vf", &filters,
+        "-frames:v", "1",
+        "-q:v", "5",
+        "-n",
+        &output,
+    ];
+    let mut cmd = create_command(&exe_and_args[..]);
+    let result = cmd.output().await.map_err(
+        |e| format!("Failed to run ffmpeg: {}", e))?;
+    if !result.status.success() {
+        let _ = tokio::fs::remove_file(&output).await;
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        return Err(format!("Failed to generate storyboard: {}", stderr.trim()));
+    }
+    Ok(())
+}
+
+pub async fn make_storyboard(location: &Path) -> Result<Storyboard, String> {
+    let media_info = probe_media(location).await?;
+    if !media_info.has_video {
+        return Err("Cannot make a storyboard of a file without video".into());
+    }
+    let mut storyboard = storyboard_layout(
+        media_info.duration.unwrap_or(0.0),
+        media_info.width.unwrap_or(0),
+        media_info.height.unwrap_or(0),
+    )?;
+    let (image_location, vtt_location) = storyboard_paths(location);
+    run_ffmpeg_storyboard(location, &image_location, &storyboard).await?;
+    let image_name = image_location.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    let vtt = write_storyboard_vtt(&storyboard, image_name);
+    if let Err(e) = tokio::fs::write(&vtt_location, vtt).await {
+        let _ = tokio::fs::remove_file(&image_location).await;
+        return Err(format!("Failed to write {:?}: {}", vtt_location, e));
+    }
+    storyboard.location = image_location;
+    storyboard.vtt_location = vtt_location;
+    Ok(storyboard)
+}
+
+pub const STORYBOARD_ANALYSIS: &'static str = "storyboard";
+
+/// makes a storyboard for the source video if it doesnt have one yet.
+/// like thumbnails, a storyboard that fails to generate does not
+/// fail anything, the player just wont have hover previews
+pub async fn generate_storyboard(video_key: &String) {
+    let location = match DATAHOLDER.lock() {
+        Err(_) => None,
+        Ok(guard) => guard.as_ref().get(video_key)
+            .filter(|video| video.storyboard.is_none() && !video.analysis_failed(STORYBOARD_ANALYSIS))
+            .map(|video| video.location.clone()),
+    };
+    if let Some(location) = location {
+        match make_storyboard(&location).await {
+            Ok(storyboard) => set_storyboard(video_key, storyboard),
+            Err(e) => {
+                println!("Failed to generate storyboard for {:?}: {}", location, e);
+                set_analysis_failed(video_key, STORYBOARD_ANALYSIS);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lays_out_storyboard_tiles() {
+        let storyboard = storyboard_layout(25.0, 1920, 1080).unwrap();
+        assert_eq!(storyboard.interval, 2.0);
+        assert_eq!(storyboard.tiles, 13);
+        assert_eq!((storyboard.tile_width, storyboard.tile_height), (160, 90));
+        assert_eq!((storyboard.columns, storyboard.rows), (10, 2));
+
+        let long = storyboard_layout(2.0 * 60.0 * 60.0, 640, 480).unwrap();
+        assert_eq!(long.interval, 36.0);
+        assert_eq!(long.tiles, STORYBOARD_MAX_TILES);
+        assert_eq!(long.tile_height, 120);
+
+        let short = storyboard_layout(3.0, 1080, 1920).unwrap();
+        assert_eq!((short.columns, short.rows, short.tile_height), (2, 1, 284));
+
+        assert!(storyboard_layout(0.0, 1920, 1080).is_err());
+        assert!(storyboard_layout(10.0, 0, 0).is_err());
+
+        let (image, vtt) = storyboard_paths(Path::new("dl/abc.mp4"));
+        assert_eq!(image, PathBuf::from("dl/abc.storyboard.jpg"));
+        assert_eq!(vtt, PathBuf::from("dl/abc.storyboard.vtt"));
+    }
+
+    #[test]
+    fn writes_storyboard_vtt() {
+        let storyboard = storyboard_layout(25.0, 1920, 1080).unwrap();
+        let vtt = write_storyboard_vtt(&storyboard, "abc.storyboard.jpg");
+        assert!(vtt.starts_with("WEBVTT\n\n00:00:00.000 --> 00:00:02.000\nabc.storyboard.jpg#xywh=0,0,160,90\n"));
+        assert!(vtt.contains("\n00:00:20.000 --> 00:00:22.000\nabc.storyboard.jpg#xywh=0,90,160,90\n"));
+        assert!(vtt.ends_with("\n00:00:24.000 --> 00:00:25.000\nabc.storyboard.jpg#xywh=320,90,160,90\n"));
+        assert_eq!(vtt.matches("#xywh").count(), 13);
+    }
+}
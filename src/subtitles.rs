@@ -0,0 +1,220 @@
+use std::path::PathBuf;
+
+use super::SubtitleTrack;
+
+pub const VALID_SUBTITLE_EXTENSIONS: [&str; 2] = ["srt", "vtt"];
+
+#[derive(Debug, PartialEq)]
+pub struct SubtitleCue {
+    /// in milliseconds
+    pub start: u64,
+    pub end: u64,
+    pub text: String,
+}
+
+/// languages get passed to youtube-dl and ffmpeg, and end
+/// up in file names, so only allow things like "en" or "pt-BR"
+pub fn validate_subtitle_language<S: AsRef<str>>(language: S) -> Result<(), String> {
+    let language = language.as_ref();
+    let is_valid_char = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if language.is_empty() || language.len() > 16 || language.starts_with('-') || !language.chars().all(is_valid_char) {
+        return Err(format!("Invalid subtitle language: {}", language));
+    }
+    Ok(())
+}
+
+/// youtube-dl writes subtitles as <key>.<language>.<ext>
+pub fn get_subtitle_tracks_from_vec(paths: Vec<PathBuf>) -> Vec<SubtitleTrack> {
+    let mut out_vec = vec![];
+    for path in paths {
+        let is_subtitle = match path.extension() {
+            Some(os_ext) => VALID_SUBTITLE_EXTENSIONS.iter().any(|e| *e == os_ext),
+            None => false,
+        };
+        if !is_subtitle {
+            continue;
+        }
+        let language = path.file_stem()
+            .map(PathBuf::from)
+            .and_then(|stem| stem.extension().and_then(|l| l.to_str()).map(|l| l.to_string()));
+        if let Some(language) = language {
+            out_vec.push(SubtitleTrack { language, location: path });
+        }
+    }
+    out_vec
+}
+
+/// picks the first track that matches one of the languages in the
+/// order they were requested. if no languages were requested, picks
+/// the first track
+pub fn pick_subtitle_track<'a>(
+    tracks: &'a [SubtitleTrack],
+    languages: &[String],
+) -> Option<&'a SubtitleTrack> {
+    if languages.is_empty() {
+        return tracks.first();
+    }
+    languages.iter().find_map(|lang| tracks.iter().find(|t| &t.language == lang))
+}
+
+/// parses HH:MM:SS,mmm (or HH:MM:SS.mmm) into milliseconds
+pub fn parse_srt_timestamp<S: AsRef<str>>(timestamp: S) -> Option<u64> {
+    let timestamp = timestamp.as_ref().trim().replace(',', ".");
+    let mut parts = timestamp.split(':');
+    let hours = parts.next()?.parse::<u64>().ok()?;
+    let minutes = parts.next()?.parse::<u64>().ok()?;
+    let seconds = parts.next()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    let seconds = seconds.parse::<f64>().ok()?;
+    Some((hours * 3600 + minutes * 60) * 1000 + (seconds * 1000.0).round() as u64)
+}
+
+pub fn format_srt_timestamp(millis: u64) -> String {
+    let hours = millis / 3_600_000;
+    let minutes = (millis / 60_000) % 60;
+    let seconds = (millis / 1000) % 60;
+    let millis = millis % 1000;
+    format!("{:02}:{:02}:{:02},{:03}", hours, minutes, seconds, millis)
+}
+
+pub fn parse_srt<S: AsRef<str>>(contents: S) -> Vec<SubtitleCue> {
+    let contents = contents.as_ref().replace("\r\n", "\n");
+    let mut cues = vec![];
+    for block in contents.split("\n\n") {
+        let mut lines = block.lines().skip_while(|l| !l.contains("-->"));
+        let timing_line = match lines.next() {
+            Some(l) => l,
+            None => continue,
+        };
+        let mut times = timing_line.split("-->");
+        let start = times.next().and_then(parse_srt_timestamp);
+        // there can be positioning info after the end time
+        let end = times.next()
+            .and_then(|t| t.trim().split_whitespace().next())
+            .and_then(parse_srt_timestamp);
+        if let (Some(start), Some(end)) = (start, end) {
+            let text = lines.collect::<Vec<&str>>().join("\n");
+            cues.push(SubtitleCue { start, end, text });
+        }
+    }
+    cues
+}
+
+pub fn write_srt(cues: &[SubtitleCue]) -> String {
+    let mut out = String::new();
+    for (i, cue) in cues.iter().enumerate() {
+        out.push_str(&format!(
+            "{}\n{} --> {}\n{}\n\n",
+            i + 1,
+            format_srt_timestamp(cue.start),
+            format_srt_timestamp(cue.end),
+            cue.text
+        ));
+    }
+    out
+}
+
+/// keeps only the cues that are visible within the clip range,
+/// trims them to fit in the range, and shifts them so that
+/// the start of the clip is time 0
+pub fn shift_cues(cues: Vec<SubtitleCue>, start_millis: u64, duration_millis: Option<u64>) -> Vec<SubtitleCue> {
+    let end_millis = duration_millis.map(|d| start_millis + d);
+    cues.into_iter().filter_map(|cue| {
+        if cue.end <= start_millis {
+            return None;
+        }
+        if let Some(end_millis) = end_millis {
+            if cue.start >= end_millis {
+                return None;
+            }
+        }
+        let start = cue.start.max(start_millis) - start_millis;
+        let end = match end_millis {
+            Some(end_millis) => cue.end.min(end_millis),
+            None => cue.end,
+        } - start_millis;
+        Some(SubtitleCue { start, end, text: cue.text })
+    }).collect()
+}
+
+/// escapes a path so it can be used as an option
+/// value inside of an ffmpeg filtergraph, ie: subtitles=<path>.
+/// ffmpeg needs two levels of escaping here, one for the
+/// option value, and one for the filtergraph itself
+pub fn escape_filter_path<S: AsRef<str>>(path: S) -> String {
+    let mut level_one = String::new();
+    for c in path.as_ref().chars() {
+        if c == '\\' || c == '\'' || c == ':' {
+            level_one.push('\\');
+        }
+        level_one.push(c);
+    }
+    let mut level_two = String::new();
+    for c in level_one.chars() {
+        if "\\',;[]".contains(c) {
+            level_two.push('\\');
+        }
+        level_two.push(c);
+    }
+    level_two
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_SRT: &str = "1\n00:00:01,000 --> 00:00:04,000\nhello\n\n2\n00:00:05,500 --> 00:00:08,000 align:start\nworld\ntwo lines\n\n3\n00:01:00,000 --> 00:01:02,000\nlater\n";
+
+    #[test]
+    fn can_parse_srt() {
+        let cues = parse_srt(TEST_SRT);
+        assert_eq!(cues.len(), 3);
+        assert_eq!(cues[0], SubtitleCue { start: 1000, end: 4000, text: "hello".into() });
+        assert_eq!(cues[1].end, 8000);
+        assert_eq!(cues[1].text, "world\ntwo lines");
+        assert_eq!(format_srt_timestamp(3_723_004), "01:02:03,004");
+        assert_eq!(parse_srt_timestamp("01:02:03,004"), Some(3_723_004));
+    }
+
+    #[test]
+    fn shifting_cues_trims_to_clip_range() {
+        let cues = shift_cues(parse_srt(TEST_SRT), 2000, Some(5000));
+        assert_eq!(cues.len(), 2);
+        assert_eq!(cues[0], SubtitleCue { start: 0, end: 2000, text: "hello".into() });
+        assert_eq!((cues[1].start, cues[1].end), (3500, 5000));
+
+        let cues = shift_cues(parse_srt(TEST_SRT), 30_000, None);
+        assert_eq!(cues.len(), 1);
+        assert_eq!((cues[0].start, cues[0].end), (30_000, 32_000));
+
+        let written = write_srt(&cues);
+        assert!(written.starts_with("1\n00:00:30,000 --> 00:00:32,000\nlater\n"));
+    }
+
+    #[test]
+    fn finds_subtitle_tracks() {
+        let paths = vec![
+            "abc.mp4".into(), "abc.en.srt".into(), "abc.pt-BR.srt".into(), "abc.info.json".into(),
+        ];
+        let tracks = get_subtitle_tracks_from_vec(paths);
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0].language, "en");
+        assert_eq!(tracks[1].language, "pt-BR");
+        let picked = pick_subtitle_track(&tracks, &["de".into(), "pt-BR".into()]).unwrap();
+        assert_eq!(picked.language, "pt-BR");
+        assert_eq!(pick_subtitle_track(&tracks, &[]).unwrap().language, "en");
+        assert!(pick_subtitle_track(&tracks, &["de".into()]).is_none());
+    }
+
+    #[test]
+    fn validates_languages_and_escapes_paths() {
+        assert!(validate_subtitle_language("en").is_ok());
+        assert!(validate_subtitle_language("pt-BR").is_ok());
+        assert!(validate_subtitle_language("en,de").is_err());
+        assert!(validate_subtitle_language("--exec").is_err());
+        assert_eq!(escape_filter_path("/a/b.srt"), "/a/b.srt");
+        assert_eq!(escape_filter_path("c:/it's"), "c\\\\:/it\\\\\\'s");
+    }
+}
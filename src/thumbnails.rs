@@ -0,0 +1,207 @@
+use std::path::Path;
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+
+use super::create_command;
+use super::return_something_from_progress_holder;
+use super::set_thumbnails;
+use super::Clip;
+use super::ProgressVars;
+use super::TaskResult;
+use super::DATAHOLDER;
+use super::PROGHOLDER;
+use super::media_probe::probe_media;
+use super::scenes::SCENE_CHANGE_THRESHOLD;
+
+/// where in the video the thumbnail is taken from, as a
+/// fraction of its duration. the first frames are often black
+pub const THUMBNAIL_POSITION: f64 = 0.1;
+pub const THUMBNAIL_MAX_WIDTH: u32 = 640;
+/// how far past the thumbnail time we look for a scene change
+/// before settling for the frame at the thumbnail time
+pub const SCENE_SEARCH_SECONDS: f64 = 60.0;
+
+/// how the frame for a thumbnail is picked
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThumbnailSelection {
+    /// the frame at this many seconds into the video
+    At(f64),
+    /// the first scene change after the thumbnail time.
+    /// usually a better frame than a fixed time, which
+    /// can land on a fade or in the middle of a transition
+    Scene,
+}
+
+impl Default for ThumbnailSelection {
+    fn default() -> Self {
+        ThumbnailSelection::Scene
+    }
+}
+
+pub fn thumbnail_time(duration: Option<f64>) -> f64 {
+    duration.map_or(0.0, |d| (d * THUMBNAIL_POSITION).max(0.0))
+}
+
+/// clip thumbnails are written next to the clip
+pub fn thumbnail_path(location: &Path) -> PathBuf {
+    location.with_extension("jpg")
+}
+
+/// the -vf filters that pick the frame and scale it down
+/// to THUMBNAIL_MAX_WIDTH if the video is wider than that
+pub fn thumbnail_filters(selection: ThumbnailSelection) -> String {
+    let scale = format!("scale='min({},iw)':-2", THUMBNAIL_MAX_WIDTH);
+    match selection {
+        ThumbnailSelection::At(_) => scale,
+        ThumbnailSelection::Scene => format!("select='gt(scene,{})',{}", SCENE_CHANGE_THRESHOLD, scale),
+    }
+}
+
+/// uses ffmpeg to write a single frame of the input at
+/// at_seconds into the output image
+pub async fn generate_thumbnail<P: AsRef<Path>>(
+    input: P,
+    output: P,
+    at_seconds: f64,
+) -> Result<(), String> {
+    run_ffmpeg_thumbnail(input.as_ref(), output.as_ref(), at_seconds, ThumbnailSelection::At(at_seconds)).await
+}
+
+/// writes a thumbnail picked by the selection. if there is no scene
+/// change close enough to the thumbnail time, the frame at the
+/// thumbnail time is used instead
+pub async fn generate_selected_thumbnail(
+    input: &Path,
+    output: &Path,
+    duration: Option<f64>,
+    selection: ThumbnailSelection,
+) -> Result<(), String> {
+    match selection {
+        ThumbnailSelection::At(at_seconds) => generate_thumbnail(input, output, at_seconds).await,
+        ThumbnailSelection::Scene => {
+            let at_seconds = thumbnail_time(duration);
+            run_ffmpeg_thumbnail(input, output, at_seconds, selection).await?;
+            // ffmpeg succeeds without writing anything
+            // if the select filter never matched
+            if !output.exists() {
+                generate_thumbnail(input, output, at_seconds).await?;
+            }
+            Ok(())
+        },
+    }
+}
+
+async fn run_ffmpeg_thumbnail(
+    input: &Path,
+    output: &Path,
+    at_seconds: f64,
+    selection: ThumbnailSelection,
+) -> Result<(), String> {
+    let (input, output) = match (input.to_str(), output.to_str()) {
+        (Some(i), Some(o)) => (i.to_string(), o.to_string()),
+        _ => return Err("File path contains invalid characters".into()),
+    };
+    let seek = format!("{:.3}", at_seconds);
+    let search_duration = format!("{:.3}", SCENE_SEARCH_SECONDS);
+    let filters = thumbnail_filters(selection);
+    let mut exe_and_args = vec![
+        "ffmpeg",
+        "-loglevel", "error",
+        "-hide_banner",
+        "-ss", &seek,
+    ];
+    if selection == ThumbnailSelection::Scene {
+        exe_and_args.extend(&["-t", &search_duration]);
+    }
+    exe_and_args.extend(&[
+        "-i", &input,
+        "-frames:v", "1",
+        "-vf", &filters,
+        "-vsync", "vfr",
+        "-n",
+        &output,
+    ]);
+    let mut cmd = create_command(&exe_and_args[..]);
+    let result = cmd.output().await.map_err(
+        |e| format!("Failed to run ffmpeg: {}", e))?;
+    if !result.status.success() {
+        let _ = tokio::fs::remove_file(&output).await;
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        return Err(format!("Failed to generate thumbnail: {}", stderr.trim()));
+    }
+    Ok(())
+}
+
+async fn media_duration(location: &Path, known_duration: Option<f64>) -> Option<f64> {
+    match known_duration {
+        Some(d) => Some(d),
+        None => probe_media(location).await.ok().and_then(|info| info.duration),
+    }
+}
+
+/// generates a thumbnail for every clip the cut_video stage made, and for
+/// the source video if it does not have one yet, ie: youtube-dl did not
+/// find one. a thumbnail that fails to generate does not fail the job,
+/// the clip just wont have one
+pub async fn generate_thumbnails(key: String, clip_selection: ThumbnailSelection) -> TaskResult {
+    let video_key: Option<String> = return_something_from_progress_holder(&key, &PROGHOLDER, |me| {
+        me.clone_var::<String>("video_key")
+    });
+    let mut clips: Vec<Clip> = return_something_from_progress_holder(&key, &PROGHOLDER, |me| {
+        me.clone_var::<Vec<Clip>>("clips")
+    }).unwrap_or_default();
+
+    let source_video = match (&video_key, DATAHOLDER.lock()) {
+        (Some(video_key), Ok(guard)) => guard.as_ref().get(video_key)
+            .filter(|video| video.thumbnail_location.is_none())
+            .map(|video| (video.location.clone(), video.duration)),
+        _ => None,
+    };
+    let mut video_thumbnail = None;
+    if let Some((location, duration)) = source_video {
+        let output = thumbnail_path(&location);
+        let duration = media_duration(&location, duration).await;
+        match generate_selected_thumbnail(&location, &output, duration, ThumbnailSelection::Scene).await {
+            Ok(_) => video_thumbnail = Some(output),
+            Err(e) => println!("Failed to generate thumbnail for {:?}: {}", location, e),
+        }
+    }
+
+    for clip in clips.iter_mut().filter(|c| c.thumbnail_location.is_none()) {
+        let output = thumbnail_path(&clip.location);
+        let duration = media_duration(&clip.location, clip.duration).await;
+        match generate_selected_thumbnail(&clip.location, &output, duration, clip_selection).await {
+            Ok(_) => clip.thumbnail_location = Some(output),
+            Err(e) => println!("Failed to generate thumbnail for {:?}: {}", clip.location, e),
+        }
+    }
+
+    if let Some(video_key) = video_key {
+        set_thumbnails(&video_key, video_thumbnail, &clips);
+    }
+    let mut progvars = ProgressVars::default();
+    progvars.insert_var("clips", Box::new(clips));
+    Ok(Some(progvars))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_thumbnail_frames() {
+        assert_eq!(thumbnail_time(Some(50.0)), 5.0);
+        assert_eq!(thumbnail_time(None), 0.0);
+        assert_eq!(thumbnail_path(Path::new("dl/myclip.mp4")), PathBuf::from("dl/myclip.jpg"));
+        assert_eq!(thumbnail_filters(ThumbnailSelection::At(1.0)), "scale='min(640,iw)':-2");
+        assert_eq!(
+            thumbnail_filters(ThumbnailSelection::Scene),
+            "select='gt(scene,0.4)',scale='min(640,iw)':-2",
+        );
+        let selection: ThumbnailSelection = serde_json::from_str(r#"{ "at": 2.5 }"#).unwrap();
+        assert_eq!(selection, ThumbnailSelection::At(2.5));
+        let selection: ThumbnailSelection = serde_json::from_str(r#""scene""#).unwrap();
+        assert_eq!(selection, ThumbnailSelection::Scene);
+    }
+}
@@ -0,0 +1,409 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use futures::Stream;
+use futures::StreamExt;
+
+use super::DownloadedVideo;
+use super::UPLOADHOLDER;
+use super::UPLOADS_PATH;
+use super::FAILED_TO_ACQUIRE_LOCK;
+use super::get_config;
+use super::random_string;
+use super::unix_timestamp_now;
+use super::data_store::initialize_object;
+use super::disk_guard::ensure_disk_limits;
+use super::import::importable_extension;
+use super::import::import_media_file;
+use super::import::upload_path;
+use super::import::ImportMode;
+use super::overlay::overlay_image_extension;
+use super::overlay::OVERLAY_IMAGE_EXTENSIONS;
+
+/// uploads that were not completed within this long
+/// are removed the next time the server starts
+pub const UPLOAD_EXPIRY_SECONDS: u64 = 7 * 24 * 60 * 60;
+/// image uploads are moved into this dir inside the download dir once
+/// they are fully received. they are kept there since the watermark
+/// of a request can keep using them long after the upload expired
+pub const ASSETS_DIR_NAME: &str = "assets";
+
+#[derive(Debug, Deserialize)]
+pub struct CreateUploadRequest {
+    pub file_name: String,
+    /// the total size of the file in bytes
+    pub size: u64,
+    pub title: Option<String>,
+}
+
+/// an upload that is in progress. the bytes received so far are
+/// in the file at location, so the offset to resume from is
+/// always the size of that file
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UploadSession {
+    pub id: String,
+    pub extension: String,
+    pub title: Option<String>,
+    pub size: u64,
+    pub location: PathBuf,
+    pub created_at: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UploadSessions {
+    #[serde(flatten)]
+    pub sessions: HashMap<String, UploadSession>,
+    /// ids of the uploads that a request is currently writing to
+    #[serde(skip)]
+    pub writing: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct UploadStatus {
+    pub id: String,
+    /// how many bytes have been received. the next
+    /// chunk must be sent starting from this offset
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum UploadError {
+    NotFound,
+    /// the offset the request expected is not where the upload is at.
+    /// contains the current status so the client can resume from it
+    Conflict(UploadStatus),
+    Invalid(String),
+}
+
+impl From<String> for UploadError {
+    fn from(e: String) -> Self {
+        UploadError::Invalid(e)
+    }
+}
+
+/// only lets one request write to an upload at a time.
+/// dropping it lets the next one write
+pub struct UploadWriteGuard(String);
+
+impl Drop for UploadWriteGuard {
+    fn drop(&mut self) {
+        if let Ok(mut guard) = UPLOADHOLDER.lock() {
+            guard.writing.retain(|id| id != &self.0);
+        }
+    }
+}
+
+fn write_upload_sessions(uploads: &UploadSessions) -> Result<(), String> {
+    let json_string = serde_json::to_string(uploads).map_err(|e| e.to_string())?;
+    std::fs::write(UPLOADS_PATH, json_string).map_err(|e| e.to_string())
+}
+
+async fn received_bytes(location: &PathBuf) -> u64 {
+    fs::metadata(location).await.map_or(0, |m| m.len())
+}
+
+fn get_session<S: AsRef<str>>(id: S) -> Result<UploadSession, UploadError> {
+    let guard = UPLOADHOLDER.lock().map_err(|_| UploadError::Invalid(FAILED_TO_ACQUIRE_LOCK.into()))?;
+    guard.sessions.get(id.as_ref()).cloned().ok_or(UploadError::NotFound)
+}
+
+/// images can be uploaded too, to use as a watermark
+fn upload_extension<S: AsRef<str>>(file_name: S) -> Result<String, String> {
+    importable_extension(file_name.as_ref())
+        .or_else(|e| overlay_image_extension(file_name.as_ref()).map_err(|_| e))
+}
+
+fn is_image_upload(session: &UploadSession) -> bool {
+    importable_extension(format!("upload.{}", session.extension)).is_err()
+}
+
+pub fn asset_path(download_dir: &PathBuf, id: &str, extension: &str) -> PathBuf {
+    download_dir.join(ASSETS_DIR_NAME).join(format!("{}.{}", id, extension))
+}
+
+/// moves a fully received image upload into the assets dir. the
+/// session is not needed after that, so the caller removes it
+fn move_to_assets(session: &UploadSession, download_dir: &PathBuf) -> Result<PathBuf, String> {
+    let location = asset_path(download_dir, &session.id, &session.extension);
+    std::fs::create_dir_all(download_dir.join(ASSETS_DIR_NAME)).map_err(
+        |e| format!("Failed to create the assets dir: {}", e))?;
+    std::fs::rename(&session.location, &location).map_err(
+        |e| format!("Failed to move {:?} into the assets dir: {}", session.location, e))?;
+    Ok(location)
+}
+
+pub async fn create_upload(request: CreateUploadRequest) -> Result<UploadStatus, String> {
+    let extension = upload_extension(&request.file_name)?;
+    let config = get_config()?;
+    if let Some(max_upload_bytes) = config.max_upload_bytes {
+        if request.size > max_upload_bytes {
+            return Err(format!("Upload is larger than {} bytes", max_upload_bytes));
+        }
+    }
+    ensure_disk_limits(&config.download_dir, None).await?;
+
+    let id = random_string(16);
+    let location = upload_path(&config.download_dir, &id);
+    fs::File::create(&location).await.map_err(
+        |e| format!("Failed to create {:?}: {}", location, e))?;
+    let file_name = request.file_name;
+    let title = request.title.or_else(|| {
+        PathBuf::from(&file_name).file_stem().and_then(|s| s.to_str()).map(|s| s.to_string())
+    });
+    let session = UploadSession {
+        id: id.clone(),
+        extension,
+        title,
+        size: request.size,
+        location,
+        created_at: unix_timestamp_now(),
+    };
+    let mut guard = UPLOADHOLDER.lock().map_err(|_| FAILED_TO_ACQUIRE_LOCK.to_string())?;
+    guard.sessions.insert(id.clone(), session);
+    write_upload_sessions(&guard)?;
+    Ok(UploadStatus { id, offset: 0, size: request.size })
+}
+
+pub async fn get_upload_status<S: AsRef<str>>(id: S) -> Result<UploadStatus, UploadError> {
+    let session = get_session(id)?;
+    Ok(UploadStatus {
+        offset: received_bytes(&session.location).await,
+        id: session.id,
+        size: session.size,
+    })
+}
+
+/// appends the chunk to the upload, if the upload is at the offset
+/// the client expects. whatever was received before an error is kept,
+/// so the client can ask for the status and resume from there
+pub async fn append_upload_chunk<S, B, E>(
+    id: String,
+    offset: u64,
+    chunks: S,
+) -> Result<UploadStatus, UploadError>
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: AsRef<[u8]>,
+    E: std::fmt::Display,
+{
+    let session = {
+        let mut guard = UPLOADHOLDER.lock().map_err(|_| UploadError::Invalid(FAILED_TO_ACQUIRE_LOCK.into()))?;
+        let session = guard.sessions.get(&id).cloned().ok_or(UploadError::NotFound)?;
+        if guard.writing.contains(&id) {
+            // the offset will have moved by the time the other request is done,
+            // so the client has to ask for the status again either way
+            let offset = std::fs::metadata(&session.location).map_or(0, |m| m.len());
+            return Err(UploadError::Conflict(UploadStatus { id, offset, size: session.size }));
+        }
+        guard.writing.push(id.clone());
+        session
+    };
+    let _write_guard = UploadWriteGuard(id);
+
+    let current_offset = received_bytes(&session.location).await;
+    if current_offset != offset {
+        return Err(UploadError::Conflict(UploadStatus {
+            id: session.id,
+            offset: current_offset,
+            size: session.size,
+        }));
+    }
+    let config = get_config()?;
+    ensure_disk_limits(&config.download_dir, None).await?;
+
+    let mut file = fs::OpenOptions::new().append(true).create(true).open(&session.location).await.map_err(
+        |e| format!("Failed to open {:?}: {}", session.location, e))?;
+    let mut chunks = chunks;
+    let mut new_offset = current_offset;
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(|e| format!("Failed to read chunk: {}", e))?;
+        let chunk = chunk.as_ref();
+        if new_offset + chunk.len() as u64 > session.size {
+            // only keep what fits, the rest was not part of the file
+            let _ = file.flush().await;
+            let _ = file.set_len(new_offset).await;
+            return Err(UploadError::Invalid(format!("Chunk goes past the size of the upload ({} bytes)", session.size)));
+        }
+        file.write_all(chunk).await.map_err(
+            |e| format!("Failed to write {:?}: {}", session.location, e))?;
+        new_offset += chunk.len() as u64;
+    }
+    file.flush().await.map_err(|e| format!("Failed to write {:?}: {}", session.location, e))?;
+
+    if new_offset == session.size && is_image_upload(&session) {
+        move_to_assets(&session, &config.download_dir)?;
+        let mut guard = UPLOADHOLDER.lock().map_err(|_| UploadError::Invalid(FAILED_TO_ACQUIRE_LOCK.into()))?;
+        guard.sessions.remove(&session.id);
+        write_upload_sessions(&guard)?;
+    }
+
+    Ok(UploadStatus { id: session.id, offset: new_offset, size: session.size })
+}
+
+/// once every byte was received, the file gets imported
+/// into the library the same way as a regular upload
+pub async fn complete_upload<S: AsRef<str>>(id: S) -> Result<(String, DownloadedVideo), UploadError> {
+    let session = {
+        let mut guard = UPLOADHOLDER.lock().map_err(|_| UploadError::Invalid(FAILED_TO_ACQUIRE_LOCK.into()))?;
+        let session = guard.sessions.get(id.as_ref()).cloned().ok_or(UploadError::NotFound)?;
+        if guard.writing.contains(&session.id) {
+            return Err(UploadError::Invalid("Upload is still being written to".into()));
+        }
+        if is_image_upload(&session) {
+            return Err(UploadError::Invalid("Image uploads can only be used as a watermark".into()));
+        }
+        let offset = std::fs::metadata(&session.location).map_or(0, |m| m.len());
+        if offset != session.size {
+            return Err(UploadError::Conflict(UploadStatus { id: session.id, offset, size: session.size }));
+        }
+        // nothing else can write to, complete, or cancel it while it is imported
+        guard.writing.push(session.id.clone());
+        session
+    };
+    let _write_guard = UploadWriteGuard(session.id.clone());
+
+    // if the import fails, the upload is kept so it can be completed again
+    let imported = import_media_file(&session.location, &session.extension, session.title, ImportMode::Move)
+        .await.map_err(String::from)?;
+    let mut guard = UPLOADHOLDER.lock().map_err(|_| UploadError::Invalid(FAILED_TO_ACQUIRE_LOCK.into()))?;
+    guard.sessions.remove(&session.id);
+    write_upload_sessions(&guard)?;
+    Ok(imported)
+}
+
+/// removes the upload, or if it was an image that
+/// was already moved into the assets dir, the asset
+pub async fn cancel_upload<S: AsRef<str>>(id: S) -> Result<(), UploadError> {
+    let location = {
+        let mut guard = UPLOADHOLDER.lock().map_err(|_| UploadError::Invalid(FAILED_TO_ACQUIRE_LOCK.into()))?;
+        if guard.writing.iter().any(|w| w == id.as_ref()) {
+            return Err(UploadError::Invalid("Upload is still being written to".into()));
+        }
+        match guard.sessions.remove(id.as_ref()) {
+            Some(session) => {
+                write_upload_sessions(&guard)?;
+                session.location
+            },
+            None => find_asset(&get_config()?.download_dir, id.as_ref()).ok_or(UploadError::NotFound)?,
+        }
+    };
+    let _ = fs::remove_file(&location).await;
+    Ok(())
+}
+
+fn find_asset(download_dir: &PathBuf, id: &str) -> Option<PathBuf> {
+    OVERLAY_IMAGE_EXTENSIONS.iter()
+        .map(|extension| asset_path(download_dir, id, extension))
+        .find(|path| path.is_file())
+}
+
+/// the file of an image upload that was fully received
+pub fn received_upload_path<S: AsRef<str>>(id: S) -> Result<PathBuf, String> {
+    let download_dir = get_config()?.download_dir;
+    if let Some(asset) = find_asset(&download_dir, id.as_ref()) {
+        return Ok(asset);
+    }
+    let session = get_session(id.as_ref()).map_err(|_| format!("No upload with id {}", id.as_ref()))?;
+    if !is_image_upload(&session) {
+        return Err(format!("Upload {} is not an image", session.id));
+    }
+    Err(format!("Upload {} has not been fully received yet", session.id))
+}
+
+/// the files of the uploads that are still in progress
+pub fn upload_locations() -> Vec<PathBuf> {
+    match UPLOADHOLDER.lock() {
+        Err(_) => vec![],
+        Ok(guard) => guard.sessions.values().map(|s| s.location.clone()).collect(),
+    }
+}
+
+pub fn is_expired(session: &UploadSession, now: u64) -> bool {
+    now.saturating_sub(session.created_at) > UPLOAD_EXPIRY_SECONDS
+}
+
+/// loads the uploads that were in progress when the server
+/// stopped, and removes the ones that expired
+pub fn initialize_uploads() -> Result<(), String> {
+    let mut uploads: UploadSessions = initialize_object(UPLOADS_PATH)?;
+    let now = unix_timestamp_now();
+    let expired: Vec<UploadSession> = uploads.sessions.values()
+        .filter(|s| is_expired(s, now))
+        .cloned()
+        .collect();
+    for session in expired.iter() {
+        uploads.sessions.remove(&session.id);
+        let _ = std::fs::remove_file(&session.location);
+    }
+    if !expired.is_empty() {
+        write_upload_sessions(&uploads)?;
+    }
+    let mut guard = UPLOADHOLDER.lock().map_err(|_| FAILED_TO_ACQUIRE_LOCK.to_string())?;
+    *guard = uploads;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upload_sessions_round_trip_and_expire() {
+        let json_string = r#"{
+            "abc": { "id": "abc", "extension": "mp4", "title": null, "size": 10, "location": "dl/abc.upload", "created_at": 100 }
+        }"#;
+        let uploads: UploadSessions = serde_json::from_str(json_string).unwrap();
+        let session = &uploads.sessions["abc"];
+        assert_eq!(session.size, 10);
+        assert!(uploads.writing.is_empty());
+        assert!(!is_expired(session, 100 + UPLOAD_EXPIRY_SECONDS));
+        assert!(is_expired(session, 101 + UPLOAD_EXPIRY_SECONDS));
+
+        let written = serde_json::to_string(&uploads).unwrap();
+        assert!(written.starts_with(r#"{"abc":{"#));
+    }
+
+    #[test]
+    fn appends_only_at_the_offset_and_only_up_to_the_size() {
+        let upload_dir = std::env::temp_dir().join(format!("vidclipper-uploads-{}", random_string(8)));
+        std::fs::create_dir_all(&upload_dir).unwrap();
+        let location = upload_dir.join("a.upload");
+        std::fs::write(&location, "abc").unwrap();
+        let id = random_string(16);
+        UPLOADHOLDER.lock().unwrap().sessions.insert(id.clone(), UploadSession {
+            id: id.clone(),
+            extension: "mp4".into(),
+            title: None,
+            size: 5,
+            location: location.clone(),
+            created_at: 0,
+        });
+        let status = |offset| UploadStatus { id: id.clone(), offset, size: 5 };
+        let chunks = |chunks: Vec<&'static str>| futures::stream::iter(chunks.into_iter().map(Ok::<_, String>));
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let res = append_upload_chunk(id.clone(), 1, chunks(vec!["d"])).await;
+            assert_eq!(res, Err(UploadError::Conflict(status(3))));
+
+            UPLOADHOLDER.lock().unwrap().writing.push(id.clone());
+            let res = append_upload_chunk(id.clone(), 3, chunks(vec!["d"])).await;
+            assert_eq!(res, Err(UploadError::Conflict(status(3))));
+            UPLOADHOLDER.lock().unwrap().writing.clear();
+
+            // the first chunk fits, the second one would go past the size
+            let res = append_upload_chunk(id.clone(), 3, chunks(vec!["d", "ef"])).await;
+            assert!(matches!(res, Err(UploadError::Invalid(_))));
+            assert_eq!(std::fs::read_to_string(&location).unwrap(), "abcd");
+
+            let res = append_upload_chunk(id.clone(), 4, chunks(vec!["e"])).await;
+            assert_eq!(res, Ok(status(5)));
+            assert_eq!(std::fs::read_to_string(&location).unwrap(), "abcde");
+        });
+        UPLOADHOLDER.lock().unwrap().sessions.remove(&id);
+        let _ = std::fs::remove_dir_all(&upload_dir);
+    }
+}
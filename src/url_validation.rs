@@ -0,0 +1,230 @@
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+
+use super::Config;
+
+#[derive(Debug, PartialEq)]
+pub struct ParsedUrl {
+    pub scheme: String,
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+/// very minimal url parsing. we only care about
+/// the scheme, the host, and the port, so we dont
+/// bother with the path/query/fragment
+pub fn parse_url<S: AsRef<str>>(url: S) -> Result<ParsedUrl, String> {
+    let url = url.as_ref().trim();
+    let scheme_index = url.find("://").map_or_else(
+        || Err(format!("Url is missing a scheme: {}", url)),
+        |i| Ok(i))?;
+    let scheme = url[..scheme_index].to_lowercase();
+    if scheme.is_empty() || !scheme.chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.') {
+        return Err(format!("Url has an invalid scheme: {}", url));
+    }
+
+    let rest = &url[(scheme_index + 3)..];
+    let authority_end = rest.find(|c| c == '/' || c == '?' || c == '#').unwrap_or(rest.len());
+    let authority = &rest[..authority_end];
+    // strip the user:pass@ if it exists
+    let host_and_port = match authority.rfind('@') {
+        Some(i) => &authority[(i + 1)..],
+        None => authority,
+    };
+
+    let (host, port_str) = if host_and_port.starts_with('[') {
+        // ipv6 literal ie: [::1]:8080
+        let close_index = host_and_port.find(']').map_or_else(
+            || Err(format!("Url has an invalid ipv6 host: {}", url)),
+            |i| Ok(i))?;
+        let host = &host_and_port[1..close_index];
+        let after = &host_and_port[(close_index + 1)..];
+        let port_str = if after.starts_with(':') { Some(&after[1..]) } else { None };
+        (host, port_str)
+    } else {
+        match host_and_port.rfind(':') {
+            Some(i) => (&host_and_port[..i], Some(&host_and_port[(i + 1)..])),
+            None => (host_and_port, None),
+        }
+    };
+
+    let port = match port_str {
+        None | Some("") => None,
+        Some(p) => Some(p.parse::<u16>().map_err(
+            |_| format!("Url has an invalid port: {}", url))?),
+    };
+
+    let host = host.trim_end_matches('.').to_lowercase();
+    if host.is_empty() {
+        return Err(format!("Url is missing a host: {}", url));
+    }
+
+    Ok(ParsedUrl { scheme, host, port })
+}
+
+/// a pattern matches the host if it is the same as the host,
+/// or if the host is a subdomain of the pattern. a leading "*."
+/// in the pattern is ignored, ie: "*.example.com" and "example.com"
+/// are treated the same
+pub fn host_matches_pattern<S: AsRef<str>>(host: &str, pattern: S) -> bool {
+    let pattern = pattern.as_ref().trim().trim_start_matches("*.").to_lowercase();
+    if pattern.is_empty() {
+        return false;
+    }
+    host == pattern || host.ends_with(&format!(".{}", pattern))
+}
+
+pub fn is_private_ipv4(ip: &Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    ip.is_private() || ip.is_loopback() || ip.is_link_local() ||
+    ip.is_unspecified() || ip.is_broadcast() || ip.is_documentation() ||
+    // 100.64.0.0/10 carrier grade nat
+    (octets[0] == 100 && (octets[1] & 0b1100_0000) == 64) ||
+    // 0.0.0.0/8
+    octets[0] == 0
+}
+
+pub fn is_private_ipv6(ip: &Ipv6Addr) -> bool {
+    if let Some(v4) = ip.to_ipv4() {
+        // ipv4 mapped/compatible addresses, ie: ::ffff:127.0.0.1
+        if is_private_ipv4(&v4) {
+            return true;
+        }
+    }
+    let first_segment = ip.segments()[0];
+    ip.is_loopback() || ip.is_unspecified() ||
+    // fc00::/7 unique local
+    (first_segment & 0xfe00) == 0xfc00 ||
+    // fe80::/10 link local
+    (first_segment & 0xffc0) == 0xfe80
+}
+
+pub fn is_private_address(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => is_private_ipv4(v4),
+        IpAddr::V6(v6) => is_private_ipv6(v6),
+    }
+}
+
+/// checks everything that can be checked without
+/// touching the network: scheme, and the domain allow/deny lists
+pub fn validate_parsed_url(parsed: &ParsedUrl, config: &Config) -> Result<(), String> {
+    if !config.allowed_schemes.iter().any(|s| s.eq_ignore_ascii_case(&parsed.scheme)) {
+        return Err(format!("Url scheme '{}' is not allowed", parsed.scheme));
+    }
+    if config.domain_denylist.iter().any(|p| host_matches_pattern(&parsed.host, p)) {
+        return Err(format!("Host '{}' is not allowed", parsed.host));
+    }
+    if !config.domain_allowlist.is_empty() &&
+        !config.domain_allowlist.iter().any(|p| host_matches_pattern(&parsed.host, p))
+    {
+        return Err(format!("Host '{}' is not in the list of allowed domains", parsed.host));
+    }
+    Ok(())
+}
+
+/// parse and validate the url against the config, and then
+/// resolve the host to make sure it does not point to an internal
+/// address (unless the config explicitly allows that).
+/// note that this only checks the url we were given. youtube-dl
+/// resolves the host again, and follows redirects, so a dns record
+/// that changes in between (dns rebinding) or a redirect to an
+/// internal address are not caught by this
+pub async fn validate_url<S: AsRef<str>>(url: S, config: &Config) -> Result<(), String> {
+    let parsed = parse_url(url)?;
+    validate_parsed_url(&parsed, config)?;
+    if config.allow_private_addresses {
+        return Ok(());
+    }
+
+    let port = parsed.port.unwrap_or(if parsed.scheme == "https" { 443 } else { 80 });
+    let addresses: Vec<IpAddr> = match parsed.host.parse::<IpAddr>() {
+        Ok(ip) => vec![ip],
+        Err(_) => {
+            let lookup = tokio::net::lookup_host((parsed.host.as_str(), port)).await.map_err(
+                |e| format!("Failed to resolve host '{}': {}", parsed.host, e))?;
+            lookup.map(|socket_addr| socket_addr.ip()).collect()
+        }
+    };
+
+    if addresses.is_empty() {
+        return Err(format!("Failed to resolve host '{}'", parsed.host));
+    }
+    if let Some(ip) = addresses.iter().find(|ip| is_private_address(ip)) {
+        return Err(format!("Host '{}' resolves to a private address: {}", parsed.host, ip));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config() -> Config {
+        let mut config = Config::default();
+        config.allowed_schemes = super::super::data_store::default_allowed_schemes();
+        config
+    }
+
+    #[test]
+    fn parse_url_works() {
+        let parsed = parse_url("https://user:pw@WWW.Youtube.com:8443/watch?v=x").unwrap();
+        assert_eq!(parsed.scheme, "https");
+        assert_eq!(parsed.host, "www.youtube.com");
+        assert_eq!(parsed.port, Some(8443));
+
+        let parsed = parse_url("http://[::1]/a").unwrap();
+        assert_eq!(parsed.host, "::1");
+        assert_eq!(parsed.port, None);
+
+        assert!(parse_url("youtube.com/watch?v=x").is_err());
+        assert!(parse_url("http:///etc/passwd").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_schemes_and_domains() {
+        let mut config = test_config();
+        let file_url = parse_url("file:///etc/passwd");
+        // file urls have no host so they fail parsing anyway
+        assert!(file_url.is_err());
+        let ftp_url = parse_url("ftp://example.com/a").unwrap();
+        assert!(validate_parsed_url(&ftp_url, &config).is_err());
+
+        config.domain_denylist = vec!["bad.com".into()];
+        let bad = parse_url("https://sub.bad.com/x").unwrap();
+        assert!(validate_parsed_url(&bad, &config).is_err());
+        let notbad = parse_url("https://notbad.com/x").unwrap();
+        assert!(validate_parsed_url(&notbad, &config).is_ok());
+
+        config.domain_allowlist = vec!["*.youtube.com".into(), "youtu.be".into()];
+        let yt = parse_url("https://www.youtube.com/watch?v=x").unwrap();
+        assert!(validate_parsed_url(&yt, &config).is_ok());
+        let short = parse_url("https://youtu.be/x").unwrap();
+        assert!(validate_parsed_url(&short, &config).is_ok());
+        assert!(validate_parsed_url(&notbad, &config).is_err());
+    }
+
+    #[test]
+    fn detects_private_addresses() {
+        let private = ["127.0.0.1", "10.1.2.3", "192.168.0.1", "169.254.169.254",
+            "0.0.0.0", "100.64.0.1", "::1", "fd00::1", "fe80::1", "::ffff:127.0.0.1"];
+        for ip in private.iter() {
+            assert!(is_private_address(&ip.parse().unwrap()), "{} should be private", ip);
+        }
+        let public = ["8.8.8.8", "142.250.72.14", "2607:f8b0:4005:80a::200e"];
+        for ip in public.iter() {
+            assert!(!is_private_address(&ip.parse().unwrap()), "{} should be public", ip);
+        }
+    }
+
+    #[test]
+    fn validate_url_rejects_literal_private_ips() {
+        let config = test_config();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            assert!(validate_url("http://127.0.0.1:8080/a", &config).await.is_err());
+            assert!(validate_url("http://[::1]/a", &config).await.is_err());
+        });
+    }
+}
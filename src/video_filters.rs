@@ -0,0 +1,281 @@
+use serde::{Deserialize, Serialize};
+
+/// a rectangle of the source video, in pixels
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CropRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// if only one side is set, the other one keeps the aspect ratio
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ScaleSize {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+/// pads the video to this size, centered
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PadSize {
+    pub width: u32,
+    pub height: u32,
+    /// an ffmpeg color, ie: black, white, 0x1e1e1e. defaults to black
+    pub color: Option<String>,
+}
+
+/// how the frames of the clip are reframed. they are applied in this
+/// order: crop, rotate, target_aspect, scale, pad. every size is
+/// rounded down to an even number, since h264 needs even sizes
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct VideoFilterRequest {
+    /// in the coordinates of the source video, before rotating
+    pub crop: Option<CropRect>,
+    /// degrees clockwise. one of 90, 180, 270
+    pub rotate: Option<u32>,
+    /// ie: "9:16" or "1:1". the center of the frame
+    /// is cropped to this aspect ratio
+    pub target_aspect: Option<String>,
+    pub scale: Option<ScaleSize>,
+    pub pad: Option<PadSize>,
+}
+
+impl VideoFilterRequest {
+    pub fn is_empty(&self) -> bool {
+        self == &VideoFilterRequest::default()
+    }
+}
+
+/// the largest width or height a scale or pad can ask for
+pub const MAX_FRAME_SIZE: u32 = 8192;
+
+fn even(n: u32) -> u32 {
+    n - n % 2
+}
+
+pub fn parse_aspect_ratio<S: AsRef<str>>(aspect: S) -> Result<(u32, u32), String> {
+    let aspect = aspect.as_ref();
+    let invalid = || format!("Invalid target_aspect '{}', expected something like 9:16", aspect);
+    let mut parts = aspect.trim().splitn(2, ':');
+    let width = parts.next().and_then(|w| w.trim().parse::<u32>().ok()).ok_or_else(invalid)?;
+    let height = parts.next().and_then(|h| h.trim().parse::<u32>().ok()).ok_or_else(invalid)?;
+    if width == 0 || height == 0 {
+        return Err(invalid());
+    }
+    Ok((width, height))
+}
+
+/// colors end up inside the filter string, so
+/// only let through what a color can contain
+pub fn validate_pad_color<S: AsRef<str>>(color: S) -> Result<(), String> {
+    let color = color.as_ref();
+    let is_valid = !color.is_empty() && color.len() <= 32 &&
+        color.chars().all(|c| c.is_ascii_alphanumeric() || c == '#' || c == '@' || c == '.');
+    if !is_valid {
+        return Err(format!("Invalid pad color '{}'", color));
+    }
+    Ok(())
+}
+
+/// checks what can be checked without knowing the size of the source
+pub fn validate_video_filters(request: &VideoFilterRequest) -> Result<(), String> {
+    if let Some(rotate) = request.rotate {
+        if rotate % 90 != 0 || rotate >= 360 {
+            return Err(format!("Cannot rotate by {} degrees, must be one of 90, 180, 270", rotate));
+        }
+    }
+    if let Some(ref aspect) = request.target_aspect {
+        parse_aspect_ratio(aspect)?;
+    }
+    if let Some(scale) = request.scale {
+        if scale.width.is_none() && scale.height.is_none() {
+            return Err("scale needs a width, a height, or both".into());
+        }
+        if scale.width == Some(0) || scale.height == Some(0) {
+            return Err("scale width and height must be positive".into());
+        }
+        if scale.width.unwrap_or(0) > MAX_FRAME_SIZE || scale.height.unwrap_or(0) > MAX_FRAME_SIZE {
+            return Err(format!("scale width and height can be at most {}", MAX_FRAME_SIZE));
+        }
+    }
+    if let Some(ref pad) = request.pad {
+        if pad.width > MAX_FRAME_SIZE || pad.height > MAX_FRAME_SIZE {
+            return Err(format!("pad width and height can be at most {}", MAX_FRAME_SIZE));
+        }
+        if let Some(ref color) = pad.color {
+            validate_pad_color(color)?;
+        }
+    }
+    Ok(())
+}
+
+/// translates the request into an ffmpeg -vf chain for a source of
+/// the given size. fails if the request does not fit the source,
+/// ie: a crop outside of the frame, or a pad smaller than the frame
+pub fn build_video_filters(
+    request: &VideoFilterRequest,
+    source_width: u32,
+    source_height: u32,
+) -> Result<Vec<String>, String> {
+    validate_video_filters(request)?;
+    let mut filters = vec![];
+    let (mut width, mut height) = (source_width, source_height);
+
+    if let Some(crop) = request.crop {
+        let fits = crop.x.checked_add(crop.width).map_or(false, |right| right <= width) &&
+            crop.y.checked_add(crop.height).map_or(false, |bottom| bottom <= height);
+        if !fits {
+            return Err(format!(
+                "Crop {}x{} at {},{} does not fit inside the {}x{} source video",
+                crop.width, crop.height, crop.x, crop.y, width, height,
+            ));
+        }
+        width = even(crop.width);
+        height = even(crop.height);
+        filters.push(format!("crop={}:{}:{}:{}", width, height, crop.x, crop.y));
+    }
+
+    match request.rotate.unwrap_or(0) {
+        90 => filters.push("transpose=clock".into()),
+        180 => filters.push("hflip,vflip".into()),
+        270 => filters.push("transpose=cclock".into()),
+        _ => {},
+    }
+    if request.rotate == Some(90) || request.rotate == Some(270) {
+        std::mem::swap(&mut width, &mut height);
+    }
+
+    let target_aspect = match request.target_aspect {
+        Some(ref aspect) => Some(parse_aspect_ratio(aspect)?),
+        None => None,
+    };
+    if let Some((aspect_width, aspect_height)) = target_aspect {
+        // keep as much of the frame as fits the aspect ratio
+        let (new_width, new_height) = if width as u64 * aspect_height as u64 > height as u64 * aspect_width as u64 {
+            (even((height as u64 * aspect_width as u64 / aspect_height as u64) as u32), height)
+        } else {
+            (width, even((width as u64 * aspect_height as u64 / aspect_width as u64) as u32))
+        };
+        if (new_width, new_height) != (width, height) {
+            filters.push(format!(
+                "crop={}:{}:{}:{}",
+                new_width, new_height, (width - new_width) / 2, (height - new_height) / 2,
+            ));
+            width = new_width;
+            height = new_height;
+        }
+    }
+
+    if let Some(scale) = request.scale {
+        // the crop to the target aspect was rounded to even sizes,
+        // so the exact ratio is what the other side is scaled by
+        let (ratio_width, ratio_height) = target_aspect.unwrap_or((width, height));
+        let (new_width, new_height) = match (scale.width, scale.height) {
+            (Some(w), Some(h)) => (w, h),
+            (Some(w), None) => (w, (w as u64 * ratio_height as u64 / ratio_width.max(1) as u64) as u32),
+            (None, Some(h)) => ((h as u64 * ratio_width as u64 / ratio_height.max(1) as u64) as u32, h),
+            (None, None) => (width, height),
+        };
+        width = even(new_width);
+        height = even(new_height);
+        filters.push(format!("scale={}:{}", width, height));
+    }
+
+    if let Some(ref pad) = request.pad {
+        let (pad_width, pad_height) = (even(pad.width), even(pad.height));
+        if pad_width < width || pad_height < height {
+            return Err(format!(
+                "Cannot pad the {}x{} frame to the smaller size {}x{}",
+                width, height, pad_width, pad_height,
+            ));
+        }
+        filters.push(format!(
+            "pad={}:{}:{}:{}:color={}",
+            pad_width, pad_height, (pad_width - width) / 2, (pad_height - height) / 2,
+            pad.color.as_deref().unwrap_or("black"),
+        ));
+        width = pad_width;
+        height = pad_height;
+    }
+
+    if width < 2 || height < 2 {
+        return Err(format!("The output frame would be {}x{}, which is too small", width, height));
+    }
+    if width > MAX_FRAME_SIZE || height > MAX_FRAME_SIZE {
+        return Err(format!(
+            "The output frame would be {}x{}, but it can be at most {}x{}",
+            width, height, MAX_FRAME_SIZE, MAX_FRAME_SIZE,
+        ));
+    }
+    if !filters.is_empty() {
+        // otherwise players may stretch the frame
+        // back to the aspect ratio of the source
+        filters.push("setsar=1".into());
+    }
+    Ok(filters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reframes_landscape_to_vertical() {
+        let request = VideoFilterRequest {
+            target_aspect: Some("9:16".into()),
+            scale: Some(ScaleSize { width: Some(1080), height: None }),
+            ..Default::default()
+        };
+        assert_eq!(build_video_filters(&request, 1920, 1080).unwrap(), vec![
+            "crop=606:1080:657:0", "scale=1080:1920", "setsar=1",
+        ]);
+
+        let square = VideoFilterRequest { target_aspect: Some("1:1".into()), ..Default::default() };
+        assert_eq!(build_video_filters(&square, 1280, 720).unwrap(), vec!["crop=720:720:280:0", "setsar=1"]);
+        assert!(build_video_filters(&VideoFilterRequest::default(), 1280, 720).unwrap().is_empty());
+    }
+
+    #[test]
+    fn crops_rotates_and_pads() {
+        let request = VideoFilterRequest {
+            crop: Some(CropRect { x: 100, y: 50, width: 641, height: 360 }),
+            rotate: Some(90),
+            pad: Some(PadSize { width: 400, height: 700, color: Some("white".into()) }),
+            ..Default::default()
+        };
+        assert_eq!(build_video_filters(&request, 1280, 720).unwrap(), vec![
+            "crop=640:360:100:50", "transpose=clock", "pad=400:700:20:30:color=white", "setsar=1",
+        ]);
+
+        let outside = VideoFilterRequest {
+            crop: Some(CropRect { x: 1000, y: 0, width: 640, height: 360 }),
+            ..Default::default()
+        };
+        assert!(build_video_filters(&outside, 1280, 720).is_err());
+
+        let small_pad = VideoFilterRequest {
+            pad: Some(PadSize { width: 640, height: 360, color: None }),
+            ..Default::default()
+        };
+        assert!(build_video_filters(&small_pad, 1280, 720).is_err());
+    }
+
+    #[test]
+    fn validates_video_filters() {
+        assert_eq!(parse_aspect_ratio("9:16").unwrap(), (9, 16));
+        assert!(parse_aspect_ratio("9").is_err());
+        assert!(parse_aspect_ratio("0:1").is_err());
+        assert!(validate_video_filters(&VideoFilterRequest { rotate: Some(45), ..Default::default() }).is_err());
+        let bad_scale = VideoFilterRequest { scale: Some(ScaleSize::default()), ..Default::default() };
+        assert!(validate_video_filters(&bad_scale).is_err());
+        let huge_scale = VideoFilterRequest { scale: Some(ScaleSize { width: Some(8194), height: None }), ..Default::default() };
+        assert!(validate_video_filters(&huge_scale).is_err());
+        let huge_pad = VideoFilterRequest { pad: Some(PadSize { width: 100_000, height: 2, color: None }), ..Default::default() };
+        assert!(validate_video_filters(&huge_pad).is_err());
+        let tall_scale = VideoFilterRequest { scale: Some(ScaleSize { width: Some(8192), height: None }), ..Default::default() };
+        assert!(build_video_filters(&tall_scale, 1080, 1920).is_err());
+        assert!(validate_pad_color("0x1e1e1e@0.5").is_ok());
+        assert!(validate_pad_color("black,drawtext").is_err());
+    }
+}
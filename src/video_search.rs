@@ -0,0 +1,312 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+
+use super::DownloadedVideo;
+use super::pagination::Page;
+use super::pagination::SortOrder;
+use super::pagination::SortValue;
+use super::pagination::decode_cursor;
+use super::pagination::paginate;
+
+pub const TITLE_WEIGHT: u32 = 4;
+pub const TAG_WEIGHT: u32 = 3;
+pub const UPLOADER_WEIGHT: u32 = 2;
+pub const DESCRIPTION_WEIGHT: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VideoSort {
+    Relevance,
+    Added,
+    Title,
+    Duration,
+    UploadDate,
+    ViewCount,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct VideoQuery {
+    pub q: Option<String>,
+    /// matches both the tags from youtube-dl and the user's tags
+    pub tag: Option<String>,
+    pub collection: Option<String>,
+    pub sort: Option<VideoSort>,
+    pub order: Option<SortOrder>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    /// the next_cursor from the previous page
+    pub cursor: Option<String>,
+}
+
+/// lowercases the text and splits it on anything
+/// that is not alphanumeric
+pub fn tokenize<S: AsRef<str>>(text: S) -> Vec<String> {
+    text.as_ref()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(|t| t.to_lowercase())
+        .collect()
+}
+
+/// an in memory inverted index of the video library.
+/// maps every term to the keys of the videos that contain
+/// that term, and how well that video matches the term
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+    postings: BTreeMap<String, HashMap<String, u32>>,
+    terms_by_key: HashMap<String, Vec<String>>,
+}
+
+impl SearchIndex {
+    pub fn build<'a, I: Iterator<Item = (&'a String, &'a DownloadedVideo)>>(videos: I) -> SearchIndex {
+        let mut index = SearchIndex::default();
+        for (key, video) in videos {
+            index.insert(key, video);
+        }
+        index
+    }
+
+    pub fn insert(&mut self, key: &String, video: &DownloadedVideo) {
+        self.remove(key);
+
+        let mut term_scores: HashMap<String, u32> = HashMap::new();
+        let mut add_terms = |text: &str, weight: u32| {
+            for term in tokenize(text) {
+                *term_scores.entry(term).or_insert(0) += weight;
+            }
+        };
+        if let Some(ref title) = video.title {
+            add_terms(title, TITLE_WEIGHT);
+        }
+        for tag in video.tags.iter().chain(video.annotations.tags.iter()) {
+            add_terms(tag, TAG_WEIGHT);
+        }
+        if let Some(ref uploader) = video.uploader {
+            add_terms(uploader, UPLOADER_WEIGHT);
+        }
+        if let Some(ref description) = video.description {
+            add_terms(description, DESCRIPTION_WEIGHT);
+        }
+        if let Some(ref notes) = video.annotations.notes {
+            add_terms(notes, DESCRIPTION_WEIGHT);
+        }
+
+        let mut terms = vec![];
+        for (term, score) in term_scores {
+            self.postings.entry(term.clone()).or_insert_with(HashMap::new).insert(key.clone(), score);
+            terms.push(term);
+        }
+        self.terms_by_key.insert(key.clone(), terms);
+    }
+
+    pub fn remove(&mut self, key: &String) {
+        let terms = match self.terms_by_key.remove(key) {
+            Some(t) => t,
+            None => return,
+        };
+        for term in terms {
+            let is_empty = match self.postings.get_mut(&term) {
+                Some(keys) => {
+                    keys.remove(key);
+                    keys.is_empty()
+                },
+                None => false,
+            };
+            if is_empty {
+                self.postings.remove(&term);
+            }
+        }
+    }
+
+    /// returns the keys of the videos that match every term of the query,
+    /// along with their score. query terms match any indexed term they are
+    /// a prefix of, so "cat" matches "cats"
+    pub fn search<S: AsRef<str>>(&self, query: S) -> HashMap<String, u32> {
+        let mut results: Option<HashMap<String, u32>> = None;
+        for query_term in tokenize(query) {
+            let mut term_results: HashMap<String, u32> = HashMap::new();
+            let matching = self.postings.range(query_term.clone()..)
+                .take_while(|(term, _)| term.starts_with(&query_term));
+            for (term, keys) in matching {
+                // exact matches count more than prefix matches
+                let multiplier = if *term == query_term { 2 } else { 1 };
+                for (key, score) in keys {
+                    *term_results.entry(key.clone()).or_insert(0) += score * multiplier;
+                }
+            }
+
+            results = Some(match results {
+                None => term_results,
+                Some(previous) => previous.into_iter()
+                    .filter_map(|(key, score)| term_results.get(&key).map(|s| (key, score + s)))
+                    .collect(),
+            });
+        }
+        results.unwrap_or_default()
+    }
+}
+
+pub fn video_sort_value(
+    sort: VideoSort,
+    key: &String,
+    video: &DownloadedVideo,
+    scores: Option<&HashMap<String, u32>>,
+) -> SortValue {
+    match sort {
+        VideoSort::Relevance => scores.and_then(|s| s.get(key)).map(|s| *s as f64).into(),
+        VideoSort::Added => video.added_at.map(|a| a as f64).into(),
+        VideoSort::Title => video.title.as_ref().map(|t| t.to_lowercase()).into(),
+        VideoSort::Duration => video.duration.into(),
+        VideoSort::UploadDate => video.upload_date.clone().into(),
+        VideoSort::ViewCount => video.view_count.map(|v| v as f64).into(),
+    }
+}
+
+pub fn video_matches_filters(video: &DownloadedVideo, query: &VideoQuery) -> bool {
+    if let Some(ref tag) = query.tag {
+        let has_tag = video.annotations.has_tag(tag) ||
+            video.tags.iter().any(|t| t.eq_ignore_ascii_case(tag));
+        if !has_tag {
+            return false;
+        }
+    }
+    match query.collection {
+        Some(ref collection) => video.annotations.in_collection(collection),
+        None => true,
+    }
+}
+
+/// filters the videos by the query text and tag/collection (if any), sorts them,
+/// and then returns the page that the cursor/offset/limit point to
+pub fn query_videos(
+    videos: Vec<(String, DownloadedVideo)>,
+    index: &SearchIndex,
+    query: &VideoQuery,
+) -> Result<Page<(String, DownloadedVideo)>, String> {
+    let cursor = match query.cursor {
+        Some(ref c) => Some(decode_cursor(c)?),
+        None => None,
+    };
+    let query_text = query.q.as_ref().map(|q| q.trim()).filter(|q| !q.is_empty());
+    let scores = query_text.map(|q| index.search(q));
+    let videos: Vec<(String, DownloadedVideo)> = match scores {
+        None => videos,
+        Some(ref scores) => videos.into_iter().filter(|(key, _)| scores.contains_key(key)).collect(),
+    };
+    let videos: Vec<(String, DownloadedVideo)> = videos.into_iter()
+        .filter(|(_, video)| video_matches_filters(video, query))
+        .collect();
+
+    let sort = query.sort.unwrap_or(match scores {
+        Some(_) => VideoSort::Relevance,
+        None => VideoSort::Added,
+    });
+    let order = query.order.unwrap_or(match sort {
+        VideoSort::Title => SortOrder::Asc,
+        _ => SortOrder::Desc,
+    });
+    let entries = videos.into_iter().map(|(key, video)| {
+        let value = video_sort_value(sort, &key, &video, scores.as_ref());
+        (value, key.clone(), (key, video))
+    }).collect();
+
+    Ok(paginate(entries, order, cursor.as_ref(), query.offset.unwrap_or(0), query.limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_videos() -> Vec<(String, DownloadedVideo)> {
+        let mut a = DownloadedVideo::default();
+        a.title = Some("Funny Cats compilation".into());
+        a.duration = Some(100.0);
+        a.tags = vec!["animals".into()];
+        a.added_at = Some(2);
+        let mut b = DownloadedVideo::default();
+        b.title = Some("Dog training".into());
+        b.description = Some("not about cats at all".into());
+        b.uploader = Some("DogChannel".into());
+        b.duration = Some(50.0);
+        b.added_at = Some(1);
+        let mut c = DownloadedVideo::default();
+        c.title = Some("another video".into());
+        c.added_at = Some(3);
+        c.annotations.tags = vec!["Animals".into()];
+        c.annotations.notes = Some("good intro shot".into());
+        c.annotations.collections = vec!["Project X".into()];
+        vec![("a".into(), a), ("b".into(), b), ("c".into(), c)]
+    }
+
+    fn keys(videos: &[(String, DownloadedVideo)]) -> Vec<&str> {
+        videos.iter().map(|(k, _)| k.as_str()).collect()
+    }
+
+    #[test]
+    fn search_index_matches_terms_and_prefixes() {
+        let videos = test_videos();
+        let mut index = SearchIndex::build(videos.iter().map(|(k, v)| (k, v)));
+        let results = index.search("cats");
+        assert_eq!(results.len(), 2);
+        // title match scores higher than a description match
+        assert!(results["a"] > results["b"]);
+        assert_eq!(index.search("anim").len(), 2);
+        assert_eq!(index.search("intro").len(), 1);
+        assert_eq!(index.search("dogchannel training").len(), 1);
+        assert_eq!(index.search("cats dog").len(), 1);
+        assert!(index.search("nothing").is_empty());
+
+        index.remove(&"a".to_string());
+        assert_eq!(index.search("cats").len(), 1);
+        assert_eq!(index.search("animals").len(), 1);
+    }
+
+    #[test]
+    fn query_videos_sorts_and_paginates() {
+        let videos = test_videos();
+        let index = SearchIndex::build(videos.iter().map(|(k, v)| (k, v)));
+
+        let mut query = VideoQuery::default();
+        let results = query_videos(videos.clone(), &index, &query).unwrap();
+        assert_eq!(keys(&results.items), vec!["c", "a", "b"]);
+
+        query.sort = Some(VideoSort::Title);
+        let results = query_videos(videos.clone(), &index, &query).unwrap();
+        assert_eq!(keys(&results.items), vec!["c", "b", "a"]);
+
+        query.sort = None;
+        query.q = Some("cats".into());
+        let results = query_videos(videos.clone(), &index, &query).unwrap();
+        assert_eq!(keys(&results.items), vec!["a", "b"]);
+
+        query.q = None;
+        query.sort = Some(VideoSort::Duration);
+        query.order = Some(SortOrder::Asc);
+        let results = query_videos(videos.clone(), &index, &query).unwrap();
+        assert_eq!(keys(&results.items), vec!["b", "a", "c"]);
+
+        query.offset = Some(1);
+        query.limit = Some(1);
+        let results = query_videos(videos.clone(), &index, &query).unwrap();
+        assert_eq!(keys(&results.items), vec!["a"]);
+
+        query.offset = None;
+        query.cursor = results.next_cursor;
+        let results = query_videos(videos.clone(), &index, &query).unwrap();
+        assert_eq!(keys(&results.items), vec!["c"]);
+        assert!(results.next_cursor.is_none());
+
+        query.cursor = Some("not a cursor".into());
+        assert!(query_videos(videos.clone(), &index, &query).is_err());
+
+        let mut query = VideoQuery::default();
+        query.tag = Some("animals".into());
+        let results = query_videos(videos.clone(), &index, &query).unwrap();
+        assert_eq!(keys(&results.items), vec!["c", "a"]);
+
+        query.collection = Some("Project X".into());
+        let results = query_videos(videos.clone(), &index, &query).unwrap();
+        assert_eq!(keys(&results.items), vec!["c"]);
+    }
+}
@@ -0,0 +1,248 @@
+use std::path::Path;
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
+
+use super::create_command;
+use super::set_analysis_failed;
+use super::set_waveform;
+use super::DATAHOLDER;
+
+/// the audio is decoded to mono at this rate. plenty
+/// to see where speech or beats are
+pub const WAVEFORM_SAMPLE_RATE: u32 = 8000;
+/// samples per pixel of each zoom level, finest first.
+/// each level must be a multiple of the first one
+pub const WAVEFORM_ZOOM_LEVELS: [u32; 3] = [256, 1024, 4096];
+
+/// one zoom level of the waveform. the fields are the same
+/// as the json that audiowaveform writes, so frontend libraries
+/// that read that format can use it as is
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Waveform {
+    pub version: u32,
+    pub channels: u32,
+    pub sample_rate: u32,
+    pub samples_per_pixel: u32,
+    pub bits: u32,
+    /// number of pixels, ie: min/max pairs in data
+    pub length: usize,
+    /// min, max, min, max...
+    pub data: Vec<i8>,
+}
+
+impl Waveform {
+    pub fn new(samples_per_pixel: u32, data: Vec<i8>) -> Waveform {
+        Waveform {
+            version: 2,
+            channels: 1,
+            sample_rate: WAVEFORM_SAMPLE_RATE,
+            samples_per_pixel,
+            bits: 8,
+            length: data.len() / 2,
+            data,
+        }
+    }
+}
+
+/// computes the min/max of every samples_per_pixel samples
+/// as they come in, so the decoded audio never has to be in
+/// memory all at once
+pub struct PeakBuilder {
+    samples_per_pixel: u32,
+    count: u32,
+    min: i8,
+    max: i8,
+    data: Vec<i8>,
+}
+
+impl PeakBuilder {
+    pub fn new(samples_per_pixel: u32) -> PeakBuilder {
+        PeakBuilder { samples_per_pixel, count: 0, min: i8::MAX, max: i8::MIN, data: vec![] }
+    }
+
+    pub fn push_sample(&mut self, sample: i16) {
+        let sample = (sample >> 8) as i8;
+        self.min = self.min.min(sample);
+        self.max = self.max.max(sample);
+        self.count += 1;
+        if self.count == self.samples_per_pixel {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        self.data.push(self.min);
+        self.data.push(self.max);
+        self.count = 0;
+        self.min = i8::MAX;
+        self.max = i8::MIN;
+    }
+
+    /// the last pixel can have fewer samples than the others
+    pub fn finish(mut self) -> Vec<i8> {
+        if self.count > 0 {
+            self.flush();
+        }
+        self.data
+    }
+}
+
+/// merges every factor pixels of the peaks into one
+pub fn downsample_peaks(data: &[i8], factor: usize) -> Vec<i8> {
+    data.chunks(factor * 2).flat_map(|pixels| {
+        let min = pixels.iter().step_by(2).min().copied().unwrap_or(0);
+        let max = pixels.iter().skip(1).step_by(2).max().copied().unwrap_or(0);
+        vec![min, max]
+    }).collect()
+}
+
+/// builds every zoom level from the peaks of the finest one
+pub fn waveform_levels(finest: Vec<i8>) -> Vec<Waveform> {
+    let base = WAVEFORM_ZOOM_LEVELS[0];
+    let mut levels: Vec<Waveform> = WAVEFORM_ZOOM_LEVELS[1..].iter()
+        .map(|spp| Waveform::new(*spp, downsample_peaks(&finest, (spp / base) as usize)))
+        .collect();
+    levels.insert(0, Waveform::new(base, finest));
+    levels
+}
+
+/// picks the most detailed level that has at least the requested
+/// samples per pixel. without a request, the most detailed level
+pub fn pick_waveform_level(levels: Vec<Waveform>, samples_per_pixel: Option<u32>) -> Option<Waveform> {
+    let mut levels = levels;
+    levels.sort_by_key(|w| w.samples_per_pixel);
+    let index = match samples_per_pixel {
+        None => 0,
+        Some(spp) => levels.iter().position(|w| w.samples_per_pixel >= spp)
+            .unwrap_or_else(|| levels.len().saturating_sub(1)),
+    };
+    if index < levels.len() { Some(levels.swap_remove(index)) } else { None }
+}
+
+pub fn waveform_path(location: &Path) -> PathBuf {
+    location.with_extension("waveform.json")
+}
+
+/// decodes the audio to 16 bit mono pcm on ffmpeg's stdout
+/// and computes the peaks of the finest zoom level from it
+pub async fn decode_peaks(location: &Path) -> Result<Vec<i8>, String> {
+    let input = match location.to_str() {
+        Some(s) => s.to_string(),
+        None => return Err(format!("File path contains invalid characters: {:?}", location)),
+    };
+    let sample_rate = WAVEFORM_SAMPLE_RATE.to_string();
+    let exe_and_args = vec![
+        "ffmpeg",
+        "-loglevel", "error",
+        "-hide_banner",
+        "-i", &input,
+        "-vn",
+        "-ac", "1",
+        "-ar", &sample_rate,
+        "-f", "s16le",
+        "-acodec", "pcm_s16le",
+        "pipe:1",
+    ];
+    let mut cmd = create_command(&exe_and_args[..]);
+    let mut child = cmd.spawn().map_err(|e| format!("Failed to run ffmpeg: {}", e))?;
+    let mut stdout = child.stdout.take().ok_or("Failed to get handle child process stdout")?;
+
+    let mut peaks = PeakBuilder::new(WAVEFORM_ZOOM_LEVELS[0]);
+    let mut buf = vec![0u8; 64 * 1024];
+    // a read can end in the middle of a sample
+    let mut leftover: Option<u8> = None;
+    loop {
+        let read = stdout.read(&mut buf).await.map_err(
+            |e| format!("Failed to read ffmpeg output: {}", e))?;
+        if read == 0 {
+            break;
+        }
+        let mut bytes = &buf[..read];
+        if let Some(low) = leftover.take() {
+            peaks.push_sample(i16::from_le_bytes([low, bytes[0]]));
+            bytes = &bytes[1..];
+        }
+        let mut samples = bytes.chunks_exact(2);
+        for sample in &mut samples {
+            peaks.push_sample(i16::from_le_bytes([sample[0], sample[1]]));
+        }
+        leftover = samples.remainder().first().copied();
+    }
+
+    let output = child.wait_with_output().await.map_err(
+        |e| format!("Failed to run ffmpeg: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("Failed to decode audio: {}", stderr.trim()));
+    }
+    Ok(peaks.finish())
+}
+
+/// writes every zoom level of the waveform next to the source video
+pub async fn make_waveform(location: &Path) -> Result<PathBuf, String> {
+    let levels = waveform_levels(decode_peaks(location).await?);
+    let json_string = serde_json::to_string(&levels).map_err(|e| e.to_string())?;
+    let output = waveform_path(location);
+    tokio::fs::write(&output, json_string).await.map_err(
+        |e| format!("Failed to write {:?}: {}", output, e))?;
+    Ok(output)
+}
+
+pub async fn read_waveform(location: &Path) -> Result<Vec<Waveform>, String> {
+    let json_string = tokio::fs::read_to_string(location).await.map_err(
+        |e| format!("Failed to read {:?}: {}", location, e))?;
+    serde_json::from_str(&json_string).map_err(
+        |e| format!("Failed to parse {:?}: {}", location, e))
+}
+
+pub const WAVEFORM_ANALYSIS: &'static str = "waveform";
+
+/// computes the waveform of the source video if it doesnt have one yet.
+/// it can fail for good, ie: the video might not have audio
+pub async fn generate_waveform(video_key: &String) {
+    let location = match DATAHOLDER.lock() {
+        Err(_) => None,
+        Ok(guard) => guard.as_ref().get(video_key)
+            .filter(|video| video.waveform_location.is_none() && !video.analysis_failed(WAVEFORM_ANALYSIS))
+            .map(|video| video.location.clone()),
+    };
+    if let Some(location) = location {
+        match make_waveform(&location).await {
+            Ok(waveform_location) => set_waveform(video_key, waveform_location),
+            Err(e) => {
+                println!("Failed to generate waveform for {:?}: {}", location, e);
+                set_analysis_failed(video_key, WAVEFORM_ANALYSIS);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_peaks_at_each_zoom_level() {
+        let mut peaks = PeakBuilder::new(2);
+        for sample in [256, -512, 0, 32767, -32768].iter() {
+            peaks.push_sample(*sample);
+        }
+        let finest = peaks.finish();
+        assert_eq!(finest, vec![-2, 1, 0, 127, -128, -128]);
+        assert_eq!(downsample_peaks(&finest, 2), vec![-2, 127, -128, -128]);
+        assert_eq!(downsample_peaks(&finest, 4), vec![-128, 127]);
+
+        let levels = waveform_levels(vec![0; 2 * 20]);
+        let lengths: Vec<(u32, usize)> = levels.iter().map(|w| (w.samples_per_pixel, w.length)).collect();
+        assert_eq!(lengths, vec![(256, 20), (1024, 5), (4096, 2)]);
+
+        assert_eq!(pick_waveform_level(levels.clone(), None).unwrap().samples_per_pixel, 256);
+        assert_eq!(pick_waveform_level(levels.clone(), Some(300)).unwrap().samples_per_pixel, 1024);
+        assert_eq!(pick_waveform_level(levels.clone(), Some(100_000)).unwrap().samples_per_pixel, 4096);
+        assert!(pick_waveform_level(vec![], None).is_none());
+
+        let json_string = serde_json::to_string(&Waveform::new(256, vec![-1, 1])).unwrap();
+        assert_eq!(json_string, r#"{"version":2,"channels":1,"sample_rate":8000,"samples_per_pixel":256,"bits":8,"length":1,"data":[-1,1]}"#);
+    }
+}
@@ -4,9 +4,23 @@ use super::create_command;
 use super::setup_child_and_reader;
 use super::handle_child_exit;
 use super::find_file_paths_matching;
+use super::fmt_string_error;
+use super::Config;
+use super::DownloadRequest;
+use super::Chapter;
+use super::get_config;
+use super::subtitles::get_subtitle_tracks_from_vec;
 use super::PROGHOLDER;
-use serde::{Deserialize, Serialize};
+use super::disk_guard::ensure_disk_limits;
+use super::disk_guard::wait_for_child_within_disk_limits;
+use serde::{Deserialize, Deserializer, Serialize, de::DeserializeOwned};
 use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+use futures::future::Either;
+use futures::future::select;
+use futures_timer::Delay;
+use tokio::io::AsyncReadExt;
 
 /// reads a given line from the output of youtube-dl
 /// and parses it (very roughly and not perfectly)
@@ -38,29 +52,102 @@ pub fn get_ytdl_progress(line: &str) -> Option<u8> {
     ret_value
 }
 
+/// youtube-dl format selectors only ever need these characters,
+/// ie: "bestvideo[height<=720][ext=mp4]+bestaudio/best"
+pub fn validate_format_selector<S: AsRef<str>>(format: S) -> Result<(), String> {
+    let format = format.as_ref();
+    let is_valid_char = |c: char| c.is_ascii_alphanumeric() || "[]<>=!+/*,:._-?^$~()".contains(c);
+    if format.is_empty() || format.starts_with('-') || !format.chars().all(is_valid_char) {
+        return Err(format!("Invalid format selector: {}", format));
+    }
+    Ok(())
+}
+
+/// translates the format options of the download request
+/// into a youtube-dl format selector. if the request has no format
+/// options, the default from the config is used
+pub fn format_selector(download_request: &DownloadRequest, config: &Config) -> Option<String> {
+    if let Some(ref format) = download_request.format {
+        return Some(format.clone());
+    }
+    if download_request.audio_only {
+        return Some("bestaudio/best".into());
+    }
+    if let Some(max_height) = download_request.max_height {
+        return Some(format!(
+            "bestvideo[height<={0}]+bestaudio/best[height<={0}]/best",
+            max_height
+        ));
+    }
+    config.default_format.clone()
+}
+
+/// options from the download request that change how youtube-dl is run
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct YtDlOptions {
+    pub format: Option<String>,
+    pub write_subtitles: bool,
+    pub subtitle_languages: Vec<String>,
+    pub auto_subtitles: bool,
+}
+
+impl YtDlOptions {
+    /// true if a download made with these options can
+    /// also be used for a request with the other options
+    pub fn satisfies(&self, other: &YtDlOptions) -> bool {
+        self.format == other.format && (!other.write_subtitles || self == other)
+    }
+}
+
 pub async fn download_video(
     key: String,
     url: String,
     download_dir: PathBuf,
+    options: YtDlOptions,
 ) -> TaskResult {
     let download_dir_string = match &download_dir.to_str() {
         Some(s) => s,
         None => "."
     };
+    // fail early if the download dir is already full
+    ensure_disk_limits(&download_dir, None).await?;
+
     let key_clone = key.clone();
     // form the command via all of the args it needs
     // and do basic spawn error checking
     let output_format = format!("{}/{}.%(ext)s", download_dir_string, &key);
-    let exe_and_args = vec![
+    let mut exe_and_args = vec![
         "youtube-dl",
         "--newline",
         "--ignore-config",
+        // normalized urls drop the playlist, so only ever
+        // download the one video the url points to
+        "--no-playlist",
         "--write-info-json",
         "--write-thumbnail",
-        &url,
-        "-o",
-        &output_format,
     ];
+    if let Some(ref format) = options.format {
+        exe_and_args.push("-f");
+        exe_and_args.push(format);
+    }
+    let subtitle_languages = options.subtitle_languages.join(",");
+    if options.write_subtitles {
+        exe_and_args.push("--write-sub");
+        if options.auto_subtitles {
+            exe_and_args.push("--write-auto-sub");
+        }
+        if !subtitle_languages.is_empty() {
+            exe_and_args.push("--sub-lang");
+            exe_and_args.push(&subtitle_languages);
+        }
+        // srt is the easiest to time shift, and ffmpeg
+        // can both burn it in and convert it to mov_text
+        exe_and_args.push("--convert-subs");
+        exe_and_args.push("srt");
+    }
+    exe_and_args.push(&url);
+    exe_and_args.push("-o");
+    exe_and_args.push(&output_format);
     println!("args: {:#?}", exe_and_args);
     let cmd = create_command(&exe_and_args[..]);
 
@@ -105,8 +192,17 @@ pub async fn download_video(
     // itself. as we await this child process, the above async future can run
     // whenever the reader finds a next line. But after here we actually return
     // our TaskResult that is read by the progresslib2
-    let child_status = child.await;
+    // the child gets killed if the disk fills up while downloading
+    let child_status = wait_for_child_within_disk_limits(child, download_dir.clone()).await;
     let res = handle_child_exit(child_status);
+    if res.is_err() {
+        // clean up any partial files so they dont take up space
+        if let Ok(partial_paths) = find_file_paths_matching(&key_clone, &download_dir).await {
+            for path in partial_paths {
+                let _ = tokio::fs::remove_file(path).await;
+            }
+        }
+    }
     let mut progvars = ProgressVars::default();
     if res.is_ok() {
         // say that we have downloaded this url
@@ -124,21 +220,47 @@ pub async fn download_video(
             "original_download_path",
             Box::new(output_path)
         );
-        if let Some(info_path) = info_json_path.take() {
-            println!("got info path: {:?}", info_path);
-            let mut ytdl_metadata = extract_metadata(&info_path).await;
-            if let Some(description) = ytdl_metadata.description.take() {
-                progvars.insert_var(
-                    "ytdl_description",
-                    Box::new(description)
-                );
-            }
-            if let Some(title) = ytdl_metadata.title.take() {
-                progvars.insert_var(
-                    "ytdl_title",
-                    Box::new(title)
-                );
-            }
+        let keep_info_json = get_config().map_or(false, |c| c.keep_info_json);
+        let mut ytdl_metadata = match info_json_path.take() {
+            Some(info_path) => {
+                println!("got info path: {:?}", info_path);
+                let metadata = extract_metadata(&info_path, keep_info_json).await;
+                if keep_info_json {
+                    progvars.insert_var(
+                        "info_json_path",
+                        Box::new(info_path)
+                    );
+                }
+                metadata
+            },
+            None => YtDlMetadata::default(),
+        };
+        // prefer the format that youtube-dl actually picked
+        // over the selector we asked for
+        ytdl_metadata.format_id = ytdl_metadata.format_id.take().or(options.format);
+        // the available formats are only interesting
+        // before downloading, ie: for /info
+        ytdl_metadata.formats.clear();
+        // the cut stage needs the chapters if
+        // the request asked to clip chapters
+        progvars.insert_var(
+            "chapters",
+            Box::new(ytdl_metadata.chapters.clone())
+        );
+        progvars.insert_var(
+            "ytdl_metadata",
+            Box::new(ytdl_metadata)
+        );
+        if options.write_subtitles {
+            let subtitle_tracks = match find_file_paths_matching(&key_clone, &download_dir).await {
+                Ok(paths) => get_subtitle_tracks_from_vec(paths),
+                Err(_) => vec![],
+            };
+            println!("got subtitles: {:?}", subtitle_tracks);
+            progvars.insert_var(
+                "subtitles",
+                Box::new(subtitle_tracks)
+            );
         }
         if let Some(thumbnail_path) = thumbnail_path.take() {
             println!("got thumbnail path: {:?}", thumbnail_path);
@@ -160,18 +282,144 @@ pub const VALID_VIDEO_EXTENSIONS: [&str; 10] = [
     "mp4", "mkv", "ts", "webm", "avi", "mov", "qt", "vob",
     "3gp", "wmv"
 ];
+/// for audio_only downloads
+pub const VALID_AUDIO_EXTENSIONS: [&str; 8] = [
+    "m4a", "mp3", "opus", "ogg", "aac", "flac", "wav", "oga"
+];
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct YtDlFormat {
+    pub format_id: Option<String>,
+    pub format_note: Option<String>,
+    pub ext: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fps: Option<f64>,
+    pub vcodec: Option<String>,
+    pub acodec: Option<String>,
+    pub tbr: Option<f64>,
+    pub filesize: Option<u64>,
+}
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+/// every field is parsed on its own, so a field that some extractor
+/// gives in an unexpected shape is dropped instead of the whole metadata
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct YtDlMetadata {
+    #[serde(default, deserialize_with = "lenient")]
     pub description: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
     pub title: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub duration: Option<f64>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub thumbnail: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub format_id: Option<String>,
+    #[serde(default, deserialize_with = "lenient_list")]
+    pub formats: Vec<YtDlFormat>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub uploader: Option<String>,
+    /// youtube-dl gives this as YYYYMMDD
+    #[serde(default, deserialize_with = "lenient")]
+    pub upload_date: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub view_count: Option<u64>,
+    #[serde(default, deserialize_with = "lenient_list")]
+    pub tags: Vec<String>,
+    #[serde(default, deserialize_with = "lenient_list")]
+    pub chapters: Vec<Chapter>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub webpage_url: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub extractor: Option<String>,
+    /// the id of the video according to the extractor
+    #[serde(default, deserialize_with = "lenient")]
+    pub id: Option<String>,
+}
+
+/// treats a field that does not parse as if it was missing
+pub fn lenient<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+    where D: Deserializer<'de>, T: Default + DeserializeOwned
+{
+    let value = serde_json::Value::deserialize(deserializer)?;
+    Ok(serde_json::from_value(value).unwrap_or_default())
+}
+
+/// youtube-dl sometimes writes null instead of an empty list.
+/// entries of the list that do not parse are skipped
+pub fn lenient_list<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+    where D: Deserializer<'de>, T: DeserializeOwned
+{
+    let values = match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Array(values) => values,
+        _ => vec![],
+    };
+    Ok(values.into_iter().filter_map(|v| serde_json::from_value(v).ok()).collect())
+}
+
+/// a hung extractor would otherwise block the request forever
+pub const METADATA_TIMEOUT_SECONDS: u64 = 60;
+
+/// runs youtube-dl without downloading anything, and
+/// parses the json it outputs for the given url. youtube-dl
+/// is killed if it takes longer than METADATA_TIMEOUT_SECONDS
+pub async fn fetch_metadata<S: AsRef<str>>(url: S) -> Result<YtDlMetadata, String> {
+    let exe_and_args = vec![
+        "youtube-dl",
+        "--ignore-config",
+        "--no-playlist",
+        "--dump-json",
+        "--skip-download",
+        url.as_ref(),
+    ];
+    let mut cmd = create_command(&exe_and_args[..]);
+    // nothing reads stderr, so dont let it fill up the pipe
+    cmd.stderr(Stdio::null());
+    let mut child = cmd.spawn().map_err(
+        |e| fmt_string_error("Failed to run youtube-dl", e))?;
+    let mut stdout = child.stdout.take().map_or_else(
+        || Err("Failed to get handle child process stdout"),
+        |o| Ok(o))?;
+
+    let finished = {
+        let run = Box::pin(async {
+            let mut output = vec![];
+            let read = stdout.read_to_end(&mut output).await;
+            let status = (&mut child).await;
+            (read.map(|_| output), status)
+        });
+        let timeout = Delay::new(Duration::from_secs(METADATA_TIMEOUT_SECONDS));
+        match select(run, timeout).await {
+            Either::Left((finished, _)) => Some(finished),
+            Either::Right(_) => None,
+        }
+    };
+    let (output, status) = match finished {
+        Some(finished) => finished,
+        None => {
+            let _ = child.kill();
+            // let the child get reaped before we return
+            let _ = child.await;
+            return Err(format!("youtube-dl did not finish within {} seconds", METADATA_TIMEOUT_SECONDS));
+        },
+    };
+    handle_child_exit(status)?;
+    let output = output.map_err(
+        |e| fmt_string_error("Failed to read youtube-dl output", e))?;
+
+    // if it is a playlist youtube-dl outputs one json object per line
+    // and we only care about the first one
+    let output_string = String::from_utf8_lossy(&output);
+    let first_line = output_string.lines().next().unwrap_or("");
+    serde_json::from_str(first_line).map_err(
+        |e| fmt_string_error("Failed to parse youtube-dl output", e))
 }
 
 /// asynchronously read the info.json file
 /// and then try to extract a few properties of
-/// interest. always delete the file after extraction
-/// because we dont need it afterwards
-pub async fn extract_metadata(path: &PathBuf) -> YtDlMetadata {
+/// interest. the file is deleted after extraction
+/// unless keep_file is set
+pub async fn extract_metadata(path: &PathBuf, keep_file: bool) -> YtDlMetadata {
     let json_string = match tokio::fs::read_to_string(path).await {
         Ok(s) => s,
         Err(_) => "{}".into(),
@@ -182,7 +430,9 @@ pub async fn extract_metadata(path: &PathBuf) -> YtDlMetadata {
 
     // before returning metadata, delete the file
     // to avoid clutter
-    let _ = tokio::fs::remove_file(path).await;
+    if !keep_file {
+        let _ = tokio::fs::remove_file(path).await;
+    }
     metadata
 }
 
@@ -208,7 +458,10 @@ pub fn get_downloaded_paths_from_vec(
                 info_json_path = Some(path);
             } else if VALID_THUMBNAIL_EXTENSIONS.iter().any(|e| *e == os_ext) && thumbnail_path.is_none() {
                 thumbnail_path = Some(path);
-            } else if VALID_VIDEO_EXTENSIONS.iter().any(|e| *e == os_ext) && output_path.is_none() {
+            } else if (
+                VALID_VIDEO_EXTENSIONS.iter().any(|e| *e == os_ext) ||
+                VALID_AUDIO_EXTENSIONS.iter().any(|e| *e == os_ext)
+            ) && output_path.is_none() {
                 output_path = Some(path);
             }
         }
@@ -246,4 +499,101 @@ mod tests {
         assert!(info_path.unwrap().to_str().unwrap().contains("vid.info.json"));
         assert!(thumbnail_path.unwrap().to_str().unwrap().contains("vid.jpg"));
     }
+
+    #[test]
+    fn odd_metadata_fields_are_dropped_on_their_own() {
+        let json = r#"{
+            "title": "a video",
+            "id": "abc",
+            "view_count": "lots",
+            "tags": ["one", 2, "three"],
+            "chapters": null,
+            "formats": [{ "format_id": "18", "height": 360 }, { "height": "tall" }]
+        }"#;
+        let metadata: YtDlMetadata = serde_json::from_str(json).unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("a video"));
+        assert_eq!(metadata.id.as_deref(), Some("abc"));
+        assert_eq!(metadata.view_count, None);
+        assert_eq!(metadata.tags, vec!["one", "three"]);
+        assert!(metadata.chapters.is_empty());
+        assert_eq!(metadata.formats.len(), 1);
+    }
+
+    #[test]
+    fn format_options_become_selectors() {
+        let mut config = Config::default();
+        let mut request = DownloadRequest::default();
+        assert_eq!(format_selector(&request, &config), None);
+        config.default_format = Some("best".into());
+        assert_eq!(format_selector(&request, &config).unwrap(), "best");
+
+        request.max_height = Some(720);
+        let selector = format_selector(&request, &config).unwrap();
+        assert!(selector.starts_with("bestvideo[height<=720]+bestaudio"));
+        assert!(validate_format_selector(&selector).is_ok());
+
+        request.audio_only = true;
+        assert_eq!(format_selector(&request, &config).unwrap(), "bestaudio/best");
+        request.format = Some("18".into());
+        assert_eq!(format_selector(&request, &config).unwrap(), "18");
+
+        assert!(validate_format_selector("-o /etc/x").is_err());
+        assert!(validate_format_selector("").is_err());
+    }
+
+    #[test]
+    fn options_satisfy_compatible_requests() {
+        let with_subs = YtDlOptions {
+            format: Some("best".into()),
+            write_subtitles: true,
+            subtitle_languages: vec!["en".into()],
+            auto_subtitles: false,
+        };
+        let without_subs = YtDlOptions { format: Some("best".into()), ..Default::default() };
+        assert!(with_subs.satisfies(&without_subs));
+        assert!(with_subs.satisfies(&with_subs.clone()));
+        assert!(!without_subs.satisfies(&with_subs));
+        let other_format = YtDlOptions { format: Some("18".into()), ..Default::default() };
+        assert!(!other_format.satisfies(&without_subs));
+    }
+
+    #[test]
+    fn can_parse_ytdl_metadata() {
+        let json_string = r#"{
+            "title": "a", "description": "b", "duration": 12.5,
+            "thumbnail": "https://example.com/a.jpg",
+            "formats": [
+                { "format_id": "18", "ext": "mp4", "width": 640, "height": 360, "vcodec": "avc1", "acodec": "mp4a" },
+                { "format_id": "140", "ext": "m4a", "vcodec": "none", "acodec": "mp4a", "filesize": 1000 }
+            ],
+            "something_else": 1
+        }"#;
+        let metadata: YtDlMetadata = serde_json::from_str(json_string).unwrap();
+        assert_eq!(metadata.duration, Some(12.5));
+        assert_eq!(metadata.formats.len(), 2);
+        assert_eq!(metadata.formats[0].height, Some(360));
+        assert_eq!(metadata.formats[1].filesize, Some(1000));
+    }
+
+    #[test]
+    fn can_parse_rich_ytdl_metadata() {
+        let json_string = r#"{
+            "id": "abc", "extractor": "youtube", "uploader": "someone",
+            "upload_date": "20201130", "view_count": 5, "tags": null,
+            "webpage_url": "https://www.youtube.com/watch?v=abc",
+            "chapters": [
+                { "start_time": 0.0, "end_time": 10.5, "title": "intro" },
+                { "start_time": 10.5, "end_time": 60, "title": "main" }
+            ],
+            "formats": null
+        }"#;
+        let metadata: YtDlMetadata = serde_json::from_str(json_string).unwrap();
+        assert_eq!(metadata.id.unwrap(), "abc");
+        assert_eq!(metadata.upload_date.unwrap(), "20201130");
+        assert!(metadata.tags.is_empty());
+        assert!(metadata.formats.is_empty());
+        assert_eq!(metadata.chapters.len(), 2);
+        assert_eq!(metadata.chapters[1].end_time, 60.0);
+        assert_eq!(metadata.chapters[1].title.as_ref().unwrap(), "main");
+    }
 }